@@ -13,25 +13,153 @@ use crate::{git::Repo, vars::*};
 #[derive(Serialize, Deserialize)]
 pub struct RepoConfig {
     pub name: String,
+    pub description: Option<String>,
     pub public: bool,
+    /// Whether search engines are invited to index the repo's site. On by default.
+    pub indexable: Option<bool>,
     pub members: Vec<String>,
     pub failed_push_message: Option<String>,
     pub web_template: Option<String>,
+    pub highlight_theme: Option<String>,
+    pub tree_template: Option<String>,
+    pub blob_template: Option<String>,
+    pub log_template: Option<String>,
+    pub commit_template: Option<String>,
+    pub releases_template: Option<String>,
+    pub markdown: Option<MarkdownConfig>,
+    pub site: Option<SiteConfig>,
+    pub mirror: Option<MirrorConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub push_mirrors: Vec<PushMirrorConfig>,
+    /// The repo this one was forked from on this server, like `alex/tool.git`.
+    pub forked_from: Option<String>,
     pub extra: Option<Table>,
 }
 
+/// GitHub-flavored Markdown extensions, which are all on unless turned off.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MarkdownConfig {
+    pub table: bool,
+    pub tasklist: bool,
+    pub strikethrough: bool,
+    pub autolink: bool,
+    pub footnotes: bool,
+}
+
+impl Default for MarkdownConfig {
+    fn default() -> Self {
+        Self {
+            table: true,
+            tasklist: true,
+            strikethrough: true,
+            autolink: true,
+            footnotes: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SiteConfig {
+    /// A directory of Markdown rendered as a multi-page docs site.
+    pub docs: Option<String>,
+    /// Branches and tags to publish previews of, as `git for-each-ref` patterns like `feature/*`.
+    #[serde(default)]
+    pub branches: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl RepoConfig {
+    /// Whether a user can clone and fetch the repo. Admins can read everything.
+    pub fn can_read(&self, username: &str, is_admin: bool) -> bool {
+        self.public || self.can_push(username, is_admin)
+    }
+
+    /// Whether a user can push to the repo, which takes being one of its members.
+    pub fn can_push(&self, username: &str, is_admin: bool) -> bool {
+        is_admin || self.members.iter().any(|member| member == username)
+    }
+}
+
+/// Keeps the repo in step with another one, which makes it read-only.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MirrorConfig {
+    /// Where to fetch from, as any URL git understands.
+    pub url: String,
+    /// How often to fetch, in minutes. Hourly by default.
+    pub interval_minutes: Option<u64>,
+}
+
+/// Another remote that gets every ref after each push, for redundancy.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PushMirrorConfig {
+    pub url: String,
+    /// The name of one of the server's credentials.
+    pub credential: Option<String>,
+}
+
+/// Mirrors lose the config on their branches to upstream's, so they keep a copy in the bare repo.
+pub fn pinned_config_path(repo_path: &Path) -> PathBuf {
+    repo_path.join(REPO_CONFIG_FILE)
+}
+
+/// The config a mirror keeps. Only a mirror's copy counts, anything else left there is stale.
+fn pinned_mirror_config(repo_path: &Path) -> anyhow::Result<Option<RepoConfig>> {
+    let pinned = pinned_config_path(repo_path);
+    if !pinned.exists() {
+        return Ok(None);
+    }
+    let config: RepoConfig = toml::from_str(&read_to_string(pinned)?)?;
+    Ok(config.mirror.is_some().then_some(config))
+}
+
+/// Keeps the config from the default branch, for when the branch no longer has it. Once kept,
+/// it only changes through `mirror config`, or goes back on the branch with `mirror stop`.
+pub async fn pin_repo_config(repo_path: &Path) -> anyhow::Result<()> {
+    if pinned_mirror_config(repo_path)?.is_some() {
+        return Ok(());
+    }
+    let text = Repo::open(repo_path)
+        .show_file("HEAD", REPO_CONFIG_FILE)
+        .await?;
+    write(pinned_config_path(repo_path), text)?;
+    Ok(())
+}
+
+/// Replaces a mirror's kept config.
+pub fn repin_repo_config(repo_path: &Path, config: &RepoConfig) -> anyhow::Result<()> {
+    write(pinned_config_path(repo_path), toml::to_string(config)?)?;
+    Ok(())
+}
+
 pub async fn load_repo_config(repo_path: &Path) -> anyhow::Result<RepoConfig> {
-    let config_name = PathBuf::from(REPO_CONFIG_FILE);
+    committed_repo_config(repo_path)
+        .await?
+        .context("Couldn't read eejit.toml")
+}
 
-    let temp_dir = tempdir()?;
-    let clone_dir = temp_dir.path().join(repo_path);
-    Repo::clone(repo_path, &clone_dir).await?;
+/// Reads a repo's config from its default branch, or the copy a mirror keeps. Brand new repos
+/// don't have one yet.
+pub async fn committed_repo_config(repo_path: &Path) -> anyhow::Result<Option<RepoConfig>> {
+    if let Some(config) = pinned_mirror_config(repo_path)? {
+        return Ok(Some(config));
+    }
 
-    let text = read_to_string(clone_dir.join(&config_name)).context("Couldn't read eejit.toml")?;
-    Ok(toml::from_str(&text)?)
+    match Repo::open(repo_path)
+        .show_file("HEAD", REPO_CONFIG_FILE)
+        .await
+    {
+        Ok(text) => Ok(Some(toml::from_str(&text)?)),
+        Err(_) => Ok(None),
+    }
 }
 
-pub async fn new_repo_config(repo_path: &Path, username: &str) -> anyhow::Result<()> {
+pub async fn new_repo_config(
+    repo_path: &Path,
+    username: &str,
+    forked_from: Option<&Path>,
+) -> anyhow::Result<()> {
     let config_name = PathBuf::from(REPO_CONFIG_FILE);
 
     let temp_dir = tempdir()?;
@@ -40,11 +168,24 @@ pub async fn new_repo_config(repo_path: &Path, username: &str) -> anyhow::Result
 
     let config = RepoConfig {
         name: repo_path.to_str().unwrap().to_string(),
+        description: None,
         public: false,
+        indexable: None,
         members: vec![username.to_string()],
         failed_push_message: None,
         extra: None,
         web_template: None,
+        highlight_theme: None,
+        tree_template: None,
+        blob_template: None,
+        log_template: None,
+        commit_template: None,
+        releases_template: None,
+        markdown: None,
+        site: None,
+        mirror: None,
+        push_mirrors: Vec::new(),
+        forked_from: forked_from.map(|path| path.to_string_lossy().to_string()),
     };
 
     let text = toml::to_string(&config)?;
@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fs::{copy, read_to_string, remove_file},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use tempfile::tempdir;
 use toml::Table;
@@ -22,11 +22,80 @@ pub struct ServerConfig {
     pub name: String,
     pub hostname: String,
     pub port: u16,
+    pub site_url: Option<String>,
+    pub highlight_theme: Option<String>,
+    /// Rebuild every repo's site when the server starts, like after an upgrade.
+    pub rebuild_sites_on_start: Option<bool>,
     pub users: HashMap<String, ServerUser>,
     pub welcome_message: Option<String>,
+    pub lfs: Option<LfsConfig>,
+    /// Let repos mirror `file://` URLs and paths outside the data directory, like for testing.
+    pub allow_local_mirrors: Option<bool>,
+    /// Credentials for push mirrors, by name, so they stay out of repos.
+    #[serde(default)]
+    pub credentials: HashMap<String, Credential>,
+    pub replication: Option<ReplicationConfig>,
+    pub patches: Option<PatchConfig>,
     pub exta: Option<Table>,
 }
 
+/// Storage limits for Git LFS objects. Repos can't raise their own, so they're set here.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct LfsConfig {
+    /// How much each repo can store, in megabytes. Unlimited if not set.
+    pub quota_mb: Option<u64>,
+    /// Quotas for particular repos, like `"alex/assets.git" = 10240`.
+    #[serde(default)]
+    pub repo_quotas: HashMap<String, u64>,
+}
+
+/// What a push mirror logs in with, which only the listed repos can use.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Credential {
+    #[serde(default)]
+    pub repos: Vec<String>,
+    /// A private key file on the server, for SSH remotes.
+    pub ssh_key: Option<String>,
+    /// For HTTPS remotes. The password can be a token.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Replicas this server keeps in sync. As config.git is replicated too, replicas see this as
+/// well, and use it to tell the primary apart from other users.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplicationConfig {
+    /// SSH URLs of the replicas, like `ssh://replication@standby.example.com:2222`.
+    #[serde(default)]
+    pub replicas: Vec<String>,
+    /// A private key file on the primary to log in to replicas with.
+    pub ssh_key: Option<String>,
+    /// The user that key belongs to, the only one replicas take pushes from.
+    pub user: String,
+}
+
+/// Where emailed patches come in, and which repos they're for.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PatchConfig {
+    /// A maildir or mbox file on the server that `git format-patch` mails are delivered to.
+    pub mailbox: String,
+    /// Repos by the address or `List-Id` their patches are sent to, like
+    /// `"tool@lists.example.com" = "alex/tool.git"`. Mails to anywhere else need the repo in
+    /// their subject, like `[PATCH alex/tool.git 1/2]`.
+    #[serde(default)]
+    pub addresses: HashMap<String, String>,
+}
+
+/// Turns a server into a replica. It's kept next to the data rather than in config.git, which
+/// comes from the primary.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplicaConfig {
+    /// Where pushes should go instead, like `ssh://example.com:2222`.
+    pub primary: String,
+    /// Listen here rather than on the primary's port, like when both run on the same machine.
+    pub port: Option<u16>,
+}
+
 // The default for ServerUser is used for guest access.
 impl Default for ServerUser {
     fn default() -> Self {
@@ -67,12 +136,57 @@ pub async fn load_server_config() -> anyhow::Result<ServerConfig> {
     Ok(toml::from_str(&text)?)
 }
 
+/// The replica config, if this server is one.
+pub fn load_replica_config() -> anyhow::Result<Option<ReplicaConfig>> {
+    let path = Path::new(REPLICA_CONFIG_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let text = read_to_string(path).context("Couldn't read replica.toml")?;
+    Ok(Some(toml::from_str(&text)?))
+}
+
 impl ServerConfig {
+    /// Where the `static` directory is served from, without a trailing slash.
+    pub fn site_url(&self) -> String {
+        match &self.site_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("https://{}", self.hostname),
+        }
+    }
+
+    pub fn clone_url(&self, repo_path: &Path) -> String {
+        format!(
+            "ssh://{}:{}/{}",
+            self.hostname,
+            self.port,
+            repo_path.to_string_lossy()
+        )
+    }
+
+    /// A credential, as long as the repo is allowed to use it.
+    pub fn credential(&self, name: &str, repo_path: &Path) -> Option<&Credential> {
+        let repo = repo_path.to_string_lossy();
+        self.credentials
+            .get(name)
+            .filter(|credential| credential.repos.iter().any(|r| *r == repo))
+    }
+
+    /// How many bytes of LFS objects a repo can store, if there's a limit.
+    pub fn lfs_quota(&self, repo_path: &Path) -> Option<u64> {
+        let lfs = self.lfs.as_ref()?;
+        let quota_mb = lfs
+            .repo_quotas
+            .get(repo_path.to_string_lossy().as_ref())
+            .or(lfs.quota_mb.as_ref())?;
+        Some(quota_mb * 1024 * 1024)
+    }
+
     pub fn get_user(&self, key: &str) -> Option<(String, ServerUser)> {
         for user in self.users.keys() {
             let key_data = self.users[user].public_key.split(' ').nth(1).unwrap();
 
-            if key == key_data && self.users[user].is_admin.unwrap_or(false) {
+            if key == key_data {
                 return Some((user.to_string(), self.users[user].clone()));
             }
         }
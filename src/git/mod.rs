@@ -1,14 +1,89 @@
 use std::{
+    collections::BTreeMap,
+    fs::{read_dir, read_to_string, remove_file, write},
     path::{Path, PathBuf},
-    process::ExitStatus,
+    process::{ExitStatus, Stdio},
 };
 
 use anyhow::{anyhow, Context};
+use serde::Serialize;
+use tempfile::tempdir;
+use tokio::io::AsyncWriteExt;
+
+use crate::vars::*;
 
 pub struct Repo {
     dir: PathBuf,
 }
 
+#[derive(Serialize, Clone)]
+pub struct Commit {
+    pub hash: String,
+    pub short_hash: String,
+    pub parents: Vec<String>,
+    pub author: String,
+    pub email: String,
+    pub date: i64,
+    pub summary: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct FileStat {
+    pub path: String,
+    pub added: Option<u64>,
+    pub deleted: Option<u64>,
+}
+
+/// Finds every bare repo under a directory, relative to it. The config repo and
+/// generated site are skipped.
+pub fn find_repos(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    fn walk(root: &Path, dir: &Path, repos: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        for entry in read_dir(root.join(dir))? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let path = dir.join(entry.file_name());
+            if entry.file_name().to_string_lossy().starts_with('.') || path == Path::new("static") {
+                continue;
+            }
+
+            if path.extension().unwrap_or_default() == "git" {
+                if path != Path::new(SERVER_CONFIG_REPO) && root.join(&path).join("HEAD").exists() {
+                    repos.push(path);
+                }
+            } else {
+                walk(root, &path, repos)?;
+            }
+        }
+        Ok(())
+    }
+
+    let mut repos = Vec::new();
+    walk(root, Path::new(""), &mut repos)?;
+    repos.sort();
+    Ok(repos)
+}
+
+#[derive(Serialize, Clone)]
+pub struct Tag {
+    pub name: String,
+    /// The commit the tag points to.
+    pub hash: String,
+    pub annotated: bool,
+    pub author: String,
+    pub date: i64,
+    /// The tag's own message if annotated, otherwise its commit's.
+    pub message: String,
+}
+
+// Fields and records are split with the ASCII unit and record separators.
+const LOG_FORMAT: &str = "--format=%H%x1f%h%x1f%P%x1f%an%x1f%ae%x1f%at%x1f%B%x1e";
+const TAG_FORMAT: &str = "--format=%(refname:short)%1f%(objecttype)%1f%(objectname)%1f\
+    %(*objectname)%1f%(taggername)%(authorname)%1f%(creatordate:unix)%1f%(contents)%1e";
+
 trait ExitOK {
     fn exit_ok(self) -> anyhow::Result<()>;
 }
@@ -47,6 +122,108 @@ impl Repo {
         Repo::from(path).await
     }
 
+    /// Makes a bare copy of a repo's branches and tags that borrows its objects through
+    /// alternates, so a fork takes next to no space until it changes.
+    pub async fn fork(from: &Path, to: &Path) -> anyhow::Result<Self> {
+        tokio::process::Command::new("git")
+            .args(["clone", "--bare", "--shared", "--quiet"])
+            .arg(from)
+            .arg(to)
+            .output()
+            .await?
+            .status
+            .exit_ok()
+            .context("Failed to fork repo")?;
+
+        // Relative to the fork's objects, so the data directory can move.
+        let up = "../".repeat(to.components().count() + 1);
+        write(
+            to.join("objects/info/alternates"),
+            format!("{}{}\n", up, from.join("objects").display()),
+        )?;
+
+        let fork = Repo::open(to);
+        fork.git_output(&["remote", "remove", "origin"]).await?;
+        Repo::open(from).keep_objects_for_forks().await?;
+        Ok(fork)
+    }
+
+    /// The repos that borrow objects from this one, which are the forks made from it.
+    pub fn forks(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let objects = self.dir.join("objects").canonicalize()?;
+        let mut forks = Vec::new();
+        for repo_path in find_repos(Path::new("."))? {
+            let fork = Repo::open(&repo_path);
+            if fork.borrowed_objects().contains(&objects) {
+                forks.push(repo_path);
+            }
+        }
+        Ok(forks)
+    }
+
+    /// The repos this one borrows objects from, if it's a fork.
+    pub fn sources(&self) -> Vec<PathBuf> {
+        self.borrowed_objects()
+            .iter()
+            .filter_map(|objects| Some(objects.parent()?.to_path_buf()))
+            .collect()
+    }
+
+    /// The object directories this repo's alternates point to, resolved.
+    fn borrowed_objects(&self) -> Vec<PathBuf> {
+        let objects = self.dir.join("objects");
+        let Ok(alternates) = read_to_string(objects.join("info/alternates")) else {
+            return Vec::new();
+        };
+        alternates
+            .lines()
+            .filter_map(|line| objects.join(line.trim()).canonicalize().ok())
+            .collect()
+    }
+
+    /// Pruning a repo that's been forked could take objects its forks still need, so it's turned
+    /// off for as long as it has any.
+    pub async fn keep_objects_for_forks(&self) -> anyhow::Result<()> {
+        match self.forks()?.is_empty() {
+            true => {
+                // Exits with 5 when it wasn't set.
+                tokio::process::Command::new("git")
+                    .current_dir(&self.dir)
+                    .args(["config", "--unset", "gc.pruneExpire"])
+                    .output()
+                    .await?;
+            }
+            false => {
+                self.git_output(&["config", "gc.pruneExpire", "never"])
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies every object a fork borrows into it, so it stands on its own and whatever it was
+    /// forked from can go.
+    pub async fn unshare(&self) -> anyhow::Result<()> {
+        let alternates = self.dir.join("objects/info/alternates");
+        if !alternates.exists() {
+            return Ok(());
+        }
+        let sources = self.sources();
+        self.git_output(&["repack", "-a", "-d", "-q"]).await?;
+        remove_file(alternates)?;
+        for source in sources {
+            Repo::open(&source).keep_objects_for_forks().await?;
+        }
+        Ok(())
+    }
+
+    /// Opens an existing repo as-is, without touching its config.
+    pub fn open(path: &Path) -> Repo {
+        Repo {
+            dir: path.to_path_buf(),
+        }
+    }
+
     pub async fn clone(from: &Path, to: &Path) -> anyhow::Result<Self> {
         tokio::process::Command::new("git")
             .arg("clone")
@@ -123,4 +300,573 @@ impl Repo {
 
         Ok(())
     }
+
+    /// Runs a git command in the repo, returning its stdout.
+    async fn git_output(&self, args: &[&str]) -> anyhow::Result<String> {
+        let output = tokio::process::Command::new("git")
+            .current_dir(&self.dir)
+            .args(args)
+            .output()
+            .await?;
+        output
+            .status
+            .exit_ok()
+            .with_context(|| format!("Failed to run git {}", args.join(" ")))?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// Lists commits newest first, taking the same revision arguments as `git log`.
+    pub async fn log(&self, revisions: &[&str]) -> anyhow::Result<Vec<Commit>> {
+        let mut args = vec!["log", LOG_FORMAT];
+        args.extend_from_slice(revisions);
+        args.push("--");
+        let output = self.git_output(&args).await?;
+
+        let mut commits = Vec::new();
+        for record in output.split('\x1e') {
+            let fields: Vec<&str> = record.trim_start().split('\x1f').collect();
+            if fields.len() != 7 {
+                continue;
+            }
+
+            let message = fields[6].trim_end().to_string();
+            commits.push(Commit {
+                hash: fields[0].to_string(),
+                short_hash: fields[1].to_string(),
+                parents: fields[2].split_whitespace().map(str::to_string).collect(),
+                author: fields[3].to_string(),
+                email: fields[4].to_string(),
+                date: fields[5].parse().unwrap_or_default(),
+                summary: message.lines().next().unwrap_or_default().to_string(),
+                message,
+            });
+        }
+
+        Ok(commits)
+    }
+
+    /// The newest commit on HEAD, if there are any.
+    pub async fn latest_commit(&self) -> anyhow::Result<Option<Commit>> {
+        Ok(self.log(&["-1", "HEAD"]).await?.into_iter().next())
+    }
+
+    /// Reads a file at a given revision, which also works in bare repos.
+    pub async fn show_file(&self, revision: &str, path: &str) -> anyhow::Result<String> {
+        self.git_output(&["show", &format!("{}:{}", revision, path)])
+            .await
+    }
+
+    /// Lists the tags on commits, newest first.
+    pub async fn tags(&self) -> anyhow::Result<Vec<Tag>> {
+        let output = self
+            .git_output(&[
+                "for-each-ref",
+                "--sort=-creatordate",
+                TAG_FORMAT,
+                "refs/tags",
+            ])
+            .await?;
+
+        let mut tags = Vec::new();
+        for record in output.split('\x1e') {
+            let fields: Vec<&str> = record.trim_start().split('\x1f').collect();
+            if fields.len() != 7 {
+                continue;
+            }
+
+            let annotated = fields[1] == "tag";
+            tags.push(Tag {
+                name: fields[0].to_string(),
+                hash: match annotated {
+                    true => fields[3].to_string(),
+                    false => fields[2].to_string(),
+                },
+                annotated,
+                author: fields[4].to_string(),
+                date: fields[5].parse().unwrap_or_default(),
+                message: fields[6].trim_end().to_string(),
+            });
+        }
+
+        Ok(tags)
+    }
+
+    /// Lists the branch names, alphabetically.
+    pub async fn branches(&self) -> anyhow::Result<Vec<String>> {
+        let output = self
+            .git_output(&["for-each-ref", "--format=%(refname:short)", "refs/heads"])
+            .await?;
+        Ok(output.lines().map(str::to_string).collect())
+    }
+
+    /// Every ref and the object it points to. Patterns work like they do for `git for-each-ref`, and
+    /// without any, all refs are listed.
+    pub async fn refs(&self, patterns: &[&str]) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut args = vec!["for-each-ref", "--format=%(refname) %(objectname)"];
+        args.extend_from_slice(patterns);
+        let output = self.git_output(&args).await?;
+        Ok(output
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(name, object)| (name.to_string(), object.to_string()))
+            .collect())
+    }
+
+    /// The branch HEAD points to.
+    pub async fn head_branch(&self) -> anyhow::Result<String> {
+        let output = self
+            .git_output(&["symbolic-ref", "--short", "HEAD"])
+            .await?;
+        Ok(output.trim().to_string())
+    }
+
+    /// Checks out a revision in a clone, without being on a branch.
+    pub async fn checkout(&self, revision: &str) -> anyhow::Result<()> {
+        self.git_output(&["checkout", "--quiet", "--detach", revision])
+            .await?;
+        Ok(())
+    }
+
+    /// The object ID of every file and directory in a revision, by path. The root is the empty path.
+    pub async fn tree_objects(&self, revision: &str) -> anyhow::Result<BTreeMap<PathBuf, String>> {
+        let root = self
+            .git_output(&["rev-parse", &format!("{}^{{tree}}", revision)])
+            .await?;
+        let output = self
+            .git_output(&["ls-tree", "-r", "-t", "-z", revision])
+            .await?;
+
+        let mut objects = BTreeMap::new();
+        objects.insert(PathBuf::new(), root.trim().to_string());
+        for entry in output.split('\0') {
+            // `<mode> <type> <object>\t<path>`
+            if let Some((info, path)) = entry.split_once('\t') {
+                if let Some(object) = info.split(' ').nth(2) {
+                    objects.insert(PathBuf::from(path), object.to_string());
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    /// A git command that talks to another repo, with nobody there to type in a password.
+    fn remote_git(&self, args: &[&str]) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new("git");
+        command
+            .current_dir(&self.dir)
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .args(["-c", "protocol.ext.allow=never"])
+            .args(args);
+        command
+    }
+
+    /// Points a ref at an object, making it if needed.
+    pub async fn update_ref(&self, name: &str, object: &str) -> anyhow::Result<()> {
+        self.git_output(&["update-ref", name, object]).await?;
+        Ok(())
+    }
+
+    pub async fn delete_ref(&self, name: &str) -> anyhow::Result<()> {
+        self.git_output(&["update-ref", "-d", name]).await?;
+        Ok(())
+    }
+
+    /// Moves a ref only if it still points at `old`, which is all zeros for a ref that
+    /// mustn't exist yet. Returns whether it was moved.
+    pub async fn swap_ref(&self, name: &str, new: &str, old: &str) -> anyhow::Result<bool> {
+        let status = tokio::process::Command::new("git")
+            .current_dir(&self.dir)
+            .args(["update-ref", name, new, old])
+            .stderr(Stdio::null())
+            .status()
+            .await?;
+        Ok(status.success())
+    }
+
+    /// Runs a git command with something on its stdin, returning its trimmed stdout.
+    async fn git_input(
+        &self,
+        args: &[&str],
+        env: &[(&str, &str)],
+        input: &str,
+    ) -> anyhow::Result<String> {
+        let mut child = tokio::process::Command::new("git")
+            .current_dir(&self.dir)
+            .args(args)
+            .envs(env.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(input.as_bytes()).await?;
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        output
+            .status
+            .exit_ok()
+            .with_context(|| format!("Failed to run git {}", args.join(" ")))?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Stores files as a commit without touching any branch or the index, returning its hash.
+    /// The files are `(name, contents)` at the top of its tree.
+    pub async fn write_commit(
+        &self,
+        files: &[(&str, &str)],
+        parent: Option<&str>,
+        message: &str,
+        author: &str,
+    ) -> anyhow::Result<String> {
+        let mut tree = String::new();
+        for (name, contents) in files {
+            let blob = self
+                .git_input(&["hash-object", "-w", "--stdin"], &[], contents)
+                .await?;
+            tree.push_str(&format!("100644 blob {}\t{}\n", blob, name));
+        }
+        let tree = self.git_input(&["mktree"], &[], &tree).await?;
+
+        let mut args = vec!["commit-tree", &tree];
+        if let Some(parent) = parent {
+            args.extend(["-p", parent]);
+        }
+        let env = [
+            ("GIT_AUTHOR_NAME", author),
+            ("GIT_AUTHOR_EMAIL", "N/A"),
+            ("GIT_COMMITTER_NAME", author),
+            ("GIT_COMMITTER_EMAIL", "N/A"),
+        ];
+        self.git_input(&args, &env, message).await
+    }
+
+    /// Merges a revision into one of the repo's branches with a merge commit, through a
+    /// temporary clone. Fails if it doesn't merge cleanly.
+    pub async fn merge(
+        &self,
+        branch: &str,
+        revision: &str,
+        message: &str,
+        author: &str,
+    ) -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let clone = Repo::clone(&self.dir, &temp_dir.path().join("merge")).await?;
+        clone
+            .git_output(&["fetch", "--quiet", "origin", revision])
+            .await?;
+        clone.git_output(&["checkout", "--quiet", branch]).await?;
+
+        let user = format!("user.name={}", author);
+        let merge = ["-c", &user, "merge", "--no-ff", "-m", message, "FETCH_HEAD"];
+        if clone.git_output(&merge).await.is_err() {
+            return Err(anyhow!("It doesn't merge cleanly into {}.", branch));
+        }
+        clone
+            .git_output(&["push", "--quiet", "origin", branch])
+            .await?;
+        Ok(())
+    }
+
+    /// Applies patch mails to a branch with `git am`, in order, through a temporary clone. A
+    /// branch that doesn't exist yet is started from the default branch. Fails if any of them
+    /// don't apply.
+    pub async fn apply_mails(
+        &self,
+        branch: &str,
+        mails: &[PathBuf],
+        committer: &str,
+    ) -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let clone = Repo::clone(&self.dir, &temp_dir.path().join("apply")).await?;
+        match self.branches().await?.iter().any(|b| b == branch) {
+            true => clone.git_output(&["checkout", "--quiet", branch]).await?,
+            false => {
+                let start = format!("origin/{}", self.head_branch().await?);
+                clone
+                    .git_output(&["checkout", "--quiet", "-b", branch, &start])
+                    .await?
+            }
+        };
+
+        // Git runs inside the clone, so relative paths would end up in there.
+        let current_dir = std::env::current_dir()?;
+        let user = format!("user.name={}", committer);
+        let mut args = vec!["-c", &user, "am", "--quiet"];
+        let mails: Vec<String> = mails
+            .iter()
+            .map(|mail| current_dir.join(mail).display().to_string())
+            .collect();
+        args.extend(mails.iter().map(String::as_str));
+        let output = tokio::process::Command::new("git")
+            .current_dir(&clone.dir)
+            .args(&args)
+            .output()
+            .await?;
+        if !output.status.success() {
+            // Like `Patch failed at 0002 Fix a typo`.
+            let failed = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .chain(String::from_utf8_lossy(&output.stderr).lines())
+                .find(|line| line.starts_with("Patch failed at"))
+                .map(|line| format!(" {}.", line))
+                .unwrap_or_default();
+            return Err(anyhow!(
+                "It doesn't apply cleanly onto {}.{}",
+                branch,
+                failed
+            ));
+        }
+        clone
+            .git_output(&["push", "--quiet", "origin", branch])
+            .await?;
+        Ok(())
+    }
+
+    /// Makes the repo's branches, tags and default branch match another repo's, dropping
+    /// any that are gone there.
+    pub async fn fetch_mirror(&self, url: &str) -> anyhow::Result<()> {
+        let output = self
+            .remote_git(&[
+                "fetch",
+                "--prune",
+                "--quiet",
+                url,
+                "+refs/heads/*:refs/heads/*",
+                "+refs/tags/*:refs/tags/*",
+            ])
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        // `ref: refs/heads/main\tHEAD`
+        let output = self
+            .remote_git(&["ls-remote", "--symref", url, "HEAD"])
+            .output()
+            .await?;
+        let head = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.strip_prefix("ref: ")?.strip_suffix("\tHEAD"))
+            .map(str::to_string);
+        if let Some(head) = head {
+            self.git_output(&["symbolic-ref", "HEAD", &head]).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes another repo's refs match this one's, with extra environment for logging in.
+    pub async fn push_mirror(&self, url: &str, env: &[(String, String)]) -> anyhow::Result<()> {
+        let output = self
+            .remote_git(&["push", "--mirror", "--quiet", url])
+            .envs(env.iter().cloned())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Maps every file at a revision to its blob. Submodules and trees are left out.
+    pub async fn blobs(&self, revision: &str) -> anyhow::Result<BTreeMap<PathBuf, String>> {
+        let output = self.git_output(&["ls-tree", "-r", "-z", revision]).await?;
+
+        let mut blobs = BTreeMap::new();
+        for entry in output.split('\0') {
+            // `<mode> <type> <object>\t<path>`
+            if let Some((info, path)) = entry.split_once('\t') {
+                let info: Vec<&str> = info.split(' ').collect();
+                if let ["100644" | "100755", "blob", object] = info.as_slice() {
+                    blobs.insert(PathBuf::from(path), object.to_string());
+                }
+            }
+        }
+        Ok(blobs)
+    }
+
+    /// Reads the contents of blobs in one go, in the same order. Ones that don't exist come back
+    /// empty.
+    pub async fn read_blobs(&self, objects: &[&str]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut child = tokio::process::Command::new("git")
+            .current_dir(&self.dir)
+            .args(["cat-file", "--batch"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        // Written alongside reading, or a full stdout pipe would hold everything up.
+        let mut stdin = child.stdin.take().unwrap();
+        let input = objects
+            .iter()
+            .map(|o| format!("{}\n", o))
+            .collect::<String>();
+        let writer = tokio::spawn(async move { stdin.write_all(input.as_bytes()).await });
+
+        let output = child.wait_with_output().await?;
+        writer.await??;
+        output
+            .status
+            .exit_ok()
+            .context("Failed to run git cat-file")?;
+
+        // Each one is `<object> <type> <size>\n<contents>\n`, or `<object> missing\n`.
+        let mut blobs = Vec::new();
+        let mut rest = output.stdout.as_slice();
+        for object in objects {
+            let header_end = rest
+                .iter()
+                .position(|b| *b == b'\n')
+                .ok_or_else(|| anyhow!("Truncated output from git cat-file"))?;
+            let header = String::from_utf8_lossy(&rest[..header_end]).to_string();
+            let size: usize = match header.split(' ').collect::<Vec<_>>().as_slice() {
+                [_, "blob", size] => size.parse()?,
+                [_, "missing"] => {
+                    blobs.push(Vec::new());
+                    rest = &rest[header_end + 1..];
+                    continue;
+                }
+                _ => return Err(anyhow!("{} isn't a blob", object)),
+            };
+            let start = header_end + 1;
+            let contents = rest
+                .get(start..start + size)
+                .ok_or_else(|| anyhow!("Truncated output from git cat-file"))?;
+            blobs.push(contents.to_vec());
+            rest = rest.get(start + size + 1..).unwrap_or_default();
+        }
+        Ok(blobs)
+    }
+
+    /// Writes an archive of a revision, in any format `git archive` knows (like `tar.gz` or `zip`).
+    pub async fn archive(
+        &self,
+        revision: &str,
+        format: &str,
+        prefix: &str,
+        output: &Path,
+    ) -> anyhow::Result<()> {
+        // Git runs inside the repo, so relative paths would end up in there.
+        let output = std::env::current_dir()?.join(output);
+        tokio::process::Command::new("git")
+            .current_dir(&self.dir)
+            .arg("archive")
+            .arg(format!("--format={}", format))
+            .arg(format!("--prefix={}/", prefix))
+            .arg("-o")
+            .arg(&output)
+            .arg(revision)
+            .output()
+            .await?
+            .status
+            .exit_ok()
+            .context("Failed to create archive")?;
+        Ok(())
+    }
+
+    pub async fn is_ancestor(&self, ancestor: &str, descendant: &str) -> anyhow::Result<bool> {
+        let status = tokio::process::Command::new("git")
+            .current_dir(&self.dir)
+            .arg("merge-base")
+            .arg("--is-ancestor")
+            .arg(ancestor)
+            .arg(descendant)
+            .output()
+            .await?
+            .status;
+        Ok(status.success())
+    }
+
+    /// The unified diff a commit introduces, relative to its first parent.
+    pub async fn diff(&self, hash: &str) -> anyhow::Result<String> {
+        self.git_output(&[
+            "show",
+            "--diff-merges=first-parent",
+            "--format=",
+            "--patch",
+            "--no-color",
+            hash,
+        ])
+        .await
+    }
+
+    /// Lines added and deleted per file in a commit. Binary files have no counts.
+    pub async fn diff_stats(&self, hash: &str) -> anyhow::Result<Vec<FileStat>> {
+        let output = self
+            .git_output(&[
+                "show",
+                "--diff-merges=first-parent",
+                "--format=",
+                "--numstat",
+                hash,
+            ])
+            .await?;
+
+        Ok(output
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let added = fields.next()?.parse().ok();
+                let deleted = fields.next()?.parse().ok();
+                Some(FileStat {
+                    path: fields.next()?.to_string(),
+                    added,
+                    deleted,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::current_dir;
+
+    use tempfile::Builder;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn forks_borrow_objects_until_unshared() {
+        // Tests run from the crate, which stands in for the data directory. Forks are found by
+        // looking through it, which skips hidden directories.
+        let cwd = current_dir().unwrap();
+        let temp_dir = Builder::new()
+            .prefix("fork-test")
+            .tempdir_in("target")
+            .unwrap();
+        let dir = temp_dir.path().strip_prefix(&cwd).unwrap();
+        let source_path = dir.join("alex").join("tool.git");
+        let fork_path = dir.join("sam").join("tool.git");
+
+        let source = Repo::create_bare(&source_path).await.unwrap();
+        let commit = source
+            .write_commit(&[("README.md", "# Tool\n")], None, "First", "alex")
+            .await
+            .unwrap();
+        source.update_ref("refs/heads/main", &commit).await.unwrap();
+
+        let fork = Repo::fork(&source_path, &fork_path).await.unwrap();
+        assert_eq!(
+            fork.refs(&["refs/heads"]).await.unwrap()["refs/heads/main"],
+            commit
+        );
+        assert_eq!(fork.sources(), [source_path.canonicalize().unwrap()]);
+        assert_eq!(source.forks().unwrap(), vec![fork_path]);
+        assert!(source.sources().is_empty());
+        let prune = || source.git_output(&["config", "gc.pruneExpire"]);
+        assert_eq!(prune().await.unwrap().trim(), "never");
+
+        fork.unshare().await.unwrap();
+        assert!(fork.sources().is_empty());
+        assert!(source.forks().unwrap().is_empty());
+        assert!(prune().await.is_err());
+        fork.git_output(&["cat-file", "-e", &commit]).await.unwrap();
+    }
 }
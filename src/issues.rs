@@ -0,0 +1,260 @@
+use std::str::from_utf8;
+
+use anyhow::anyhow;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    git::{Commit, Repo},
+    mirror::now,
+    vars::*,
+};
+
+/// Each issue is a chain of commits on `refs/issues/<id>`, so it's cloned, mirrored and replicated
+/// along with everything else. Every commit is a comment or change, with the issue as it then
+/// stood in its tree.
+const ISSUES_REF: &str = "refs/issues";
+const ISSUE_FILE: &str = "issue.toml";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Open,
+    Closed,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Open => "open",
+            Status::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Issue {
+    pub id: u64,
+    pub title: String,
+    pub author: String,
+    pub status: Status,
+    /// Unix timestamp.
+    pub created: u64,
+}
+
+impl Issue {
+    pub fn ref_name(&self) -> String {
+        format!("{}/{}", ISSUES_REF, self.id)
+    }
+}
+
+/// Every issue in a repo, oldest first. Anyone who can push can write to `refs/issues`, so ones
+/// that can't be read are logged and left out rather than breaking the rest.
+pub async fn list(repo: &Repo) -> anyhow::Result<Vec<Issue>> {
+    let refs = repo.refs(&[ISSUES_REF]).await?;
+    let objects: Vec<String> = refs
+        .values()
+        .map(|head| format!("{}:{}", head, ISSUE_FILE))
+        .collect();
+    let objects: Vec<&str> = objects.iter().map(String::as_str).collect();
+
+    let mut issues = Vec::new();
+    for (name, blob) in refs.keys().zip(repo.read_blobs(&objects).await?) {
+        match parse_issue(&blob) {
+            Ok(issue) if issue.ref_name() == *name => issues.push(issue),
+            Ok(issue) => warn!("Skipping {}, which says it's #{}", name, issue.id),
+            Err(e) => warn!("Skipping unreadable issue {}: {:#}", name, e),
+        }
+    }
+    issues.sort_by_key(|issue| issue.id);
+    Ok(issues)
+}
+
+fn parse_issue(blob: &[u8]) -> anyhow::Result<Issue> {
+    Ok(toml::from_str(from_utf8(blob)?)?)
+}
+
+/// The number after the last issue, counting ones that can't be read so they're never reused.
+async fn next_id(repo: &Repo) -> anyhow::Result<u64> {
+    let refs = repo.refs(&[ISSUES_REF]).await?;
+    let last = refs
+        .keys()
+        .filter_map(|name| name.rsplit('/').next()?.parse::<u64>().ok())
+        .chain(list(repo).await?.iter().map(|issue| issue.id))
+        .max();
+    Ok(last.unwrap_or(0) + 1)
+}
+
+/// Looks up an issue by its number, with or without the `#`, along with the commit it's at.
+pub async fn load(repo: &Repo, id: &str) -> anyhow::Result<(Issue, String)> {
+    let not_found = || anyhow!("There's no issue {}.", id);
+    let number: u64 = id
+        .trim_start_matches('#')
+        .parse()
+        .map_err(|_| not_found())?;
+    let name = format!("{}/{}", ISSUES_REF, number);
+    let head = repo
+        .refs(&[&name])
+        .await?
+        .remove(&name)
+        .ok_or_else(not_found)?;
+    let issue = toml::from_str(&repo.show_file(&head, ISSUE_FILE).await?)?;
+    Ok((issue, head))
+}
+
+/// An issue's comments and changes, oldest first.
+pub async fn history(repo: &Repo, issue: &Issue) -> anyhow::Result<Vec<Commit>> {
+    let mut commits = repo.log(&[&issue.ref_name()]).await?;
+    commits.reverse();
+    Ok(commits)
+}
+
+/// Opens a new issue, numbered after the last one.
+pub async fn open(repo: &Repo, title: &str, author: &str) -> anyhow::Result<Issue> {
+    let id = next_id(repo).await?;
+    let issue = Issue {
+        id,
+        title: title.to_string(),
+        author: author.to_string(),
+        status: Status::Open,
+        created: now(),
+    };
+    record(repo, &issue, None, "Opened.", author).await?;
+    Ok(issue)
+}
+
+/// Adds a comment to an issue, saving any change made to it along the way. `head` is the commit
+/// it was loaded at.
+pub async fn update(
+    repo: &Repo,
+    issue: &Issue,
+    head: &str,
+    message: &str,
+    author: &str,
+) -> anyhow::Result<()> {
+    record(repo, issue, Some(head), message, author).await
+}
+
+async fn record(
+    repo: &Repo,
+    issue: &Issue,
+    parent: Option<&str>,
+    message: &str,
+    author: &str,
+) -> anyhow::Result<()> {
+    let contents = toml::to_string(issue)?;
+    let commit = repo
+        .write_commit(&[(ISSUE_FILE, &contents)], parent, message, author)
+        .await?;
+    // Two people changing the same issue at once mustn't lose either change.
+    if !repo
+        .swap_ref(&issue.ref_name(), &commit, parent.unwrap_or(NULL_OBJECT))
+        .await?
+    {
+        return Err(anyhow!(
+            "#{} was changed at the same time, try again.",
+            issue.id
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+
+    async fn bare_repo() -> (TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo = Repo::create_bare(&dir.path().join("demo.git"))
+            .await
+            .unwrap();
+        (dir, repo)
+    }
+
+    /// Points an issue ref at a commit with whatever's given as its `issue.toml`.
+    async fn write_raw(repo: &Repo, name: &str, contents: &str) {
+        let commit = repo
+            .write_commit(&[(ISSUE_FILE, contents)], None, "Opened.", "mallory")
+            .await
+            .unwrap();
+        repo.update_ref(name, &commit).await.unwrap();
+    }
+
+    #[test]
+    fn issues_are_read_from_toml() {
+        let issue = parse_issue(
+            b"id = 3\ntitle = \"It breaks\"\nauthor = \"bob\"\nstatus = \"closed\"\ncreated = 10\n",
+        )
+        .unwrap();
+        assert_eq!(issue.id, 3);
+        assert_eq!(issue.title, "It breaks");
+        assert!(issue.status == Status::Closed);
+        assert_eq!(issue.ref_name(), "refs/issues/3");
+
+        assert!(parse_issue(b"id = 3\ntitle = \"No author\"\n").is_err());
+        assert!(parse_issue(b"id = 3\nstatus = \"wontfix\"\n").is_err());
+        assert!(parse_issue(b"\xff\xfe").is_err());
+    }
+
+    #[tokio::test]
+    async fn issues_keep_their_history() {
+        let (_dir, repo) = bare_repo().await;
+        let first = open(&repo, "It breaks", "bob").await.unwrap();
+        let second = open(&repo, "It breaks again", "alice").await.unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+
+        let (mut issue, head) = load(&repo, "#1").await.unwrap();
+        issue.status = Status::Closed;
+        update(&repo, &issue, &head, "Fixed now.", "admin")
+            .await
+            .unwrap();
+
+        let (issue, _) = load(&repo, "1").await.unwrap();
+        assert!(issue.status == Status::Closed);
+        let messages: Vec<_> = history(&repo, &issue)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|commit| commit.summary)
+            .collect();
+        assert_eq!(messages, vec!["Opened.", "Fixed now."]);
+        assert!(load(&repo, "#3").await.is_err());
+        assert!(load(&repo, "one").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn changes_made_at_the_same_time_are_refused() {
+        let (_dir, repo) = bare_repo().await;
+        open(&repo, "It breaks", "bob").await.unwrap();
+        let (issue, head) = load(&repo, "1").await.unwrap();
+        update(&repo, &issue, &head, "Me too.", "alice")
+            .await
+            .unwrap();
+        assert!(update(&repo, &issue, &head, "And me.", "carol")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn unreadable_issues_are_skipped() {
+        let (_dir, repo) = bare_repo().await;
+        open(&repo, "It breaks", "bob").await.unwrap();
+        write_raw(&repo, "refs/issues/2", "not = [toml").await;
+        // Saying it's another issue mustn't let it stand in for that one.
+        write_raw(
+            &repo,
+            "refs/issues/3",
+            "id = 1\ntitle = \"Impostor\"\nauthor = \"mallory\"\nstatus = \"open\"\ncreated = 0\n",
+        )
+        .await;
+
+        let issues = list(&repo).await.unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].title, "It breaks");
+
+        // Numbers are never reused, even for issues that can't be read.
+        assert_eq!(next_id(&repo).await.unwrap(), 4);
+    }
+}
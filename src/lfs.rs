@@ -0,0 +1,439 @@
+use std::{
+    fs::{create_dir_all, metadata, read_dir, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The most data a pkt-line can carry.
+pub const MAX_PACKET_DATA: usize = 65516;
+
+/// Git LFS objects live inside their bare repo, laid out the way git-lfs keeps them locally:
+/// `lfs/objects/ab/cd/abcd...`. They move and go away along with the repo.
+pub struct LfsStore {
+    dir: PathBuf,
+}
+
+/// An object on its way in, checked against its ID before it's stored.
+pub struct Upload {
+    file: NamedTempFile,
+    hasher: Sha256,
+    size: u64,
+}
+
+pub enum Packet {
+    Data(Vec<u8>),
+    Flush,
+    Delim,
+}
+
+pub fn packet(data: &[u8]) -> Vec<u8> {
+    let mut packet = format!("{:04x}", data.len() + 4).into_bytes();
+    packet.extend_from_slice(data);
+    packet
+}
+
+pub fn line(text: &str) -> Vec<u8> {
+    packet(format!("{}\n", text).as_bytes())
+}
+
+pub const FLUSH: &[u8] = b"0000";
+pub const DELIM: &[u8] = b"0001";
+
+pub async fn read_packet(input: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<Packet>> {
+    let mut length = [0u8; 4];
+    match input.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = usize::from_str_radix(std::str::from_utf8(&length)?, 16)?;
+    match length {
+        0 => Ok(Some(Packet::Flush)),
+        1 => Ok(Some(Packet::Delim)),
+        2..=4 => Err(anyhow!("Invalid pkt-line length {}", length)),
+        _ => {
+            let mut data = vec![0u8; length - 4];
+            input.read_exact(&mut data).await?;
+            Ok(Some(Packet::Data(data)))
+        }
+    }
+}
+
+pub fn packet_text(data: Vec<u8>) -> anyhow::Result<String> {
+    let text = String::from_utf8(data)?;
+    Ok(text.strip_suffix('\n').unwrap_or(&text).to_string())
+}
+
+/// Object IDs are SHA-256 hashes, which also keeps them from escaping the store.
+pub fn is_valid_oid(oid: &str) -> bool {
+    oid.len() == 64
+        && oid
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+impl LfsStore {
+    pub fn open(repo_path: &Path) -> Self {
+        Self {
+            dir: repo_path.join("lfs"),
+        }
+    }
+
+    fn object_path(&self, oid: &str) -> PathBuf {
+        self.dir
+            .join("objects")
+            .join(&oid[0..2])
+            .join(&oid[2..4])
+            .join(oid)
+    }
+
+    /// The size of a stored object, if it's there.
+    pub fn size(&self, oid: &str) -> Option<u64> {
+        metadata(self.object_path(oid)).ok().map(|m| m.len())
+    }
+
+    pub fn read(&self, oid: &str) -> anyhow::Result<File> {
+        Ok(File::open(self.object_path(oid))?)
+    }
+
+    /// How many bytes the repo's objects take up.
+    pub fn usage(&self) -> anyhow::Result<u64> {
+        fn walk(dir: &Path) -> anyhow::Result<u64> {
+            let mut total = 0;
+            for entry in read_dir(dir)? {
+                let entry = entry?;
+                total += match entry.file_type()?.is_dir() {
+                    true => walk(&entry.path())?,
+                    false => entry.metadata()?.len(),
+                };
+            }
+            Ok(total)
+        }
+
+        let objects = self.dir.join("objects");
+        match objects.exists() {
+            true => walk(&objects),
+            false => Ok(0),
+        }
+    }
+
+    /// Every stored object's ID and size.
+    pub fn objects(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        fn walk(dir: &Path, objects: &mut Vec<(String, u64)>) -> anyhow::Result<()> {
+            for entry in read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if entry.file_type()?.is_dir() {
+                    walk(&entry.path(), objects)?;
+                } else if is_valid_oid(&name) {
+                    objects.push((name, entry.metadata()?.len()));
+                }
+            }
+            Ok(())
+        }
+
+        let mut objects = Vec::new();
+        let dir = self.dir.join("objects");
+        if dir.exists() {
+            walk(&dir, &mut objects)?;
+        }
+        Ok(objects)
+    }
+
+    pub fn start_upload(&self) -> anyhow::Result<Upload> {
+        // On the same filesystem as the objects, so finishing is just a rename.
+        let incomplete = self.dir.join("incomplete");
+        create_dir_all(&incomplete)?;
+        Ok(Upload {
+            file: NamedTempFile::new_in(incomplete)?,
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+}
+
+impl Upload {
+    /// How much has been written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Stores the object, as long as it's what the client said it would be.
+    pub fn finish(self, store: &LfsStore, oid: &str, size: u64) -> anyhow::Result<()> {
+        if self.size != size {
+            return Err(anyhow!("Expected {} bytes, got {}", size, self.size));
+        }
+        let hash: String = self
+            .hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if hash != oid {
+            return Err(anyhow!("The object's contents don't match its ID"));
+        }
+
+        let path = store.object_path(oid);
+        create_dir_all(path.parent().unwrap())?;
+        self.file.persist(path)?;
+        Ok(())
+    }
+}
+
+/// Reads a `git-lfs-transfer` reply up to its flush, failing unless its status is 200. Returns
+/// the lines after the status.
+async fn read_reply(input: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    loop {
+        match read_packet(input).await? {
+            Some(Packet::Data(data)) => lines.push(packet_text(data)?),
+            Some(Packet::Delim) => {}
+            Some(Packet::Flush) => break,
+            None => return Err(anyhow!("The server hung up mid-reply")),
+        }
+    }
+    match lines.first().map(String::as_str) {
+        Some("status 200") => Ok(lines.split_off(1)),
+        Some(status) => Err(anyhow!("{}: {}", status, lines[1..].join(" "))),
+        None => Err(anyhow!("Empty reply")),
+    }
+}
+
+/// Sends a store's objects to another server's `git-lfs-transfer <repo> upload`, as git-lfs
+/// would, skipping any it already has. Returns how many were sent.
+pub async fn upload_objects(
+    store: &LfsStore,
+    input: &mut (impl AsyncRead + Unpin),
+    output: &mut (impl AsyncWrite + Unpin),
+) -> anyhow::Result<usize> {
+    // Its capabilities, which there's nothing to do with.
+    while let Some(Packet::Data(_)) = read_packet(input).await? {}
+    output
+        .write_all(&[line("version 1"), FLUSH.to_vec()].concat())
+        .await?;
+    read_reply(input).await?;
+
+    let objects = store.objects()?;
+    let mut batch = vec![line("batch"), line("hash-algo=sha256"), DELIM.to_vec()];
+    batch.extend(
+        objects
+            .iter()
+            .map(|(oid, size)| line(&format!("{} {}", oid, size))),
+    );
+    batch.push(FLUSH.to_vec());
+    output.write_all(&batch.concat()).await?;
+    let wanted: Vec<String> = read_reply(input)
+        .await?
+        .iter()
+        .filter_map(
+            |reply| match reply.split(' ').collect::<Vec<_>>().as_slice() {
+                [oid, _, "upload"] => Some(oid.to_string()),
+                _ => None,
+            },
+        )
+        .collect();
+
+    for (oid, size) in objects.iter().filter(|(oid, _)| wanted.contains(oid)) {
+        output
+            .write_all(
+                &[
+                    line(&format!("put-object {}", oid)),
+                    line(&format!("size={}", size)),
+                    DELIM.to_vec(),
+                ]
+                .concat(),
+            )
+            .await?;
+        let mut file = tokio::fs::File::from_std(store.read(oid)?);
+        let mut buf = vec![0u8; MAX_PACKET_DATA];
+        loop {
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            output.write_all(&packet(&buf[..read])).await?;
+        }
+        output.write_all(FLUSH).await?;
+        read_reply(input).await?;
+    }
+
+    output
+        .write_all(&[line("quit"), FLUSH.to_vec()].concat())
+        .await?;
+    read_reply(input).await?;
+    Ok(wanted.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn oid_of(data: &[u8]) -> String {
+        Sha256::digest(data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn store_object(store: &LfsStore, data: &[u8]) -> String {
+        let oid = oid_of(data);
+        let mut upload = store.start_upload().unwrap();
+        upload.write(data).unwrap();
+        upload.finish(store, &oid, data.len() as u64).unwrap();
+        oid
+    }
+
+    #[test]
+    fn object_ids_are_lowercase_sha256_hex() {
+        assert!(is_valid_oid(&"a1".repeat(32)));
+        assert!(!is_valid_oid(&"A1".repeat(32)));
+        assert!(!is_valid_oid(&"a1".repeat(31)));
+        assert!(!is_valid_oid(&format!("../{}", "a".repeat(61))));
+        assert!(!is_valid_oid(""));
+    }
+
+    #[tokio::test]
+    async fn packets_read_back_as_written() {
+        let written = [
+            line("version 1"),
+            DELIM.to_vec(),
+            packet(b"\x00\x01"),
+            FLUSH.to_vec(),
+        ]
+        .concat();
+        assert_eq!(&written[..4], b"000e");
+
+        let mut input = Cursor::new(written);
+        match read_packet(&mut input).await.unwrap() {
+            Some(Packet::Data(data)) => assert_eq!(packet_text(data).unwrap(), "version 1"),
+            _ => panic!("Expected data"),
+        }
+        assert!(matches!(
+            read_packet(&mut input).await.unwrap(),
+            Some(Packet::Delim)
+        ));
+        match read_packet(&mut input).await.unwrap() {
+            Some(Packet::Data(data)) => assert_eq!(data, b"\x00\x01"),
+            _ => panic!("Expected data"),
+        }
+        assert!(matches!(
+            read_packet(&mut input).await.unwrap(),
+            Some(Packet::Flush)
+        ));
+        assert!(read_packet(&mut input).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn bad_packets_are_rejected() {
+        assert!(read_packet(&mut Cursor::new(b"0003".to_vec()))
+            .await
+            .is_err());
+        assert!(read_packet(&mut Cursor::new(b"zzzz".to_vec()))
+            .await
+            .is_err());
+        // Cut off partway through.
+        assert!(read_packet(&mut Cursor::new(b"0010abc".to_vec()))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn uploads_are_checked_before_they_are_stored() {
+        let temp_dir = tempdir().unwrap();
+        let store = LfsStore::open(temp_dir.path());
+        let data = b"some large file";
+
+        let mut upload = store.start_upload().unwrap();
+        upload.write(data).unwrap();
+        assert!(upload.finish(&store, &oid_of(data), 3).is_err());
+
+        let mut upload = store.start_upload().unwrap();
+        upload.write(data).unwrap();
+        assert!(upload
+            .finish(&store, &oid_of(b"something else"), data.len() as u64)
+            .is_err());
+        assert!(store.objects().unwrap().is_empty());
+
+        let oid = store_object(&store, data);
+        assert_eq!(store.size(&oid), Some(data.len() as u64));
+        assert_eq!(store.objects().unwrap(), vec![(oid, data.len() as u64)]);
+        assert_eq!(store.usage().unwrap(), data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn only_objects_the_server_wants_are_uploaded() {
+        let temp_dir = tempdir().unwrap();
+        let store = LfsStore::open(temp_dir.path());
+        let wanted = store_object(&store, b"new to the server");
+        let had = store_object(&store, b"already on the server");
+
+        // Everything the server says, in order: its capabilities, then replies to the version,
+        // batch, the one upload and quitting.
+        let replies = [
+            line("version=1"),
+            FLUSH.to_vec(),
+            line("status 200"),
+            FLUSH.to_vec(),
+            line("status 200"),
+            DELIM.to_vec(),
+            line(&format!("{} 17 upload", wanted)),
+            line(&format!("{} 21 noop", had)),
+            FLUSH.to_vec(),
+            line("status 200"),
+            FLUSH.to_vec(),
+            line("status 200"),
+            FLUSH.to_vec(),
+        ]
+        .concat();
+        let mut output = Vec::new();
+        let sent = upload_objects(&store, &mut Cursor::new(replies), &mut output)
+            .await
+            .unwrap();
+        assert_eq!(sent, 1);
+
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains(&format!("put-object {}", wanted)));
+        assert!(output.contains("new to the server"));
+        assert!(!output.contains(&format!("put-object {}", had)));
+        assert!(output.ends_with("0009quit\n0000"));
+    }
+
+    #[tokio::test]
+    async fn errors_from_the_server_are_passed_on() {
+        let temp_dir = tempdir().unwrap();
+        let store = LfsStore::open(temp_dir.path());
+        store_object(&store, b"too big");
+
+        let replies = [
+            line("version=1"),
+            FLUSH.to_vec(),
+            line("status 200"),
+            FLUSH.to_vec(),
+            line("status 507"),
+            DELIM.to_vec(),
+            line("Over quota."),
+            FLUSH.to_vec(),
+        ]
+        .concat();
+        let error = upload_objects(&store, &mut Cursor::new(replies), &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "status 507: Over quota.");
+    }
+}
@@ -1,13 +1,21 @@
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 
 mod state;
 use env_logger::Env;
+use git::find_repos;
 use log::{error, info};
 use state::State;
 use tokio::sync::Mutex;
 
 mod config;
 mod git;
+mod issues;
+mod lfs;
+mod merge_requests;
+mod mirror;
+mod patches;
+mod replication;
+mod search;
 mod site;
 mod ssh;
 mod utils;
@@ -16,8 +24,35 @@ mod vars;
 async fn start() -> anyhow::Result<()> {
     info!("Loading state...");
     let state = State::new().await?;
+    let rebuild_sites = state.server_config.rebuild_sites_on_start.unwrap_or(false);
     let state = Arc::new(Mutex::new(state));
 
+    if rebuild_sites {
+        info!("Rebuilding static sites...");
+        for repo_path in find_repos(Path::new("."))? {
+            match site::force_rebuild_site(&state, &repo_path).await {
+                Ok(()) => info!("Rebuilt {}", repo_path.display()),
+                Err(e) => error!("Couldn't rebuild {}: {:#}", repo_path.display(), e),
+            }
+        }
+    }
+
+    info!("Reconciling static sites...");
+    if let Err(e) = site::reconcile_sites(&state).await {
+        error!("Couldn't reconcile static sites: {:#}", e);
+    }
+
+    info!("Updating search indexes...");
+    if let Err(e) = search::update_all_indexes().await {
+        error!("Couldn't update search indexes: {:#}", e);
+    }
+
+    merge_requests::install_hooks()?;
+
+    tokio::spawn(mirror::run_scheduler(state.clone()));
+    tokio::spawn(replication::run_sync(state.clone()));
+    tokio::spawn(patches::run_intake(state.clone()));
+
     info!("Starting server...");
     let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
     ssh::start_server(state).await?;
@@ -0,0 +1,493 @@
+use std::{
+    collections::HashMap,
+    env::current_dir,
+    fs::{create_dir_all, read_to_string, set_permissions, write, Permissions},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{git::Repo, mirror::now, vars::*};
+
+/// Runs before every push is accepted, noting down its `refs/for/...` refs and push options for
+/// the server to pick up afterwards. Pushes from people who can only open merge requests are
+/// turned away if they touch anything else.
+const PRE_RECEIVE_HOOK: &str = r#"#!/bin/sh
+# Written by gitenator, which reads what's noted here once the push is done.
+i=0
+while [ "$i" -lt "${GIT_PUSH_OPTION_COUNT:-0}" ]; do
+    eval "option=\$GIT_PUSH_OPTION_$i"
+    printf 'option %s\n' "$option" >> "$GITENATOR_PUSH_FILE"
+    i=$((i + 1))
+done
+while read -r old new ref; do
+    case "$ref" in
+    refs/for/*) printf 'ref %s %s\n' "$new" "$ref" >> "$GITENATOR_PUSH_FILE" ;;
+    *)
+        if [ -n "$GITENATOR_ONLY_MERGE_REQUESTS" ]; then
+            echo "$GITENATOR_ONLY_MERGE_REQUESTS" >&2
+            exit 1
+        fi
+        ;;
+    esac
+done
+"#;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Open,
+    Merged,
+    Closed,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Open => "open",
+            Status::Merged => "merged",
+            Status::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MergeRequest {
+    pub id: u64,
+    pub title: String,
+    pub description: Option<String>,
+    pub author: String,
+    /// The branch it's asking to be merged into.
+    pub target: String,
+    /// Pushing to the same topic again updates the request.
+    pub topic: String,
+    pub head: String,
+    pub status: Status,
+    /// Unix timestamps.
+    pub created: u64,
+    pub updated: u64,
+    /// Members who've been told about it.
+    #[serde(default)]
+    pub notified: Vec<String>,
+}
+
+impl MergeRequest {
+    /// Where its commits are kept, out of the way of branches and tags.
+    pub fn head_ref(&self) -> String {
+        format!("refs/merge-requests/{}/head", self.id)
+    }
+}
+
+const MERGE_REQUESTS_REF: &str = "refs/merge-requests";
+
+/// Every request is kept in one `merge-requests.toml`, on a chain of commits like issues are, so
+/// it goes wherever the repo's refs do.
+const STATE_REF: &str = "refs/merge-requests/state";
+
+/// A repo's merge requests.
+#[derive(Serialize, Deserialize, Default)]
+pub struct MergeRequests {
+    #[serde(default)]
+    pub requests: Vec<MergeRequest>,
+    /// The commit they were loaded from.
+    #[serde(skip)]
+    head: Option<String>,
+}
+
+impl MergeRequests {
+    pub async fn load(repo_path: &Path) -> anyhow::Result<Self> {
+        let repo = Repo::open(repo_path);
+        let Some(head) = repo.refs(&[STATE_REF]).await?.remove(STATE_REF) else {
+            return Ok(Self::default());
+        };
+        let mut requests: Self =
+            toml::from_str(&repo.show_file(&head, MERGE_REQUESTS_FILE).await?)?;
+        requests.head = Some(head);
+        Ok(requests)
+    }
+
+    /// Records the requests as they now are, with a message saying what changed.
+    pub async fn save(
+        &mut self,
+        repo_path: &Path,
+        message: &str,
+        author: &str,
+    ) -> anyhow::Result<()> {
+        let repo = Repo::open(repo_path);
+        let contents = toml::to_string(self)?;
+        let commit = repo
+            .write_commit(
+                &[(MERGE_REQUESTS_FILE, &contents)],
+                self.head.as_deref(),
+                message,
+                author,
+            )
+            .await?;
+        let old = self.head.as_deref().unwrap_or(NULL_OBJECT);
+        if !repo.swap_ref(STATE_REF, &commit, old).await? {
+            return Err(anyhow!(
+                "The merge requests were changed at the same time, try again."
+            ));
+        }
+        self.head = Some(commit);
+        Ok(())
+    }
+
+    /// Looks up a request by its number, with or without the `!`.
+    pub fn get_mut(&mut self, id: &str) -> anyhow::Result<&mut MergeRequest> {
+        let not_found = || anyhow!("There's no merge request {}.", id);
+        let id: u64 = id
+            .trim_start_matches('!')
+            .parse()
+            .map_err(|_| not_found())?;
+        self.requests
+            .iter_mut()
+            .find(|request| request.id == id)
+            .ok_or_else(not_found)
+    }
+
+    /// Open requests a member hasn't been told about yet, which they now have been. Authors
+    /// already know about their own.
+    pub fn notify(&mut self, member: &str) -> Vec<MergeRequest> {
+        let mut unseen = Vec::new();
+        for request in &mut self.requests {
+            if request.status == Status::Open
+                && request.author != member
+                && !request.notified.iter().any(|m| m == member)
+            {
+                request.notified.push(member.to_string());
+                unseen.push(request.clone());
+            }
+        }
+        unseen
+    }
+}
+
+/// Writes out the hooks pushes run with, if they've changed. They're shared by every repo.
+pub fn install_hooks() -> anyhow::Result<()> {
+    let dir = Path::new(HOOKS_DIR);
+    let hook = dir.join("pre-receive");
+    if read_to_string(&hook).ok().as_deref() != Some(PRE_RECEIVE_HOOK) {
+        create_dir_all(dir)?;
+        write(&hook, PRE_RECEIVE_HOOK)?;
+        set_permissions(&hook, Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Git config set through the environment, for just the one command.
+fn config_env(config: &[(&str, &str)]) -> Vec<(String, String)> {
+    let mut env = vec![("GIT_CONFIG_COUNT".to_string(), config.len().to_string())];
+    for (i, (key, value)) in config.iter().enumerate() {
+        env.push((format!("GIT_CONFIG_KEY_{}", i), key.to_string()));
+        env.push((format!("GIT_CONFIG_VALUE_{}", i), value.to_string()));
+    }
+    env
+}
+
+/// Environment for `git-receive-pack` to run the hooks with. The hook notes the push down in
+/// `push_file`, and with `only_merge_requests` set, rejects anything but merge requests with it.
+/// Merge requests are only changed through the server, so their refs can't be pushed to.
+pub fn receive_pack_env(
+    push_file: &Path,
+    only_merge_requests: Option<&str>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let hooks = current_dir()?.join(HOOKS_DIR).display().to_string();
+    let mut env = config_env(&[
+        ("core.hooksPath", &hooks),
+        ("receive.advertisePushOptions", "true"),
+        ("receive.hideRefs", MERGE_REQUESTS_REF),
+    ]);
+    env.push((
+        "GITENATOR_PUSH_FILE".to_string(),
+        push_file.display().to_string(),
+    ));
+    if let Some(message) = only_merge_requests {
+        env.push((
+            "GITENATOR_ONLY_MERGE_REQUESTS".to_string(),
+            message.to_string(),
+        ));
+    }
+    Ok(env)
+}
+
+/// Environment for `git-upload-pack` when someone other than a member fetches, hiding the merge
+/// requests, whose commits come from anyone.
+pub fn upload_pack_env() -> Vec<(String, String)> {
+    config_env(&[("uploadpack.hideRefs", MERGE_REQUESTS_REF)])
+}
+
+/// Reads what the hook noted down about a push: its push options, and the `refs/for/...` refs
+/// it pushed with the objects they were pushed at.
+fn parse_push_file(contents: &str) -> (HashMap<String, String>, Vec<(String, String)>) {
+    let mut options = HashMap::new();
+    let mut refs = Vec::new();
+    for line in contents.lines() {
+        match line.split_once(' ') {
+            Some(("option", option)) => {
+                let (key, value) = option.split_once('=').unwrap_or((option, ""));
+                options.insert(key.to_string(), value.to_string());
+            }
+            Some(("ref", pushed)) => {
+                if let Some((object, name)) = pushed.split_once(' ') {
+                    refs.push((object.to_string(), name.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    (options, refs)
+}
+
+/// The branch a `refs/for/...` ref asks to be merged into, and its topic: `refs/for/main/topic`,
+/// or `refs/for/main` with `-o topic=...`.
+fn target_and_topic<'a>(
+    name: &'a str,
+    options: &'a HashMap<String, String>,
+) -> Option<(&'a str, &'a str)> {
+    let rest = name.trim_start_matches("refs/for/");
+    match options.get("topic") {
+        Some(topic) => Some((rest, topic.as_str())),
+        None => rest.rsplit_once('/'),
+    }
+}
+
+/// Turns a push's `refs/for/<branch>/<topic>` refs into merge requests, updating the author's
+/// open one for the same topic if there is one. The refs themselves are only a way in, so they're
+/// deleted. Returns what to tell the pusher.
+pub async fn receive_push(
+    repo_path: &Path,
+    push_file: &Path,
+    author: &str,
+) -> anyhow::Result<Vec<String>> {
+    let (options, refs) = parse_push_file(&read_to_string(push_file)?);
+    if refs.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let repo = Repo::open(repo_path);
+    let branches = repo.branches().await?;
+    let mut requests = MergeRequests::load(repo_path).await?;
+    let mut messages = Vec::new();
+    let mut changes = Vec::new();
+    for (head, name) in refs {
+        if head == NULL_OBJECT {
+            continue;
+        }
+        repo.delete_ref(&name).await?;
+
+        let Some((target, topic)) = target_and_topic(&name, &options) else {
+            messages.push(format!("Give {} a topic, like {}/my-change.", name, name));
+            continue;
+        };
+        if !branches.iter().any(|branch| branch == target) {
+            messages.push(format!("There's no branch {} to merge into.", target));
+            continue;
+        }
+
+        let now = now();
+        let existing = requests.requests.iter_mut().find(|request| {
+            request.status == Status::Open
+                && request.author == author
+                && request.target == target
+                && request.topic == topic
+        });
+        let request = match existing {
+            Some(request) => {
+                request.head = head.clone();
+                request.updated = now;
+                if let Some(title) = options.get("title") {
+                    request.title = title.clone();
+                }
+                if let Some(description) = options.get("description") {
+                    request.description = Some(description.clone());
+                }
+                messages.push(format!(
+                    "Updated merge request !{}: {}",
+                    request.id, request.title
+                ));
+                request
+            }
+            None => {
+                let title = match options.get("title") {
+                    Some(title) => title.clone(),
+                    None => repo
+                        .log(&["-1", &head])
+                        .await?
+                        .first()
+                        .map(|commit| commit.summary.clone())
+                        .unwrap_or_default(),
+                };
+                let id = requests.requests.iter().map(|r| r.id).max().unwrap_or(0) + 1;
+                requests.requests.push(MergeRequest {
+                    id,
+                    title,
+                    description: options.get("description").cloned(),
+                    author: author.to_string(),
+                    target: target.to_string(),
+                    topic: topic.to_string(),
+                    head: head.clone(),
+                    status: Status::Open,
+                    created: now,
+                    updated: now,
+                    notified: Vec::new(),
+                });
+                let request = requests.requests.last_mut().unwrap();
+                messages.push(format!(
+                    "Opened merge request !{}: {}",
+                    request.id, request.title
+                ));
+                request
+            }
+        };
+        repo.update_ref(&request.head_ref(), &head).await?;
+        changes.push(format!("!{}", request.id));
+    }
+
+    if !changes.is_empty() {
+        let message = format!("Pushed to {}", changes.join(", "));
+        requests.save(repo_path, &message, author).await?;
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn request(id: u64, author: &str, status: Status) -> MergeRequest {
+        MergeRequest {
+            id,
+            title: format!("Change {}", id),
+            description: None,
+            author: author.to_string(),
+            target: "main".to_string(),
+            topic: "change".to_string(),
+            head: "a".repeat(40),
+            status,
+            created: 0,
+            updated: 0,
+            notified: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn push_files_have_options_and_refs() {
+        let (options, refs) = parse_push_file(
+            "option topic=fix-typo\n\
+             option title=Fix the typo\n\
+             option draft\n\
+             ref 1234 refs/for/main\n\
+             ref 5678\n\
+             something else\n",
+        );
+        assert_eq!(options["topic"], "fix-typo");
+        assert_eq!(options["title"], "Fix the typo");
+        assert_eq!(options["draft"], "");
+        assert_eq!(
+            refs,
+            vec![("1234".to_string(), "refs/for/main".to_string())]
+        );
+    }
+
+    #[test]
+    fn topics_come_from_the_ref_or_an_option() {
+        let none = HashMap::new();
+        assert_eq!(
+            target_and_topic("refs/for/main/fix-typo", &none),
+            Some(("main", "fix-typo"))
+        );
+        // Branches can have slashes in them, topics can't.
+        assert_eq!(
+            target_and_topic("refs/for/release/1.0/fix-typo", &none),
+            Some(("release/1.0", "fix-typo"))
+        );
+        assert_eq!(target_and_topic("refs/for/main", &none), None);
+
+        let options = HashMap::from([("topic".to_string(), "fix-typo".to_string())]);
+        assert_eq!(
+            target_and_topic("refs/for/release/1.0", &options),
+            Some(("release/1.0", "fix-typo"))
+        );
+    }
+
+    #[test]
+    fn requests_are_looked_up_by_number() {
+        let mut requests = MergeRequests {
+            requests: vec![
+                request(1, "bob", Status::Open),
+                request(2, "bob", Status::Open),
+            ],
+            head: None,
+        };
+        assert_eq!(requests.get_mut("2").unwrap().id, 2);
+        assert_eq!(requests.get_mut("!1").unwrap().id, 1);
+        assert!(requests.get_mut("3").is_err());
+        assert!(requests.get_mut("!one").is_err());
+    }
+
+    #[test]
+    fn members_are_told_about_open_requests_once() {
+        let mut requests = MergeRequests {
+            requests: vec![
+                request(1, "bob", Status::Open),
+                request(2, "alice", Status::Open),
+                request(3, "bob", Status::Merged),
+            ],
+            head: None,
+        };
+        let ids = |requests: Vec<MergeRequest>| requests.iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids(requests.notify("alice")), vec![1]);
+        assert!(requests.notify("alice").is_empty());
+        assert_eq!(ids(requests.notify("carol")), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn requests_are_kept_on_a_chain_of_commits() {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("demo.git");
+        let repo = Repo::create_bare(&repo_path).await.unwrap();
+
+        let mut requests = MergeRequests::load(&repo_path).await.unwrap();
+        assert!(requests.requests.is_empty());
+        requests.requests.push(request(1, "bob", Status::Open));
+        requests
+            .save(&repo_path, "Pushed to !1", "bob")
+            .await
+            .unwrap();
+
+        let mut requests = MergeRequests::load(&repo_path).await.unwrap();
+        requests.get_mut("1").unwrap().status = Status::Merged;
+        requests
+            .save(&repo_path, "Merged !1", "admin")
+            .await
+            .unwrap();
+
+        let requests = MergeRequests::load(&repo_path).await.unwrap();
+        assert!(requests.requests[0].status == Status::Merged);
+        let history = repo.log(&[STATE_REF]).await.unwrap();
+        let summaries: Vec<_> = history.iter().map(|c| c.summary.as_str()).collect();
+        assert_eq!(summaries, vec!["Merged !1", "Pushed to !1"]);
+    }
+
+    #[tokio::test]
+    async fn saving_over_a_newer_state_fails() {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("demo.git");
+        Repo::create_bare(&repo_path).await.unwrap();
+
+        let mut first = MergeRequests::load(&repo_path).await.unwrap();
+        let mut second = MergeRequests::load(&repo_path).await.unwrap();
+        first.requests.push(request(1, "bob", Status::Open));
+        first.save(&repo_path, "Pushed to !1", "bob").await.unwrap();
+        second.requests.push(request(1, "alice", Status::Open));
+        assert!(second
+            .save(&repo_path, "Pushed to !1", "alice")
+            .await
+            .is_err());
+    }
+}
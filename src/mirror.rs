@@ -0,0 +1,445 @@
+use std::{
+    env::current_dir,
+    fs::{read_to_string, remove_file, write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+use tokio::{sync::Mutex, time::timeout};
+
+use crate::{
+    config::{
+        repo::{
+            committed_repo_config, pin_repo_config, pinned_config_path, MirrorConfig,
+            PushMirrorConfig, RepoConfig,
+        },
+        server::{Credential, ServerConfig},
+    },
+    git::{find_repos, Repo},
+    replication::replicate,
+    search::update_index,
+    site::rebuild_site,
+    state::State,
+    vars::*,
+};
+
+/// How often the scheduler looks for mirrors that are due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fetches and pushes that take longer than this are given up on, so one slow remote can't hold
+/// up the rest.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+pub const DEFAULT_INTERVAL_MINUTES: u64 = 60;
+
+/// How many times a push mirror is tried, waiting twice as long each time.
+pub const PUSH_ATTEMPTS: u32 = 3;
+pub const PUSH_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Only the most recent push mirror attempts are kept.
+const MAX_PUSH_LOG_LINES: usize = 1000;
+
+/// How a mirror's syncs have been going, kept in the bare repo.
+#[derive(Serialize, Deserialize, Default)]
+pub struct MirrorStatus {
+    /// Unix timestamps.
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    /// Why the last attempt failed, if it did.
+    pub error: Option<String>,
+}
+
+impl MirrorStatus {
+    pub fn load(repo_path: &Path) -> anyhow::Result<Self> {
+        let path = repo_path.join(MIRROR_STATUS_FILE);
+        match path.exists() {
+            true => Ok(toml::from_str(&read_to_string(path)?)?),
+            false => Ok(Self::default()),
+        }
+    }
+
+    fn save(&self, repo_path: &Path) -> anyhow::Result<()> {
+        write(repo_path.join(MIRROR_STATUS_FILE), toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn is_due(&self, config: &MirrorConfig, now: u64) -> bool {
+        let interval = config
+            .interval_minutes
+            .unwrap_or(DEFAULT_INTERVAL_MINUTES)
+            .max(1);
+        match self.last_attempt {
+            Some(last) => now >= last + interval * 60,
+            None => true,
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Local remotes could be other repos on this server, which would get around their permissions,
+/// so they need turning on and can't be inside the data directory.
+fn check_remote(url: &str, allow_local: bool) -> anyhow::Result<()> {
+    if url.starts_with('-') {
+        return Err(anyhow!("Invalid mirror URL {}", url));
+    }
+
+    // `host:path` is SSH, as long as there's no slash before the colon.
+    let is_scp_like = url
+        .split_once(':')
+        .is_some_and(|(host, _)| !host.contains('/'));
+    let local_path = match url.strip_prefix("file://") {
+        Some(path) => Some(path),
+        None if !url.contains("://") && !is_scp_like => Some(url),
+        None => None,
+    };
+
+    if let Some(path) = local_path {
+        if !allow_local {
+            return Err(anyhow!(
+                "Mirroring local repos isn't allowed on this server."
+            ));
+        }
+        let path = PathBuf::from(path)
+            .canonicalize()
+            .with_context(|| format!("Couldn't find {}", path))?;
+        if path.starts_with(current_dir()?.canonicalize()?) {
+            return Err(anyhow!("Mirrors can't fetch from repos on this server."));
+        }
+    }
+    Ok(())
+}
+
+/// Fetches a mirror from its upstream, recording how it went. Returns whether anything changed.
+pub async fn sync_mirror(
+    repo_path: &Path,
+    config: &MirrorConfig,
+    allow_local: bool,
+) -> anyhow::Result<bool> {
+    let repo = Repo::open(repo_path);
+    let before = repo.refs(&[]).await?;
+
+    let mut status = MirrorStatus::load(repo_path)?;
+    status.last_attempt = Some(now());
+    let result = match check_remote(&config.url, allow_local) {
+        Ok(()) => match timeout(FETCH_TIMEOUT, repo.fetch_mirror(&config.url)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("Timed out fetching from {}", config.url)),
+        },
+        Err(e) => Err(e),
+    };
+    match &result {
+        Ok(()) => {
+            status.last_success = status.last_attempt;
+            status.error = None;
+        }
+        Err(e) => status.error = Some(format!("{:#}", e)),
+    }
+    status.save(repo_path)?;
+    result?;
+
+    Ok(repo.refs(&[]).await? != before)
+}
+
+/// Brings a mirror up to date, along with its search index and site.
+pub async fn update_mirror(
+    state: &Arc<Mutex<State>>,
+    repo_path: &Path,
+    config: &MirrorConfig,
+) -> anyhow::Result<bool> {
+    let allow_local = state
+        .lock()
+        .await
+        .server_config
+        .allow_local_mirrors
+        .unwrap_or(false);
+    pin_repo_config(repo_path).await?;
+    let changed = sync_mirror(repo_path, config, allow_local).await?;
+    if changed {
+        update_index(repo_path).await?;
+        rebuild_site(state, repo_path).await?;
+        replicate(state, repo_path).await;
+    }
+    Ok(changed)
+}
+
+/// Turns a mirror back into a normal repo. Its config goes back on the default branch, without
+/// the mirror section, so it's changed by pushing again.
+pub async fn stop_mirror(repo_path: &Path, mut config: RepoConfig) -> anyhow::Result<()> {
+    config.mirror = None;
+
+    let temp_dir = tempdir()?;
+    let clone_dir = temp_dir.path().join(repo_path);
+    let repo = Repo::clone(repo_path, &clone_dir).await?;
+    write(clone_dir.join(REPO_CONFIG_FILE), toml::to_string(&config)?)?;
+    repo.push_changes("chore: stop mirroring").await?;
+
+    remove_file(pinned_config_path(repo_path))?;
+    if repo_path.join(MIRROR_STATUS_FILE).exists() {
+        remove_file(repo_path.join(MIRROR_STATUS_FILE))?;
+    }
+    Ok(())
+}
+
+/// Fetches every mirror that's due, forever.
+pub async fn run_scheduler(state: Arc<Mutex<State>>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sync_due_mirrors(&state).await {
+            error!("Couldn't sync mirrors: {:#}", e);
+        }
+    }
+}
+
+async fn sync_due_mirrors(state: &Arc<Mutex<State>>) -> anyhow::Result<()> {
+    // Replicas get their mirrors from the primary.
+    if state.lock().await.replica.is_some() {
+        return Ok(());
+    }
+
+    let now = now();
+    for repo_path in find_repos(Path::new("."))? {
+        let Ok(Some(config)) = committed_repo_config(&repo_path).await else {
+            continue;
+        };
+        let Some(mirror) = config.mirror else {
+            continue;
+        };
+        if !MirrorStatus::load(&repo_path)?.is_due(&mirror, now) {
+            continue;
+        }
+
+        match update_mirror(state, &repo_path, &mirror).await {
+            Ok(true) => info!("Synced mirror {}", repo_path.display()),
+            Ok(false) => {}
+            Err(e) => error!("Couldn't sync mirror {}: {:#}", repo_path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+/// One try at a push mirror, as kept in the repo's log.
+pub struct PushAttempt {
+    pub time: u64,
+    pub url: String,
+    pub error: Option<String>,
+}
+
+impl PushAttempt {
+    /// Reads the log, oldest first.
+    pub fn load_log(repo_path: &Path) -> anyhow::Result<Vec<Self>> {
+        let path = repo_path.join(PUSH_MIRROR_LOG_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        // `<time>\t<url>\tok` or `<time>\t<url>\tfailed: <error>`
+        Ok(read_to_string(path)?
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let time = fields.next()?.parse().ok()?;
+                let url = fields.next()?.to_string();
+                let error = fields.next()?.strip_prefix("failed: ").map(str::to_string);
+                Some(Self { time, url, error })
+            })
+            .collect())
+    }
+
+    fn append(&self, repo_path: &Path) -> anyhow::Result<()> {
+        let mut lines: Vec<String> = match repo_path.join(PUSH_MIRROR_LOG_FILE).exists() {
+            true => read_to_string(repo_path.join(PUSH_MIRROR_LOG_FILE))?
+                .lines()
+                .map(str::to_string)
+                .collect(),
+            false => Vec::new(),
+        };
+        let result = match &self.error {
+            // Kept to one line, like everything else in there.
+            Some(error) => format!("failed: {}", error.replace(['\n', '\t'], " ")),
+            None => "ok".to_string(),
+        };
+        lines.push(format!("{}\t{}\t{}", self.time, self.url, result));
+
+        let start = lines.len().saturating_sub(MAX_PUSH_LOG_LINES);
+        let text: String = lines[start..].iter().map(|l| format!("{}\n", l)).collect();
+        write(repo_path.join(PUSH_MIRROR_LOG_FILE), text)?;
+        Ok(())
+    }
+}
+
+/// An SSH command line that never asks anything, logging in with the given key if there is one.
+/// Hosts are trusted the first time they're seen.
+pub fn ssh_command(key: Option<&str>) -> String {
+    let mut command = "ssh -o BatchMode=yes -o StrictHostKeyChecking=accept-new".to_string();
+    if let Some(key) = key {
+        command += &format!(" -i '{}' -o IdentitiesOnly=yes", key.replace('\'', "'\\''"));
+    }
+    command
+}
+
+/// Environment for git to log in to a remote with.
+fn credential_env(credential: &Credential) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    if let Some(key) = &credential.ssh_key {
+        env.push(("GIT_SSH_COMMAND".to_string(), ssh_command(Some(key))));
+    }
+    if let (Some(username), Some(password)) = (&credential.username, &credential.password) {
+        // A credential helper that reads them back from the environment, keeping them off
+        // the command line.
+        let helper = r#"!f() { echo "username=$GITENATOR_USERNAME"; echo "password=$GITENATOR_PASSWORD"; }; f"#;
+        env.extend([
+            ("GIT_CONFIG_COUNT".to_string(), "1".to_string()),
+            (
+                "GIT_CONFIG_KEY_0".to_string(),
+                "credential.helper".to_string(),
+            ),
+            ("GIT_CONFIG_VALUE_0".to_string(), helper.to_string()),
+            ("GITENATOR_USERNAME".to_string(), username.clone()),
+            ("GITENATOR_PASSWORD".to_string(), password.clone()),
+        ]);
+    }
+    env
+}
+
+/// Tries pushing every ref to a push mirror once, and logs how it went.
+pub async fn push_to_mirror(
+    repo_path: &Path,
+    mirror: &PushMirrorConfig,
+    server_config: &ServerConfig,
+) -> anyhow::Result<()> {
+    let push = async {
+        check_remote(
+            &mirror.url,
+            server_config.allow_local_mirrors.unwrap_or(false),
+        )?;
+        let env = match &mirror.credential {
+            Some(name) => credential_env(
+                server_config
+                    .credential(name, repo_path)
+                    .ok_or_else(|| anyhow!("This repo can't use the credential {:?}", name))?,
+            ),
+            None => Vec::new(),
+        };
+        match timeout(
+            FETCH_TIMEOUT,
+            Repo::open(repo_path).push_mirror(&mirror.url, &env),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("Timed out pushing to {}", mirror.url)),
+        }
+    };
+    let result = push.await;
+
+    PushAttempt {
+        time: now(),
+        url: mirror.url.clone(),
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+    }
+    .append(repo_path)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn remote_urls_are_allowed() {
+        assert!(check_remote("https://github.com/alex/tool.git", false).is_ok());
+        assert!(check_remote("ssh://git@example.com/alex/tool.git", false).is_ok());
+        assert!(check_remote("git@example.com:alex/tool.git", false).is_ok());
+    }
+
+    #[test]
+    fn urls_cant_be_options() {
+        assert!(check_remote("-uploadpack=touch /tmp/x", true).is_err());
+        assert!(check_remote("--upload-pack=sh", false).is_err());
+    }
+
+    #[test]
+    fn local_paths_need_allowing() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        assert!(check_remote(path, false).is_err());
+        assert!(check_remote(&format!("file://{}", path), false).is_err());
+        // A slash before the colon makes it a path, not SSH.
+        assert!(check_remote("./host:path", false).is_err());
+
+        assert!(check_remote(path, true).is_ok());
+        assert!(check_remote(&format!("file://{}", path), true).is_ok());
+    }
+
+    #[test]
+    fn local_paths_cant_be_on_this_server() {
+        // Tests run from the crate, which stands in for the data directory.
+        assert!(check_remote(".", true).is_err());
+        assert!(check_remote("src/../src", true).is_err());
+        let here = current_dir().unwrap();
+        assert!(check_remote(&format!("file://{}", here.display()), true).is_err());
+    }
+
+    #[test]
+    fn mirrors_are_due_after_their_interval() {
+        let config = MirrorConfig {
+            url: "https://example.com/tool.git".to_string(),
+            interval_minutes: Some(30),
+        };
+        let mut status = MirrorStatus::default();
+        assert!(status.is_due(&config, 1000));
+
+        status.last_attempt = Some(1000);
+        assert!(!status.is_due(&config, 1000 + 29 * 60));
+        assert!(status.is_due(&config, 1000 + 30 * 60));
+
+        // However it's set, it's at most once a minute.
+        let config = MirrorConfig {
+            interval_minutes: Some(0),
+            ..config
+        };
+        assert!(!status.is_due(&config, 1030));
+        assert!(status.is_due(&config, 1060));
+    }
+
+    #[test]
+    fn push_logs_keep_one_line_per_attempt() {
+        let temp_dir = tempdir().unwrap();
+        let attempts = [
+            PushAttempt {
+                time: 1,
+                url: "https://example.com/a.git".to_string(),
+                error: None,
+            },
+            PushAttempt {
+                time: 2,
+                url: "https://example.com/b.git".to_string(),
+                error: Some("fatal:\tsomething\nwent wrong".to_string()),
+            },
+        ];
+        for attempt in &attempts {
+            attempt.append(temp_dir.path()).unwrap();
+        }
+
+        let log = PushAttempt::load_log(temp_dir.path()).unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].url, "https://example.com/a.git");
+        assert_eq!(log[0].error, None);
+        assert_eq!(log[1].time, 2);
+        assert_eq!(log[1].error.as_deref(), Some("fatal: something went wrong"));
+    }
+}
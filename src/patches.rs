@@ -0,0 +1,814 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fs::{create_dir_all, read, read_dir, read_to_string, remove_file, rename, write, OpenOptions},
+    io::Write,
+    path::{Component, Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+use tokio::{io::AsyncWriteExt, process::Command, sync::Mutex};
+
+use crate::{config::server::PatchConfig, mirror::now, state::State, vars::*};
+
+/// How often the mailbox is checked for new patches.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One mail of a series, kept in the repo's queue.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Patch {
+    /// Its place in the series, where 0 is the cover letter.
+    pub number: u32,
+    pub subject: String,
+    pub message_id: String,
+    /// Its file name in the queue.
+    pub file: String,
+}
+
+/// Patches sent together, like the mails of one `git format-patch --thread` run.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Series {
+    /// Made from the thread and version, so it's the same however the mails arrive.
+    pub id: String,
+    pub author: String,
+    /// How many patches there are meant to be, not counting a cover letter.
+    pub total: u32,
+    /// Unix timestamp of the first mail.
+    pub received: u64,
+    /// The branch it went onto, once it has.
+    pub applied: Option<String>,
+    pub patches: Vec<Patch>,
+}
+
+impl Series {
+    /// The cover letter's subject, or else the first patch's.
+    pub fn subject(&self) -> &str {
+        self.patches
+            .first()
+            .map(|patch| patch.subject.as_str())
+            .unwrap_or_default()
+    }
+
+    /// The patches to apply, in order, leaving out the cover letter.
+    pub fn to_apply(&self) -> Vec<&Patch> {
+        self.patches.iter().filter(|p| p.number > 0).collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.to_apply().len() as u32 == self.total
+    }
+
+    /// Where a patch's mail is. Applied ones are marked as seen, the way mail clients do it.
+    pub fn mail_path(&self, repo_path: &Path, patch: &Patch) -> PathBuf {
+        let dir = repo_path.join(PATCH_QUEUE_DIR);
+        match self.applied {
+            Some(_) => dir.join("cur").join(format!("{}:2,S", patch.file)),
+            None => dir.join("new").join(&patch.file),
+        }
+    }
+}
+
+/// The patches mailed in for a repo, which are kept in a maildir in the bare repo.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PatchQueue {
+    #[serde(default)]
+    pub series: Vec<Series>,
+}
+
+impl PatchQueue {
+    pub fn load(repo_path: &Path) -> anyhow::Result<Self> {
+        let path = repo_path.join(PATCH_QUEUE_FILE);
+        match path.exists() {
+            true => Ok(toml::from_str(&read_to_string(path)?)?),
+            false => Ok(Self::default()),
+        }
+    }
+
+    pub fn save(&self, repo_path: &Path) -> anyhow::Result<()> {
+        write(repo_path.join(PATCH_QUEUE_FILE), toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> anyhow::Result<&mut Series> {
+        self.series
+            .iter_mut()
+            .find(|series| series.id == id)
+            .ok_or_else(|| anyhow!("There's no patch series {}.", id))
+    }
+
+    fn contains(&self, message_id: &str) -> bool {
+        self.series
+            .iter()
+            .any(|s| s.patches.iter().any(|p| p.message_id == message_id))
+    }
+
+    /// Moves an applied series' mails over to the seen ones.
+    pub fn mark_applied(&mut self, repo_path: &Path, id: &str, branch: &str) -> anyhow::Result<()> {
+        let series = self.get_mut(id)?;
+        let unseen: Vec<PathBuf> = series
+            .patches
+            .iter()
+            .map(|patch| series.mail_path(repo_path, patch))
+            .collect();
+        series.applied = Some(branch.to_string());
+        let seen: Vec<PathBuf> = series
+            .patches
+            .iter()
+            .map(|patch| series.mail_path(repo_path, patch))
+            .collect();
+        create_dir_all(repo_path.join(PATCH_QUEUE_DIR).join("cur"))?;
+        for (from, to) in unseen.iter().zip(&seen) {
+            rename(from, to)?;
+        }
+        self.save(repo_path)
+    }
+}
+
+/// A mail's headers, with folded lines joined up and names in lowercase.
+fn parse_headers(mail: &str) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in mail.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+    headers
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// The `<...>` message IDs in a header, in order.
+fn message_ids(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('>'))
+        .map(|(id, _)| format!("<{}>", id))
+        .collect()
+}
+
+/// A few bytes of a SHA-256, in hex.
+fn short_hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .take(4)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// What `git mailinfo` makes of a mail: its author and subject, decoded, with the subject's
+/// `[PATCH ...]` kept.
+async fn mail_info(mail: &[u8]) -> anyhow::Result<(String, String)> {
+    let temp_dir = tempdir()?;
+    let mut child = Command::new("git")
+        .current_dir(temp_dir.path())
+        .args(["mailinfo", "-k", "msg", "patch"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(mail).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!("Failed to run git mailinfo"));
+    }
+
+    let info = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| {
+        info.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+    let author = match field("Email: ").as_str() {
+        "" => field("Author: "),
+        email => format!("{} <{}>", field("Author: "), email),
+    };
+    Ok((author, field("Subject: ")))
+}
+
+/// The words in a patch subject's brackets, like `PATCH`, `v2` and `1/3`, or nothing if it isn't
+/// a patch. Replies aren't.
+fn subject_tags(subject: &str) -> Option<Vec<&str>> {
+    let tags = subject.strip_prefix('[')?.split_once(']')?.0;
+    tags.contains("PATCH")
+        .then(|| tags.split_whitespace().collect())
+}
+
+/// A patch's place in its series and how long it is, from a `n/N` tag.
+fn series_position(tags: &[&str]) -> (u32, u32) {
+    tags.iter()
+        .find_map(|tag| {
+            let (number, total) = tag.split_once('/')?;
+            Some((number.parse().ok()?, total.parse().ok()?))
+        })
+        .unwrap_or((1, 1))
+}
+
+/// A repo named in the subject's tags, like `alex/tool.git` or `alex/tool`.
+fn tagged_repo(tags: &[&str]) -> Option<PathBuf> {
+    tags.iter().find_map(|tag| {
+        let mut path = PathBuf::from(tag);
+        if path.extension().is_none_or(|ext| ext != "git") {
+            path = PathBuf::from(format!("{}.git", tag));
+        }
+        let is_inside = path.components().all(|c| matches!(c, Component::Normal(_)));
+        let is_repo = is_inside && path != Path::new(SERVER_CONFIG_REPO);
+        (is_repo && path.join("HEAD").exists()).then_some(path)
+    })
+}
+
+/// The address in a mailbox like `Alex <alex@example.com>` or `alex@example.com (Alex)`, or the
+/// ID in a `List-Id` like `Tool <tool.lists.example.com>`.
+fn address(mailbox: &str) -> &str {
+    match mailbox.rsplit_once('<') {
+        Some((_, rest)) => rest.split('>').next().unwrap_or(rest).trim(),
+        None => mailbox.split_whitespace().next().unwrap_or_default(),
+    }
+}
+
+/// Everywhere a mail was sent: its `To` and `Cc` addresses and its `List-Id`, in lowercase.
+fn recipients(headers: &[(String, String)]) -> Vec<String> {
+    let mut recipients = Vec::new();
+    for (name, value) in headers {
+        let mailboxes = match name.as_str() {
+            "to" | "cc" => {
+                // Commas separate mailboxes, unless they're in a quoted name.
+                let mut in_quotes = false;
+                value
+                    .split(|c| {
+                        in_quotes ^= c == '"';
+                        c == ',' && !in_quotes
+                    })
+                    .collect()
+            }
+            "list-id" => vec![value.as_str()],
+            _ => continue,
+        };
+        recipients.extend(
+            mailboxes
+                .into_iter()
+                .map(|mailbox| address(mailbox).to_lowercase())
+                .filter(|address| !address.is_empty()),
+        );
+    }
+    recipients
+}
+
+/// Which repo a mail's for: the one its address is set up for, or else the one its subject names.
+fn find_repo(
+    headers: &[(String, String)],
+    tags: &[&str],
+    addresses: &HashMap<String, String>,
+) -> Option<PathBuf> {
+    let recipients = recipients(headers);
+    addresses
+        .iter()
+        .find(|(address, _)| recipients.contains(&address.to_lowercase()))
+        .map(|(_, repo)| PathBuf::from(repo))
+        .filter(|path| path.join("HEAD").exists())
+        .or_else(|| tagged_repo(tags))
+}
+
+/// A patch mail that's been read, ready to go into its repo's queue.
+struct Incoming {
+    series_id: String,
+    author: String,
+    total: u32,
+    patch: Patch,
+    mail: Vec<u8>,
+}
+
+/// The message IDs of every mail intake has already looked at, so a mailbox that only grows isn't
+/// gone through again each time.
+fn load_seen() -> anyhow::Result<HashSet<String>> {
+    match Path::new(PATCHES_SEEN_FILE).exists() {
+        true => Ok(read_to_string(PATCHES_SEEN_FILE)?
+            .lines()
+            .map(str::to_string)
+            .collect()),
+        false => Ok(HashSet::new()),
+    }
+}
+
+fn save_seen(message_ids: &[String]) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(PATCHES_SEEN_FILE)?;
+    for message_id in message_ids {
+        writeln!(file, "{}", message_id)?;
+    }
+    Ok(())
+}
+
+/// Reads a mail, working out which repo and series it's for. Mails that aren't patches, or aren't
+/// for any repo, are left alone.
+async fn read_patch(
+    mail: Vec<u8>,
+    message_id: &str,
+    config: &PatchConfig,
+) -> anyhow::Result<Option<(PathBuf, Incoming)>> {
+    let headers = parse_headers(&String::from_utf8_lossy(&mail));
+    // Subjects are only encoded when they need to be, and decoding takes a git process, so
+    // it's left until it's needed.
+    let raw_subject = header(&headers, "subject").unwrap_or_default();
+    let info = match raw_subject.contains("=?") {
+        true => Some(mail_info(&mail).await?),
+        false => None,
+    };
+    let subject = info
+        .as_ref()
+        .map_or(raw_subject.to_string(), |(_, subject)| subject.clone());
+    let Some(tags) = subject_tags(&subject) else {
+        return Ok(None);
+    };
+    let Some(repo_path) = find_repo(&headers, &tags, &config.addresses) else {
+        debug!("No repo for patch {}", message_id);
+        return Ok(None);
+    };
+
+    // Threads start at the cover letter or first patch, and a new version is a new series.
+    let thread = header(&headers, "references")
+        .or(header(&headers, "in-reply-to"))
+        .and_then(|value| message_ids(value).into_iter().next())
+        .unwrap_or(message_id.to_string());
+    let series_id = short_hash(format!("{} {}", thread, series_version(&tags)).as_bytes());
+    let (number, total) = series_position(&tags);
+    let (author, subject) = match info {
+        Some(info) => info,
+        None => mail_info(&mail).await?,
+    };
+    let patch = Patch {
+        number,
+        subject,
+        message_id: message_id.to_string(),
+        file: String::new(),
+    };
+    Ok(Some((
+        repo_path,
+        Incoming {
+            series_id,
+            author,
+            total,
+            patch,
+            mail,
+        },
+    )))
+}
+
+/// A series' version, like `v2`, from the subject's tags.
+fn series_version<'a>(tags: &[&'a str]) -> &'a str {
+    tags.iter()
+        .find(|tag| tag.starts_with('v') && tag[1..].parse::<u32>().is_ok())
+        .unwrap_or(&"v1")
+}
+
+/// Adds a patch to its series, replacing any it already has in the same place, as a resent patch
+/// is meant to. Returns the file of the one it replaced.
+fn add_patch(queue: &mut PatchQueue, incoming: Incoming, file: String) -> Option<String> {
+    let patch = Patch {
+        file,
+        ..incoming.patch
+    };
+    let Some(series) = queue.series.iter_mut().find(|s| s.id == incoming.series_id) else {
+        queue.series.push(Series {
+            id: incoming.series_id,
+            author: incoming.author,
+            total: incoming.total,
+            received: now(),
+            applied: None,
+            patches: vec![patch],
+        });
+        return None;
+    };
+
+    let replaced = series
+        .patches
+        .iter()
+        .position(|p| p.number == patch.number)
+        .map(|i| series.patches.remove(i).file);
+    series.patches.push(patch);
+    series.patches.sort_by_key(|p| p.number);
+    replaced
+}
+
+/// Puts newly read patches in a repo's queue, which is kept in a maildir in the bare repo.
+/// Returns whether any went in.
+fn queue_patches(repo_path: &Path, patches: Vec<Incoming>) -> anyhow::Result<bool> {
+    let mut queue = PatchQueue::load(repo_path)?;
+    let dir = repo_path.join(PATCH_QUEUE_DIR);
+    let mut changed = false;
+    for incoming in patches {
+        let message_id = &incoming.patch.message_id;
+        if queue.contains(message_id) {
+            continue;
+        }
+        if queue
+            .series
+            .iter()
+            .any(|s| s.id == incoming.series_id && s.applied.is_some())
+        {
+            debug!("Patch {} is for a series that's been applied", message_id);
+            continue;
+        }
+
+        // Written in full to `tmp` first, as maildirs are.
+        for sub_dir in ["tmp", "new", "cur"] {
+            create_dir_all(dir.join(sub_dir))?;
+        }
+        let name = format!(
+            "{}.{}.{}",
+            now(),
+            incoming.series_id,
+            short_hash(message_id.as_bytes())
+        );
+        write(dir.join("tmp").join(&name), &incoming.mail)?;
+        rename(dir.join("tmp").join(&name), dir.join("new").join(&name))?;
+
+        if let Some(replaced) = add_patch(&mut queue, incoming, name) {
+            remove_file(dir.join("new").join(replaced))?;
+        }
+        changed = true;
+    }
+
+    if changed {
+        queue.save(repo_path)?;
+    }
+    Ok(changed)
+}
+
+/// Queues up any patches in the maildir or mbox that haven't been already, and returns the repos
+/// that got new ones. Each mail is only looked at once, whatever became of it.
+pub async fn intake(state: &Arc<Mutex<State>>) -> anyhow::Result<Vec<PathBuf>> {
+    let (config, intake_lock) = {
+        let state = state.lock().await;
+        // Replicas get their repos from the primary, which takes the patches in.
+        match (&state.server_config.patches, &state.replica) {
+            (Some(config), None) => (config.clone(), state.patch_intake.clone()),
+            _ => return Ok(Vec::new()),
+        }
+    };
+    let _intake_lock = intake_lock.lock().await;
+
+    let mailbox = Path::new(&config.mailbox);
+    if !mailbox.exists() {
+        return Ok(Vec::new());
+    }
+
+    // Splits either kind of mailbox into one file per mail.
+    let temp_dir = tempdir()?;
+    let mut split_arg = OsString::from("-o");
+    split_arg.push(temp_dir.path());
+    let output = Command::new("git")
+        .arg("mailsplit")
+        .arg(split_arg)
+        .arg(mailbox)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Couldn't read {}: {}",
+            mailbox.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let mut files: Vec<PathBuf> = read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    files.sort();
+
+    let mut seen = load_seen()?;
+    let mut newly_seen = Vec::new();
+    let mut incoming: BTreeMap<PathBuf, Vec<Incoming>> = BTreeMap::new();
+    for file in files {
+        let mail = read(&file)?;
+        let headers = parse_headers(&String::from_utf8_lossy(&mail));
+        // Mails straight out of `git format-patch` don't get one unless they're threaded.
+        let message_id = header(&headers, "message-id")
+            .and_then(|value| message_ids(value).into_iter().next())
+            .unwrap_or_else(|| format!("<{}@gitenator>", short_hash(&mail)));
+        if !seen.insert(message_id.clone()) {
+            continue;
+        }
+        newly_seen.push(message_id.clone());
+
+        if let Some((repo_path, patch)) = read_patch(mail, &message_id, &config).await? {
+            incoming.entry(repo_path).or_default().push(patch);
+        }
+    }
+
+    let mut changed = Vec::new();
+    for (repo_path, patches) in incoming {
+        let repo_lock = state.lock().await.repo_lock(&repo_path);
+        let _repo_lock = repo_lock.lock().await;
+        if queue_patches(&repo_path, patches)? {
+            changed.push(repo_path);
+        }
+    }
+    save_seen(&newly_seen)?;
+    Ok(changed)
+}
+
+/// Checks the mailbox for patches, forever.
+pub async fn run_intake(state: Arc<Mutex<State>>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        match intake(&state).await {
+            Ok(repos) => {
+                for repo_path in repos {
+                    info!("Received patches for {}", repo_path.display());
+                }
+            }
+            Err(e) => error!("Couldn't take in patches: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::current_dir, fs::create_dir_all};
+
+    use tempfile::{tempdir, Builder};
+
+    use super::*;
+
+    fn mail(message_id: &str, subject: &str, references: Option<&str>) -> Vec<u8> {
+        let references = references
+            .map(|r| format!("In-Reply-To: {}\nReferences: {}\n", r, r))
+            .unwrap_or_default();
+        format!(
+            "From: Bob <bob@example.com>\n\
+             To: tool@lists.example.com\n\
+             Subject: {}\n\
+             Message-ID: {}\n\
+             {}\n\
+             ---\n",
+            subject, message_id, references
+        )
+        .into_bytes()
+    }
+
+    fn incoming(series_id: &str, number: u32, message_id: &str) -> Incoming {
+        Incoming {
+            series_id: series_id.to_string(),
+            author: "Bob <bob@example.com>".to_string(),
+            total: 2,
+            patch: Patch {
+                number,
+                subject: format!("[PATCH {}/2] Change", number),
+                message_id: message_id.to_string(),
+                file: String::new(),
+            },
+            mail: message_id.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn headers_are_unfolded() {
+        let headers = parse_headers(
+            "Subject: [PATCH 1/2] A subject that\r\n\
+             \tgoes on\r\n\
+             References: <one@example.com>\r\n \
+             <two@example.com>\r\n\
+             \r\n\
+             Body: not a header\r\n",
+        );
+        assert_eq!(
+            header(&headers, "subject"),
+            Some("[PATCH 1/2] A subject that goes on")
+        );
+        assert_eq!(header(&headers, "body"), None);
+        assert_eq!(
+            message_ids(header(&headers, "references").unwrap()),
+            vec!["<one@example.com>", "<two@example.com>"]
+        );
+    }
+
+    #[test]
+    fn patch_subjects_are_tagged() {
+        assert_eq!(
+            subject_tags("[PATCH v2 alex/tool 2/3] Change"),
+            Some(vec!["PATCH", "v2", "alex/tool", "2/3"])
+        );
+        assert_eq!(
+            subject_tags("[RFC PATCH] Change"),
+            Some(vec!["RFC", "PATCH"])
+        );
+        assert_eq!(subject_tags("Re: [PATCH 2/3] Change"), None);
+        assert_eq!(subject_tags("[ANNOUNCE] Release"), None);
+        assert_eq!(subject_tags("[PATCH Change"), None);
+    }
+
+    #[test]
+    fn series_have_positions_and_versions() {
+        assert_eq!(series_position(&["PATCH", "v2", "2/3"]), (2, 3));
+        assert_eq!(series_position(&["PATCH", "0/3"]), (0, 3));
+        assert_eq!(series_position(&["PATCH"]), (1, 1));
+        assert_eq!(series_position(&["PATCH", "a/b"]), (1, 1));
+        assert_eq!(series_version(&["PATCH", "v2", "2/3"]), "v2");
+        assert_eq!(series_version(&["PATCH", "vim"]), "v1");
+        assert_eq!(series_version(&["PATCH"]), "v1");
+    }
+
+    #[test]
+    fn recipients_are_whole_addresses() {
+        let headers = parse_headers(
+            "To: \"Doe, Alex\" <Alex@Example.com>, tool@lists.example.com (Tool)\n\
+             Cc: bob@example.com\n\
+             List-Id: Tool development <tool.lists.example.com>\n\
+             \n",
+        );
+        assert_eq!(
+            recipients(&headers),
+            vec![
+                "alex@example.com",
+                "tool@lists.example.com",
+                "bob@example.com",
+                "tool.lists.example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn lookalike_addresses_dont_match() {
+        let dir = tempdir().unwrap();
+        let repo = dir.path().join("tool.git");
+        create_dir_all(&repo).unwrap();
+        write(repo.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let addresses = HashMap::from([(
+            "Tool@Lists.Example.com".to_string(),
+            repo.display().to_string(),
+        )]);
+        let find = |headers: &str| find_repo(&parse_headers(headers), &[], &addresses);
+
+        assert_eq!(
+            find("To: Tool <tool@lists.example.com>\n"),
+            Some(repo.clone())
+        );
+        assert_eq!(
+            find("Cc: alex@example.com, TOOL@lists.example.com\n"),
+            Some(repo)
+        );
+        assert_eq!(find("To: oldtool@lists.example.com\n"), None);
+        assert_eq!(find("To: <tool@lists.example.com.evil>\n"), None);
+        assert_eq!(
+            find("To: \"tool@lists.example.com\" <alex@example.com>\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn tagged_repos_are_on_this_server() {
+        // Tests run from the crate, which stands in for the data directory.
+        let cwd = current_dir().unwrap();
+        let dir = Builder::new().tempdir_in("target").unwrap();
+        let repo = dir.path().strip_prefix(&cwd).unwrap().join("tool.git");
+        create_dir_all(&repo).unwrap();
+        write(repo.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let named = repo.with_extension("");
+        let named = named.to_str().unwrap();
+        assert_eq!(tagged_repo(&["PATCH", named]), Some(repo.clone()));
+        assert_eq!(tagged_repo(&["PATCH", "missing/tool"]), None);
+
+        // It has to be under the data directory, however it's written.
+        let absolute = cwd.join(&repo);
+        assert_eq!(tagged_repo(&["PATCH", absolute.to_str().unwrap()]), None);
+        let up = Path::new("..").join(cwd.file_name().unwrap()).join(&repo);
+        assert!(up.join("HEAD").exists());
+        assert_eq!(tagged_repo(&["PATCH", up.to_str().unwrap()]), None);
+    }
+
+    #[test]
+    fn resent_patches_replace_the_old_ones() {
+        let mut queue = PatchQueue::default();
+        assert_eq!(
+            add_patch(&mut queue, incoming("s", 2, "<b>"), "b".into()),
+            None
+        );
+        assert_eq!(
+            add_patch(&mut queue, incoming("s", 0, "<c>"), "c".into()),
+            None
+        );
+        let series = &queue.series[0];
+        assert!(!series.is_complete());
+
+        assert_eq!(
+            add_patch(&mut queue, incoming("s", 1, "<a>"), "a".into()),
+            None
+        );
+        assert_eq!(
+            add_patch(&mut queue, incoming("s", 2, "<b2>"), "b2".into()),
+            Some("b".to_string())
+        );
+        assert_eq!(
+            add_patch(&mut queue, incoming("t", 1, "<d>"), "d".into()),
+            None
+        );
+
+        let series = &queue.series[0];
+        assert!(series.is_complete());
+        assert_eq!(series.subject(), "[PATCH 0/2] Change");
+        let files: Vec<_> = series.to_apply().iter().map(|p| p.file.as_str()).collect();
+        assert_eq!(files, vec!["a", "b2"]);
+        assert_eq!(queue.series.len(), 2);
+    }
+
+    #[test]
+    fn queued_patches_are_only_taken_once() {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path();
+        assert!(queue_patches(
+            repo_path,
+            vec![incoming("s", 1, "<a>"), incoming("s", 2, "<b>")]
+        )
+        .unwrap());
+        assert!(!queue_patches(repo_path, vec![incoming("s", 1, "<a>")]).unwrap());
+
+        // Replaced mails are removed from the maildir.
+        assert!(queue_patches(repo_path, vec![incoming("s", 2, "<b2>")]).unwrap());
+        let mut queue = PatchQueue::load(repo_path).unwrap();
+        assert_eq!(
+            read_dir(repo_path.join(PATCH_QUEUE_DIR).join("new"))
+                .unwrap()
+                .count(),
+            2
+        );
+
+        // Nothing more goes into a series once it's been applied.
+        queue.mark_applied(repo_path, "s", "main").unwrap();
+        assert!(!queue_patches(repo_path, vec![incoming("s", 2, "<b3>")]).unwrap());
+        let series = &PatchQueue::load(repo_path).unwrap().series[0];
+        for patch in &series.patches {
+            assert!(series.mail_path(repo_path, patch).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn threads_make_up_a_series() {
+        let dir = tempdir().unwrap();
+        let repo = dir.path().join("tool.git");
+        create_dir_all(&repo).unwrap();
+        write(repo.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let config = PatchConfig {
+            mailbox: String::new(),
+            addresses: HashMap::from([(
+                "tool@lists.example.com".to_string(),
+                repo.display().to_string(),
+            )]),
+        };
+
+        let read = |message_id: &'static str, subject: &'static str, reply_to| {
+            let config = config.clone();
+            async move {
+                read_patch(mail(message_id, subject, reply_to), message_id, &config)
+                    .await
+                    .unwrap()
+            }
+        };
+        let (path, cover) = read("<0@x>", "[PATCH 0/2] Changes", None).await.unwrap();
+        assert_eq!(path, repo);
+        assert_eq!(cover.author, "Bob <bob@example.com>");
+        assert_eq!((cover.patch.number, cover.total), (0, 2));
+
+        let (_, first) = read("<1@x>", "[PATCH 1/2] One", Some("<0@x>"))
+            .await
+            .unwrap();
+        assert_eq!(first.series_id, cover.series_id);
+        assert_eq!(first.patch.subject, "[PATCH 1/2] One");
+
+        let (_, resent) = read("<2@x>", "[PATCH v2 1/2] One", Some("<0@x>"))
+            .await
+            .unwrap();
+        assert_ne!(resent.series_id, cover.series_id);
+
+        assert!(read("<3@x>", "Re: [PATCH 1/2] One", Some("<1@x>"))
+            .await
+            .is_none());
+        assert!(read("<4@x>", "Lunch?", None).await.is_none());
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    fs::{read, remove_dir_all, remove_file, write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::{error, info};
+use tokio::{process::Command, sync::Mutex, time::timeout};
+
+use crate::{
+    config::{
+        repo::{pinned_config_path, RepoConfig},
+        server::ReplicationConfig,
+    },
+    git::{find_repos, Repo},
+    lfs::{upload_objects, LfsStore},
+    mirror::ssh_command,
+    search::remove_index,
+    site::reconcile_sites,
+    state::State,
+    vars::*,
+};
+
+/// How often every repo is checked against the replicas, catching up ones that missed a push and
+/// deleting repos that are gone.
+const SYNC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Pushes and commands that take longer than this are given up on until the next sync.
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The replication settings, if this server is a primary.
+async fn primary_config(state: &Arc<Mutex<State>>) -> Option<ReplicationConfig> {
+    let state = state.lock().await;
+    match state.replica {
+        Some(_) => None,
+        None => state.server_config.replication.clone(),
+    }
+}
+
+/// An SSH command that runs something on a replica, logged in as the replication user.
+fn replica_command(
+    config: &ReplicationConfig,
+    replica: &str,
+    args: &[&str],
+) -> anyhow::Result<Command> {
+    let host = replica
+        .strip_prefix("ssh://")
+        .and_then(|rest| rest.split('/').next())
+        .filter(|host| !host.is_empty() && !host.starts_with('-'))
+        .ok_or_else(|| anyhow!("Replicas need ssh:// URLs, not {}", replica))?;
+    let (destination, port) = match host.rsplit_once(':') {
+        Some((destination, port)) => (destination, Some(port)),
+        None => (host, None),
+    };
+
+    // The same SSH command git uses, so both log in the same way.
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(format!("{} \"$@\"", ssh_command(config.ssh_key.as_deref())))
+        .arg("ssh");
+    if let Some(port) = port {
+        command.args(["-p", port]);
+    }
+    command.arg(destination).args(args);
+    Ok(command)
+}
+
+/// Runs one of the `replication` commands on a replica, returning what it printed.
+async fn run_on_replica(
+    config: &ReplicationConfig,
+    replica: &str,
+    args: &[&str],
+) -> anyhow::Result<String> {
+    let mut command = replica_command(config, replica, &[&["replication"], args].concat())?;
+    let output = match timeout(REPLICA_TIMEOUT, command.output()).await {
+        Ok(output) => output?,
+        Err(_) => return Err(anyhow!("Timed out")),
+    };
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Brings a replica's copy of a repo up to date.
+async fn push_to_replica(
+    config: &ReplicationConfig,
+    replica: &str,
+    repo_path: &Path,
+) -> anyhow::Result<()> {
+    // Mirrors keep their config outside of any branch, so it goes separately, and first so the
+    // replica can build the site when the push lands.
+    // Ones that stopped being mirrors need theirs dropped, or it would still be used there.
+    let pinned = pinned_config_path(repo_path);
+    let repo = repo_path.to_string_lossy();
+    if pinned.exists() {
+        let hex: String = read(pinned)?.iter().map(|b| format!("{:02x}", b)).collect();
+        run_on_replica(config, replica, &["pin", &repo, &hex]).await?;
+    } else if repo_path != Path::new(SERVER_CONFIG_REPO) {
+        run_on_replica(config, replica, &["unpin", &repo]).await?;
+    }
+
+    let url = format!("{}/{}", replica.trim_end_matches('/'), repo_path.display());
+    let env = [(
+        "GIT_SSH_COMMAND".to_string(),
+        ssh_command(config.ssh_key.as_deref()),
+    )];
+    match timeout(
+        REPLICA_TIMEOUT,
+        Repo::open(repo_path).push_mirror(&url, &env),
+    )
+    .await
+    {
+        Ok(result) => result?,
+        Err(_) => return Err(anyhow!("Timed out")),
+    }
+
+    // After the push, so the repo's there for them to go in.
+    let store = LfsStore::open(repo_path);
+    if !store.objects()?.is_empty() {
+        match timeout(
+            REPLICA_TIMEOUT,
+            push_lfs_to_replica(config, replica, repo_path, &store),
+        )
+        .await
+        {
+            Ok(result) => result?,
+            Err(_) => return Err(anyhow!("Timed out sending LFS objects")),
+        }
+    }
+    Ok(())
+}
+
+/// Sends a replica the repo's Git LFS objects it doesn't have yet, the same way a client would.
+async fn push_lfs_to_replica(
+    config: &ReplicationConfig,
+    replica: &str,
+    repo_path: &Path,
+    store: &LfsStore,
+) -> anyhow::Result<()> {
+    let repo = repo_path.to_string_lossy();
+    let mut child = replica_command(config, replica, &[LFS_TRANSFER_COMMAND, &repo, "upload"])?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut input = child.stdout.take().unwrap();
+    let mut output = child.stdin.take().unwrap();
+
+    let result = upload_objects(store, &mut input, &mut output).await;
+    drop(output);
+    let stderr = child.wait_with_output().await?.stderr;
+    match result {
+        Ok(0) => {}
+        Ok(sent) => info!(
+            "Sent {} LFS object{} of {} to {}",
+            sent,
+            if sent == 1 { "" } else { "s" },
+            repo_path.display(),
+            replica
+        ),
+        // Whatever the replica said is more use than the protocol running out.
+        Err(e) => {
+            let stderr = String::from_utf8_lossy(&stderr);
+            return Err(match stderr.trim() {
+                "" => e,
+                stderr => anyhow!("{:#}: {}", e, stderr),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Pushes a repo to every replica in the background. Any that miss it catch up at the next sync.
+pub async fn replicate(state: &Arc<Mutex<State>>, repo_path: &Path) {
+    let Some(config) = primary_config(state).await else {
+        return;
+    };
+    let repo_path = repo_path.to_path_buf();
+    tokio::spawn(async move {
+        for replica in &config.replicas {
+            if let Err(e) = push_to_replica(&config, replica, &repo_path).await {
+                error!(
+                    "Couldn't replicate {} to {}: {:#}",
+                    repo_path.display(),
+                    replica,
+                    e
+                );
+            }
+        }
+    });
+}
+
+/// Syncs every replica in full, forever.
+pub async fn run_sync(state: Arc<Mutex<State>>) {
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(config) = primary_config(&state).await else {
+            continue;
+        };
+        for replica in &config.replicas {
+            if let Err(e) = sync_replica(&config, replica).await {
+                error!("Couldn't sync replica {}: {:#}", replica, e);
+            }
+        }
+    }
+}
+
+/// Pushes every repo to a replica, and deletes the ones it has that are gone here.
+async fn sync_replica(config: &ReplicationConfig, replica: &str) -> anyhow::Result<()> {
+    let mut repos = vec![PathBuf::from(SERVER_CONFIG_REPO)];
+    repos.extend(find_repos(Path::new("."))?);
+    for repo_path in &repos {
+        if let Err(e) = push_to_replica(config, replica, repo_path).await {
+            error!(
+                "Couldn't replicate {} to {}: {:#}",
+                repo_path.display(),
+                replica,
+                e
+            );
+        }
+    }
+
+    for repo in run_on_replica(config, replica, &["list"]).await?.lines() {
+        // Checked again rather than against the list, as repos can be made in the meantime.
+        if !Path::new(repo).join("HEAD").exists() {
+            run_on_replica(config, replica, &["delete", repo]).await?;
+            info!("Deleted {} from replica {}", repo, replica);
+        }
+    }
+    Ok(())
+}
+
+/// Removes a repo, along with its index and site. Forks of it get their own copies of the
+/// objects they borrowed first.
+pub async fn delete_repo(state: &Arc<Mutex<State>>, repo_path: &Path) -> anyhow::Result<()> {
+    let repo = Repo::open(repo_path);
+    for fork in repo.forks()? {
+        Repo::open(&fork).unshare().await?;
+    }
+    let sources = repo.sources();
+    remove_dir_all(repo_path)?;
+    for source in sources {
+        Repo::open(&source).keep_objects_for_forks().await?;
+    }
+    remove_index(repo_path)?;
+    reconcile_sites(state).await
+}
+
+/// Keeps a mirror's config, as sent by the primary in hex. The repo is made if it's new, ready
+/// for the push that follows. Returns whether the config changed.
+pub async fn pin_config(repo_path: &Path, hex: &str) -> anyhow::Result<bool> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+    toml::from_str::<RepoConfig>(std::str::from_utf8(&bytes)?)?;
+
+    if !repo_path.exists() {
+        Repo::create_bare(repo_path).await?;
+    }
+    let pinned = pinned_config_path(repo_path);
+    if read(&pinned).ok().as_ref() == Some(&bytes) {
+        return Ok(false);
+    }
+    write(pinned, bytes)?;
+    Ok(true)
+}
+
+/// Drops the config a repo kept while it was a mirror, if it has one.
+pub fn unpin_config(repo_path: &Path) -> anyhow::Result<()> {
+    let pinned = pinned_config_path(repo_path);
+    if pinned.exists() {
+        remove_file(pinned)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn hex(text: &str) -> String {
+        text.bytes().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn replicas_are_reached_over_ssh() {
+        let config = ReplicationConfig {
+            replicas: Vec::new(),
+            ssh_key: None,
+            user: "replication".to_string(),
+        };
+        let args = |replica| {
+            let command = replica_command(&config, replica, &["replication", "sync"]).unwrap();
+            // After `sh -c <ssh command> ssh`.
+            command
+                .as_std()
+                .get_args()
+                .skip(3)
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            args("ssh://replication@standby:2222"),
+            ["-p", "2222", "replication@standby", "replication", "sync"]
+        );
+        assert_eq!(
+            args("ssh://standby/ignored"),
+            ["standby", "replication", "sync"]
+        );
+
+        for replica in [
+            "standby:2222",
+            "ssh://",
+            "ssh://-oProxyCommand=x",
+            "https://standby",
+        ] {
+            assert!(
+                replica_command(&config, replica, &[]).is_err(),
+                "{}",
+                replica
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_configs_are_checked_and_kept() {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("tool.git");
+        let config = "name = \"tool\"\npublic = true\nmembers = []\n";
+
+        assert!(pin_config(&repo_path, "zz").await.is_err());
+        assert!(pin_config(&repo_path, &hex("name = 1")).await.is_err());
+        assert!(!repo_path.exists());
+
+        assert!(pin_config(&repo_path, &hex(config)).await.unwrap());
+        assert!(repo_path.join("HEAD").exists());
+        assert_eq!(
+            read(pinned_config_path(&repo_path)).unwrap(),
+            config.as_bytes()
+        );
+        assert!(!pin_config(&repo_path, &hex(config)).await.unwrap());
+
+        unpin_config(&repo_path).unwrap();
+        assert!(!pinned_config_path(&repo_path).exists());
+        unpin_config(&repo_path).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read, read_dir, remove_dir, remove_file, rename, write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    git::{find_repos, Repo},
+    vars::*,
+};
+
+/// Bigger files are left out of the index, they're rarely worth searching.
+const MAX_INDEXED_SIZE: usize = 1024 * 1024;
+
+/// How many blobs are read from git at a time, to keep memory in check.
+const BATCH_SIZE: usize = 256;
+
+/// Matching lines are cut down to this many characters.
+const MAX_LINE_LENGTH: usize = 200;
+
+/// A repo's default branch, broken down into the trigrams of each file.
+#[derive(Serialize, Deserialize, Default)]
+struct RepoIndex {
+    /// The commit the index was built from.
+    commit: String,
+    files: BTreeMap<String, IndexedFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexedFile {
+    blob: String,
+    /// Sorted and without duplicates. Binary and oversized files have none, so they never match.
+    trigrams: Vec<u32>,
+}
+
+/// A line matching a search.
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub repo: PathBuf,
+    pub path: String,
+    pub line_number: usize,
+    pub line: String,
+}
+
+/// Every run of three bytes, case-folded so searches ignore ASCII case.
+fn trigrams(text: &[u8]) -> Vec<u32> {
+    let mut trigrams: Vec<u32> = text
+        .windows(3)
+        .map(|w| {
+            let [a, b, c] = [w[0], w[1], w[2]].map(|b| b.to_ascii_lowercase() as u32);
+            a << 16 | b << 8 | c
+        })
+        .collect();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    trigrams
+}
+
+fn is_indexable(data: &[u8]) -> bool {
+    // Git's own heuristic: a NUL byte early on means binary.
+    data.len() <= MAX_INDEXED_SIZE && !data.iter().take(8000).any(|b| *b == 0)
+}
+
+fn index_path(repo_path: &Path) -> PathBuf {
+    let mut path = Path::new(SEARCH_INDEX_DIR).join(repo_path).into_os_string();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+fn load_index(repo_path: &Path) -> anyhow::Result<Option<RepoIndex>> {
+    let path = index_path(repo_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = read(&path)?;
+    // An index from an older version is simply rebuilt.
+    Ok(bincode::deserialize(&data).ok())
+}
+
+fn save_index(repo_path: &Path, index: &RepoIndex) -> anyhow::Result<()> {
+    let path = index_path(repo_path);
+    create_dir_all(path.parent().unwrap())?;
+
+    // Searches can run at any time, so they should never see half an index.
+    let temp_path = path.with_extension("idx.tmp");
+    write(&temp_path, bincode::serialize(index)?)?;
+    rename(&temp_path, &path)?;
+    Ok(())
+}
+
+/// Brings a repo's search index up to date with its default branch. Only files that changed
+/// since the last update are read.
+pub async fn update_index(repo_path: &Path) -> anyhow::Result<()> {
+    let repo = Repo::open(repo_path);
+    let Some(commit) = repo.latest_commit().await? else {
+        // Nothing's been pushed yet.
+        return remove_index(repo_path);
+    };
+
+    let mut previous = load_index(repo_path)?.unwrap_or_default();
+    if previous.commit == commit.hash {
+        return Ok(());
+    }
+
+    let mut index = RepoIndex {
+        commit: commit.hash,
+        files: BTreeMap::new(),
+    };
+    let mut changed = Vec::new();
+    for (path, blob) in repo.blobs("HEAD").await? {
+        let path = path.to_string_lossy().to_string();
+        match previous.files.remove(&path) {
+            Some(file) if file.blob == blob => {
+                index.files.insert(path, file);
+            }
+            _ => changed.push((path, blob)),
+        }
+    }
+
+    for batch in changed.chunks(BATCH_SIZE) {
+        let objects: Vec<&str> = batch.iter().map(|(_, blob)| blob.as_str()).collect();
+        let contents = repo.read_blobs(&objects).await?;
+        for ((path, blob), data) in batch.iter().zip(contents) {
+            let trigrams = match is_indexable(&data) {
+                true => trigrams(&data),
+                false => Vec::new(),
+            };
+            index.files.insert(
+                path.clone(),
+                IndexedFile {
+                    blob: blob.clone(),
+                    trigrams,
+                },
+            );
+        }
+    }
+
+    save_index(repo_path, &index)
+        .with_context(|| format!("Couldn't save the search index of {}", repo_path.display()))
+}
+
+/// Forgets a repo's search index, along with any folders it leaves empty.
+pub fn remove_index(repo_path: &Path) -> anyhow::Result<()> {
+    let path = index_path(repo_path);
+    if path.exists() {
+        remove_file(&path)?;
+    }
+
+    let mut dir = path.parent();
+    while let Some(parent) = dir {
+        if parent == Path::new(SEARCH_INDEX_DIR) || !parent.exists() {
+            break;
+        }
+        if read_dir(parent)?.next().is_some() {
+            break;
+        }
+        remove_dir(parent)?;
+        dir = parent.parent();
+    }
+    Ok(())
+}
+
+/// Indexes every repo on the server, and drops the indexes of repos that are gone.
+pub async fn update_all_indexes() -> anyhow::Result<()> {
+    let repos = find_repos(Path::new("."))?;
+    for repo_path in &repos {
+        match update_index(repo_path).await {
+            Ok(()) => info!("Indexed {}", repo_path.display()),
+            Err(e) => error!("Couldn't index {}: {:#}", repo_path.display(), e),
+        }
+    }
+
+    fn walk(dir: &Path, indexes: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                walk(&path, indexes)?;
+            } else {
+                indexes.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let root = Path::new(SEARCH_INDEX_DIR);
+    if !root.exists() {
+        return Ok(());
+    }
+    let mut indexes = Vec::new();
+    walk(root, &mut indexes)?;
+    for path in indexes {
+        let repo_path = path
+            .strip_prefix(root)?
+            .to_string_lossy()
+            .strip_suffix(".idx")
+            .map(PathBuf::from);
+        match repo_path {
+            Some(repo_path) if repos.contains(&repo_path) => {}
+            Some(repo_path) => remove_index(&repo_path)?,
+            // Leftovers from an interrupted save.
+            None => remove_file(path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Finds lines containing a query in the given repos, ignoring ASCII case. Callers are expected
+/// to have checked the user can read every one of them.
+pub async fn search(
+    repos: &[PathBuf],
+    query: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<SearchHit>> {
+    let query = query.to_ascii_lowercase();
+    let wanted = trigrams(query.as_bytes());
+    if wanted.is_empty() {
+        return Err(anyhow!("Searches need at least 3 characters."));
+    }
+
+    let mut hits = Vec::new();
+    for repo_path in repos {
+        let Some(index) = load_index(repo_path)? else {
+            continue;
+        };
+
+        // The index only narrows things down, the files themselves have the final say.
+        let candidates: Vec<(&String, &IndexedFile)> = index
+            .files
+            .iter()
+            .filter(|(_, file)| {
+                wanted
+                    .iter()
+                    .all(|trigram| file.trigrams.binary_search(trigram).is_ok())
+            })
+            .collect();
+
+        let repo = Repo::open(repo_path);
+        for batch in candidates.chunks(BATCH_SIZE) {
+            let objects: Vec<&str> = batch.iter().map(|(_, file)| file.blob.as_str()).collect();
+            let contents = repo.read_blobs(&objects).await?;
+            for ((path, _), data) in batch.iter().zip(contents) {
+                let text = String::from_utf8_lossy(&data);
+                for (number, line) in text.lines().enumerate() {
+                    if !line.to_ascii_lowercase().contains(&query) {
+                        continue;
+                    }
+                    hits.push(SearchHit {
+                        repo: repo_path.clone(),
+                        path: path.to_string(),
+                        line_number: number + 1,
+                        line: line.trim().chars().take(MAX_LINE_LENGTH).collect(),
+                    });
+                    if hits.len() >= limit {
+                        return Ok(hits);
+                    }
+                }
+            }
+        }
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trigram(text: &str) -> u32 {
+        trigrams(text.as_bytes())[0]
+    }
+
+    #[test]
+    fn trigrams_are_sorted_deduplicated_and_case_folded() {
+        let found = trigrams(b"abcABC");
+        assert!(found.windows(2).all(|w| w[0] < w[1]));
+        // abc, bca, cab, and ABC again as abc.
+        assert_eq!(found.len(), 3);
+        assert_eq!(trigram("ABC"), trigram("abc"));
+        assert!(found.contains(&trigram("bca")));
+        assert!(found.contains(&trigram("cab")));
+    }
+
+    #[test]
+    fn short_text_has_no_trigrams() {
+        assert!(trigrams(b"").is_empty());
+        assert!(trigrams(b"ab").is_empty());
+        assert_eq!(trigrams(b"abc").len(), 1);
+    }
+
+    #[test]
+    fn a_query_only_matches_files_with_all_its_trigrams() {
+        let file = trigrams(b"fn main() { println!(\"Hello\"); }");
+        let matches = |query: &str| {
+            trigrams(query.to_ascii_lowercase().as_bytes())
+                .iter()
+                .all(|t| file.binary_search(t).is_ok())
+        };
+        assert!(matches("println"));
+        assert!(matches("HELLO"));
+        assert!(!matches("goodbye"));
+    }
+
+    #[test]
+    fn binary_and_oversized_files_are_not_indexed() {
+        assert!(is_indexable(b"plain text\n"));
+        assert!(!is_indexable(b"\x7fELF\0\0\0"));
+        assert!(!is_indexable(&vec![b'a'; MAX_INDEXED_SIZE + 1]));
+    }
+
+    #[test]
+    fn index_paths_mirror_the_repo_paths() {
+        assert_eq!(
+            index_path(Path::new("alex/tool.git")),
+            Path::new(SEARCH_INDEX_DIR).join("alex/tool.git.idx")
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    fs::{create_dir_all, read, write},
+    path::Path,
+};
+
+/// Files every page can use, embedded so sites work without reaching out to a CDN.
+const ASSETS: [(&str, &str); 2] = [
+    ("site.css", include_str!("assets/site.css")),
+    ("search.js", include_str!("assets/search.js")),
+];
+
+/// Writes the bundled assets into `static/_assets`, unless they're already up to date.
+pub fn write_assets() -> anyhow::Result<()> {
+    let assets_path = Path::new("static").join("_assets");
+    create_dir_all(&assets_path)?;
+
+    for (name, contents) in ASSETS {
+        let path = assets_path.join(name);
+        if read(&path).ok().as_deref() != Some(contents.as_bytes()) {
+            write(path, contents)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::site::templates::BUILTIN;
+
+    #[test]
+    fn templates_only_use_bundled_assets() {
+        for (name, template) in BUILTIN.iter().chain(ASSETS.iter()) {
+            assert!(
+                !template.contains("https://") && !template.contains("http://"),
+                "{} reaches out to another site",
+                name
+            );
+            for reference in template.split("{{assets}}").skip(1) {
+                let asset = reference.split('"').next().unwrap();
+                assert!(
+                    ASSETS.iter().any(|(name, _)| *name == asset),
+                    "{} uses {}, which isn't bundled",
+                    name,
+                    asset
+                );
+            }
+        }
+    }
+}
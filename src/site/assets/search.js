@@ -0,0 +1,107 @@
+// The search box on README and docs pages. It searches the checkout's search.json in the
+// browser, so sites stay completely static.
+(function () {
+    const MAX_RESULTS = 10;
+    const SNIPPET_LENGTH = 120;
+
+    const form = document.getElementById("site-search");
+    if (!form) {
+        return;
+    }
+    const input = form.querySelector("input");
+    const list = document.getElementById("search-results");
+    const root = form.dataset.root;
+
+    // Only fetched once someone starts typing.
+    let pages = null;
+    async function loadPages() {
+        if (pages === null) {
+            const response = await fetch(root + "search.json");
+            pages = response.ok ? await response.json() : [];
+        }
+        return pages;
+    }
+
+    function snippet(text, term) {
+        const at = text.toLowerCase().indexOf(term);
+        if (at < 0) {
+            return text.slice(0, SNIPPET_LENGTH);
+        }
+        const start = Math.max(0, at - SNIPPET_LENGTH / 3);
+        const end = start + SNIPPET_LENGTH;
+        return (start > 0 ? "…" : "") + text.slice(start, end) + (end < text.length ? "…" : "");
+    }
+
+    // Every word has to appear somewhere. Titles count most, then headings, then the text.
+    function search(pages, query) {
+        const terms = query.toLowerCase().split(/\s+/).filter(Boolean);
+        const results = [];
+        for (const page of pages) {
+            let score = 0;
+            let anchor = "";
+            const matchesAll = terms.every((term) => {
+                let found = false;
+                if (page.title.toLowerCase().includes(term)) {
+                    score += 10;
+                    found = true;
+                }
+                for (const heading of page.headings) {
+                    if (heading.text.toLowerCase().includes(term)) {
+                        score += 5;
+                        found = true;
+                        if (!anchor && heading.id) {
+                            anchor = "#" + heading.id;
+                        }
+                    }
+                }
+                if (page.text.toLowerCase().includes(term)) {
+                    score += 1;
+                    found = true;
+                }
+                return found;
+            });
+            if (matchesAll) {
+                results.push({ page, score, anchor, snippet: snippet(page.text, terms[0]) });
+            }
+        }
+        results.sort((a, b) => b.score - a.score);
+        return results.slice(0, MAX_RESULTS);
+    }
+
+    function show(results, query) {
+        list.replaceChildren();
+        if (!query) {
+            return;
+        }
+        if (results.length === 0) {
+            const item = document.createElement("li");
+            item.textContent = "Nothing found.";
+            list.append(item);
+            return;
+        }
+        for (const result of results) {
+            const item = document.createElement("li");
+            const link = document.createElement("a");
+            link.href = root + result.page.url + result.anchor;
+            link.textContent = result.page.title;
+            const text = document.createElement("p");
+            text.textContent = result.snippet;
+            item.append(link, text);
+            list.append(item);
+        }
+    }
+
+    input.addEventListener("input", async () => {
+        const query = input.value.trim();
+        show(query ? search(await loadPages(), query) : [], query);
+    });
+
+    // Enter goes straight to the best match.
+    form.addEventListener("submit", (event) => {
+        event.preventDefault();
+        const first = list.querySelector("a");
+        if (first) {
+            window.location.href = first.href;
+        }
+    });
+})();
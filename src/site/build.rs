@@ -0,0 +1,200 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{
+        copy, create_dir, create_dir_all, hard_link, read_dir, read_to_string, remove_dir_all,
+        remove_file, rename, write,
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::vars::*;
+
+/// What the last build of a repo's site produced, kept next to its output.
+#[derive(Serialize, Deserialize, Default)]
+pub struct BuildState {
+    pub last_commit: Option<String>,
+    /// A hash of the repo's refs and the server config. If it hasn't changed, neither has the site.
+    pub refs: Option<String>,
+    /// A hash of what goes into every page: templates, config and the shared context.
+    pub inputs: Option<String>,
+    /// The file browser's pages, by path, keyed on the git object they show.
+    #[serde(default)]
+    pub tree: BTreeMap<String, String>,
+    /// The same for each branch or tag preview, by name.
+    #[serde(default)]
+    pub previews: BTreeMap<String, BTreeMap<String, String>>,
+    /// Archives made for each tag, so they're only made once.
+    #[serde(default)]
+    pub releases: BTreeMap<String, Release>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Release {
+    /// The commit the tag pointed to when its archives were made.
+    pub commit: String,
+    /// SHA-256 checksums, by archive file name.
+    pub checksums: BTreeMap<String, String>,
+}
+
+impl BuildState {
+    pub fn load(static_path: &Path) -> anyhow::Result<Self> {
+        let path = static_path.join(SITE_STATE_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let text = read_to_string(path).context("Couldn't read site build state")?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    pub fn save(&self, static_path: &Path) -> anyhow::Result<()> {
+        let text = toml::to_string(self)?;
+        write_file(&static_path.join(SITE_STATE_FILE), text)
+            .context("Couldn't write site build state")?;
+        Ok(())
+    }
+}
+
+/// Hashes a list of inputs into one key.
+pub fn hash_inputs<I: AsRef<[u8]>>(inputs: impl IntoIterator<Item = I>) -> String {
+    let mut hasher = Sha256::new();
+    for input in inputs {
+        hasher.update(input.as_ref());
+        // Keeps `["ab", "c"]` and `["a", "bc"]` apart.
+        hasher.update([0]);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Replaces a file. Staged files can be hard links into the live site, so they're never written in place.
+pub fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    if path.exists() {
+        remove_file(path)?;
+    }
+    write(path, contents)?;
+    Ok(())
+}
+
+/// Replaces a file with a copy of another, like [`write_file`].
+pub fn copy_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if to.exists() {
+        remove_file(to)?;
+    }
+    copy(from, to)?;
+    Ok(())
+}
+
+/// Where a repo's site is built before it goes live.
+pub fn staging_path(static_path: &Path) -> PathBuf {
+    let path = static_path.strip_prefix("static").unwrap_or(static_path);
+    Path::new("static").join(".build").join(path)
+}
+
+fn link_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    for entry in read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            create_dir(&target)?;
+            link_dir(&entry.path(), &target)?;
+        } else {
+            hard_link(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Starts a build from the live site, so unchanged pages can be kept. Copies are hard links, which makes
+/// this cheap.
+pub fn stage(static_path: &Path, staging: &Path) -> anyhow::Result<()> {
+    if staging.exists() {
+        remove_dir_all(staging)?;
+    }
+    create_dir_all(staging)?;
+    if static_path.exists() {
+        link_dir(static_path, staging).context("Couldn't stage site build")?;
+    }
+    Ok(())
+}
+
+/// Takes a repo's site offline, along with any build of it in progress.
+pub fn remove_site(static_path: &Path) -> anyhow::Result<()> {
+    for path in [static_path.to_path_buf(), staging_path(static_path)] {
+        if path.exists() {
+            remove_dir_all(&path).context("Couldn't remove site")?;
+        }
+    }
+    Ok(())
+}
+
+/// Swaps a finished build in for the live site, so visitors never see half-written pages.
+pub fn publish(staging: &Path, static_path: &Path) -> anyhow::Result<()> {
+    let mut old = OsString::from(staging);
+    old.push(".old");
+    let old = PathBuf::from(old);
+    if old.exists() {
+        remove_dir_all(&old)?;
+    }
+
+    if static_path.exists() {
+        rename(static_path, &old)?;
+    } else if let Some(parent) = static_path.parent() {
+        create_dir_all(parent)?;
+    }
+    rename(staging, static_path).context("Couldn't publish site build")?;
+
+    if old.exists() {
+        remove_dir_all(&old)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn inputs_are_hashed_apart() {
+        assert_eq!(hash_inputs(["ab", "c"]), hash_inputs(["ab", "c"]));
+        assert_ne!(hash_inputs(["ab", "c"]), hash_inputs(["a", "bc"]));
+        assert_ne!(hash_inputs(["abc"]), hash_inputs(["abc", ""]));
+    }
+
+    #[test]
+    fn staged_builds_leave_the_live_site_alone() {
+        let dir = tempdir().unwrap();
+        let live = dir.path().join("site");
+        let staging = dir.path().join("build").join("site");
+        create_dir_all(live.join("log")).unwrap();
+        write(live.join("index.html"), "old").unwrap();
+        write(live.join("log").join("index.html"), "log").unwrap();
+
+        stage(&live, &staging).unwrap();
+        assert_eq!(
+            read_to_string(staging.join("log").join("index.html")).unwrap(),
+            "log"
+        );
+        write_file(&staging.join("index.html"), "new").unwrap();
+        assert_eq!(read_to_string(live.join("index.html")).unwrap(), "old");
+
+        publish(&staging, &live).unwrap();
+        assert_eq!(read_to_string(live.join("index.html")).unwrap(), "new");
+        assert_eq!(
+            read_to_string(live.join("log").join("index.html")).unwrap(),
+            "log"
+        );
+        assert!(!staging.exists());
+        assert!(!dir.path().join("build").join("site.old").exists());
+    }
+}
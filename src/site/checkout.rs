@@ -0,0 +1,130 @@
+use std::{
+    collections::BTreeMap,
+    fs::{read_to_string, remove_dir_all, remove_file},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as AnyhowContext;
+use tera::{Context, Tera};
+
+use crate::config::repo::RepoConfig;
+
+use super::{
+    checkout_path,
+    docs::DocsBuilder,
+    markdown::{markdown_options, render_document, LinkRewriter},
+    search::{write_search_index, SearchEntry},
+    templates::render,
+    tree::TreeBuilder,
+    write_page,
+};
+
+const READMES: [&str; 2] = ["README.md", "readme.me"];
+
+/// The docs directory from the repo config, if the checkout has one.
+pub fn docs_dir<'a>(config: &'a RepoConfig, clone_dir: &Path) -> Option<&'a Path> {
+    config
+        .site
+        .as_ref()
+        .and_then(|site| site.docs.as_deref())
+        .and_then(|dir| checkout_path(clone_dir, dir))
+        .filter(|dir| clone_dir.join(dir).is_dir())
+}
+
+/// Renders what's checked out in a clone: its file browser, docs and README.
+pub struct CheckoutBuilder<'a> {
+    pub clone_dir: &'a Path,
+    pub static_path: &'a Path,
+    /// Shared by every page.
+    pub context: &'a Context,
+    /// Added to the README and docs, which can show what's new.
+    pub main_context: &'a Context,
+    pub theme: &'a str,
+    pub templates: &'a Tera,
+    pub config: &'a RepoConfig,
+    /// See [`TreeBuilder`].
+    pub objects: &'a BTreeMap<PathBuf, String>,
+    pub inputs: &'a str,
+}
+
+impl CheckoutBuilder<'_> {
+    /// Returns the keys of the file browser's pages, given the ones from the last build.
+    pub fn build(
+        &self,
+        previous: &BTreeMap<String, String>,
+    ) -> anyhow::Result<BTreeMap<String, String>> {
+        let pages = TreeBuilder {
+            clone_dir: self.clone_dir,
+            static_path: self.static_path,
+            context: self.context,
+            theme: self.theme,
+            templates: self.templates,
+            objects: self.objects,
+            inputs: self.inputs,
+            previous,
+        }
+        .build()?;
+
+        // The README and docs are redone every time, along with their search index.
+        let mut context = self.context.clone();
+        context.extend(self.main_context.clone());
+        let options = markdown_options(&self.config.markdown.clone().unwrap_or_default());
+        let docs_dir = docs_dir(self.config, self.clone_dir);
+
+        let docs_path = self.static_path.join("docs");
+        if docs_path.exists() {
+            remove_dir_all(&docs_path)?;
+        }
+        let mut entries = Vec::new();
+        if let Some(docs_dir) = docs_dir {
+            let mut options = options.clone();
+            options.extension.header_ids = Some(String::new());
+            entries = DocsBuilder {
+                clone_dir: self.clone_dir,
+                static_path: self.static_path,
+                context: &context,
+                theme: self.theme,
+                options: &options,
+                templates: self.templates,
+                docs_dir,
+            }
+            .build()
+            .context("Couldn't build docs")?;
+        }
+
+        let readme = READMES
+            .iter()
+            .map(|r| self.clone_dir.join(r))
+            .find(|path| path.exists());
+        let index = self.static_path.join("index.html");
+        if let Some(readme) = readme {
+            let readme = read_to_string(readme)?;
+            let links = LinkRewriter {
+                clone_dir: self.clone_dir,
+                base_dir: Path::new(""),
+                root: "",
+                docs_dir,
+            };
+            let rendered = render_document(&readme, &options, self.theme, Some(&links));
+            let repo_name = self.context.get("repo_name").and_then(|v| v.as_str());
+            let title = rendered.title.as_deref().or(repo_name).unwrap_or_default();
+            entries.insert(0, SearchEntry::new(title, "", &rendered, false));
+            context.insert("content", &rendered.html);
+            context.insert(
+                "summary",
+                &self.config.description.clone().or(rendered.summary),
+            );
+            if let Some(url) = self.context.get("url") {
+                context.insert("page_url", url);
+            }
+
+            let result = render(self.templates, "default.html", &context)?;
+            write_page(self.static_path, result)?;
+        } else if index.exists() {
+            remove_file(index)?;
+        }
+        write_search_index(self.static_path, &entries)?;
+
+        Ok(pages)
+    }
+}
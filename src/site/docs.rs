@@ -0,0 +1,250 @@
+use std::{
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+};
+
+use comrak::ComrakOptions;
+use log::warn;
+use percent_encoding::utf8_percent_encode;
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use super::{
+    markdown::{render_document, Heading, LinkRewriter, Rendered, PATH},
+    page_context,
+    search::SearchEntry,
+    templates::render,
+    write_page,
+};
+
+#[derive(Serialize, Clone)]
+struct DocLink {
+    title: String,
+    url: String,
+    depth: usize,
+    current: bool,
+}
+
+#[derive(Serialize)]
+struct DocsNav<'a> {
+    pages: Vec<DocLink>,
+    toc: Vec<&'a Heading>,
+    prev: Option<DocLink>,
+    next: Option<DocLink>,
+}
+
+/// Where a Markdown file in the docs directory ends up, relative to the repo's
+/// main page. Index and README files stand in for their directory.
+fn doc_slug(doc: &Path) -> PathBuf {
+    let mut slug = PathBuf::from("docs").join(doc);
+    slug.set_extension("");
+
+    let stem = slug.file_name().unwrap_or_default().to_string_lossy();
+    if stem.eq_ignore_ascii_case("index") || stem.eq_ignore_ascii_case("readme") {
+        slug.pop();
+    }
+    slug
+}
+
+/// Which file makes a page when more than one would: an index before a README, before anything
+/// else, like `guide.md` next to `guide/index.md`.
+fn doc_rank(doc: &Path) -> u8 {
+    let stem = doc.file_stem().unwrap_or_default().to_string_lossy();
+    match stem.to_lowercase().as_str() {
+        "index" => 0,
+        "readme" => 1,
+        _ => 2,
+    }
+}
+
+/// Puts docs in the order they're listed, leaving out any that would make the same page as
+/// another.
+fn order_docs(docs: &mut Vec<PathBuf>) {
+    // Sorting by URL puts every directory's index right before its pages.
+    docs.sort_by_cached_key(|doc| (doc_url(doc), doc_rank(doc), doc.clone()));
+    docs.dedup_by(|doc, kept| {
+        let is_same_page = doc_slug(doc) == doc_slug(kept);
+        if is_same_page {
+            warn!(
+                "Skipping {}, which would make the same page as {}",
+                doc.display(),
+                kept.display()
+            );
+        }
+        is_same_page
+    });
+}
+
+/// The link to a docs page, relative to the repo's main page.
+pub fn doc_url(doc: &Path) -> String {
+    format!(
+        "{}/",
+        utf8_percent_encode(&doc_slug(doc).to_string_lossy(), PATH)
+    )
+}
+
+/// Finds the Markdown files under a directory, relative to it. Symlinks are skipped, as they
+/// could point anywhere on the server.
+fn find_docs(root: &Path, dir: &Path, docs: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in read_dir(root.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            continue;
+        }
+        if file_type.is_dir() {
+            find_docs(root, &path, docs)?;
+        } else if path.extension().unwrap_or_default() == "md" {
+            docs.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Renders a directory of Markdown into `static/<repo>/docs`, laid out with the repo's template.
+pub struct DocsBuilder<'a> {
+    pub clone_dir: &'a Path,
+    pub static_path: &'a Path,
+    pub context: &'a Context,
+    pub theme: &'a str,
+    pub options: &'a ComrakOptions,
+    pub templates: &'a Tera,
+    /// Relative to the repo.
+    pub docs_dir: &'a Path,
+}
+
+impl DocsBuilder<'_> {
+    /// Returns the pages for the search index.
+    pub fn build(&self) -> anyhow::Result<Vec<SearchEntry>> {
+        let mut docs = Vec::new();
+        find_docs(
+            &self.clone_dir.join(self.docs_dir),
+            Path::new(""),
+            &mut docs,
+        )?;
+        order_docs(&mut docs);
+
+        let mut pages: Vec<(PathBuf, Rendered)> = Vec::new();
+        for doc in docs {
+            let slug = doc_slug(&doc);
+            let root = "../".repeat(slug.components().count());
+            let text = read_to_string(self.clone_dir.join(self.docs_dir).join(&doc))?;
+            let links = LinkRewriter {
+                clone_dir: self.clone_dir,
+                base_dir: &self.docs_dir.join(doc.parent().unwrap_or(Path::new(""))),
+                root: &root,
+                docs_dir: Some(self.docs_dir),
+            };
+            let mut rendered = render_document(&text, self.options, self.theme, Some(&links));
+
+            if rendered.title.is_none() {
+                rendered.title = Some(match doc.file_stem() {
+                    Some(stem) if slug != Path::new("docs") => stem.to_string_lossy().to_string(),
+                    _ => "Documentation".to_string(),
+                });
+            }
+            pages.push((doc, rendered));
+        }
+
+        for (i, (doc, rendered)) in pages.iter().enumerate() {
+            let slug = doc_slug(doc);
+            let root = "../".repeat(slug.components().count());
+            let link = |j: usize| {
+                let (doc, rendered) = &pages[j];
+                DocLink {
+                    title: rendered.title.clone().unwrap_or_default(),
+                    url: format!("{}{}", root, doc_url(doc)),
+                    depth: doc_slug(doc).components().count() - 1,
+                    current: i == j,
+                }
+            };
+
+            let nav = DocsNav {
+                pages: (0..pages.len()).map(link).collect(),
+                toc: rendered
+                    .headings
+                    .iter()
+                    .filter(|h| (2..=3).contains(&h.level))
+                    .collect(),
+                prev: i.checked_sub(1).map(link),
+                next: (i + 1 < pages.len()).then(|| link(i + 1)),
+            };
+
+            let mut context = page_context(self.context, &root);
+            context.insert("title", &rendered.title);
+            let description = self.context.get("description").and_then(|v| v.as_str());
+            context.insert(
+                "summary",
+                &rendered.summary.clone().or(description.map(str::to_string)),
+            );
+            if let Some(url) = self.context.get("url").and_then(|v| v.as_str()) {
+                context.insert("page_url", &format!("{}{}", url, doc_url(doc)));
+            }
+            context.insert("content", &rendered.html);
+            context.insert("docs", &nav);
+            let result = render(self.templates, "default.html", &context)?;
+
+            write_page(&self.static_path.join(slug), result)?;
+        }
+
+        Ok(pages
+            .iter()
+            .map(|(doc, rendered)| {
+                let title = rendered.title.clone().unwrap_or_default();
+                SearchEntry::new(&title, &doc_url(doc), rendered, true)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn docs_get_a_page_each() {
+        assert_eq!(doc_slug(Path::new("guide.md")), Path::new("docs/guide"));
+        assert_eq!(
+            doc_slug(Path::new("api/client.md")),
+            Path::new("docs/api/client")
+        );
+        assert_eq!(doc_slug(Path::new("index.md")), Path::new("docs"));
+        assert_eq!(doc_slug(Path::new("README.md")), Path::new("docs"));
+        assert_eq!(doc_slug(Path::new("api/Readme.md")), Path::new("docs/api"));
+        assert_eq!(
+            doc_url(Path::new("getting started.md")),
+            "docs/getting%20started/"
+        );
+        assert_eq!(doc_url(Path::new("index.md")), "docs/");
+    }
+
+    #[test]
+    fn one_file_makes_each_page() {
+        let mut docs: Vec<PathBuf> = [
+            "guide.md",
+            "README.md",
+            "api/README.md",
+            "api/client.md",
+            "index.md",
+            "guide/index.md",
+            "api/readme.md",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        order_docs(&mut docs);
+        assert_eq!(
+            docs,
+            [
+                "index.md",
+                "api/README.md",
+                "api/client.md",
+                "guide/index.md"
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::HashSet,
+    fs::{read_to_string, write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as AnyhowContext;
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+
+use crate::{git::Commit, git::Repo, vars::*};
+
+use super::{build::write_file, highlight::DEFAULT_THEME, render_markdown, repo_url};
+
+const FEED_LENGTH: usize = 20;
+const PUSH_LOG_LENGTH: usize = 100;
+
+#[derive(Serialize)]
+struct FeedEntry {
+    title: String,
+    id: String,
+    link: String,
+    updated: i64,
+    author: String,
+    content: String,
+}
+
+fn render_feed(
+    title: &str,
+    feed_url: &str,
+    page_url: &str,
+    entries: &[FeedEntry],
+) -> anyhow::Result<String> {
+    let mut context = Context::new();
+    context.insert("title", title);
+    context.insert("feed_url", feed_url);
+    context.insert("page_url", page_url);
+    context.insert(
+        "updated",
+        &entries.iter().map(|e| e.updated).max().unwrap_or_default(),
+    );
+    context.insert("entries", entries);
+    Ok(Tera::one_off(include_str!("feed.atom"), &context, true)?)
+}
+
+/// Writes `commits.atom` and `tags.atom` for a repo.
+pub struct FeedBuilder<'a> {
+    pub repo: &'a Repo,
+    pub static_path: &'a Path,
+    pub repo_name: &'a str,
+    pub theme: &'a str,
+    /// The absolute URL of the repo's pages, with a trailing slash.
+    pub repo_url: &'a str,
+}
+
+impl FeedBuilder<'_> {
+    pub async fn build(&self) -> anyhow::Result<()> {
+        let commits = self
+            .repo
+            .log(&[&format!("-{}", FEED_LENGTH), "HEAD"])
+            .await?;
+        let entries: Vec<FeedEntry> = commits
+            .iter()
+            .map(|commit| {
+                let link = format!("{}commit/{}/", self.repo_url, commit.hash);
+                FeedEntry {
+                    title: commit.summary.clone(),
+                    id: link.clone(),
+                    link,
+                    updated: commit.date,
+                    author: commit.author.clone(),
+                    content: render_markdown(&commit.message, self.theme),
+                }
+            })
+            .collect();
+        let feed = render_feed(
+            &format!("{} commits", self.repo_name),
+            &format!("{}commits.atom", self.repo_url),
+            &format!("{}log/", self.repo_url),
+            &entries,
+        )?;
+        write_file(&self.static_path.join("commits.atom"), feed)?;
+
+        let tags = self.repo.tags().await?;
+        let entries: Vec<FeedEntry> = tags
+            .iter()
+            .take(FEED_LENGTH)
+            .map(|tag| {
+                let link = format!("{}releases/#{}", self.repo_url, tag.name);
+                FeedEntry {
+                    title: tag.name.clone(),
+                    id: format!("{}commit/{}/#{}", self.repo_url, tag.hash, tag.name),
+                    link,
+                    updated: tag.date,
+                    author: tag.author.clone(),
+                    content: render_markdown(&tag.message, self.theme),
+                }
+            })
+            .collect();
+        let feed = render_feed(
+            &format!("{} tags", self.repo_name),
+            &format!("{}tags.atom", self.repo_url),
+            &format!("{}releases/", self.repo_url),
+            &entries,
+        )?;
+        write_file(&self.static_path.join("tags.atom"), feed)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PushedCommit {
+    pub hash: String,
+    pub short_hash: String,
+    pub summary: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Push {
+    pub repo_path: PathBuf,
+    pub repo_name: String,
+    pub date: i64,
+    pub head: String,
+    pub count: usize,
+    pub commits: Vec<PushedCommit>,
+}
+
+/// The most recent pushes to public repos, for the server-wide feed.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PushLog {
+    pub pushes: Vec<Push>,
+}
+
+impl PushLog {
+    fn path() -> PathBuf {
+        Path::new("static").join(PUSH_LOG_FILE)
+    }
+
+    pub fn load() -> anyhow::Result<Self> {
+        if !Self::path().exists() {
+            return Ok(Self::default());
+        }
+
+        let text = read_to_string(Self::path()).context("Couldn't read push log")?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// Records the commits a push brought in, newest first.
+    pub fn record(repo_path: &Path, repo_name: &str, commits: &[Commit]) -> anyhow::Result<()> {
+        let Some(head) = commits.first() else {
+            return Ok(());
+        };
+
+        let mut log = Self::load()?;
+        log.pushes.insert(
+            0,
+            Push {
+                repo_path: repo_path.to_path_buf(),
+                repo_name: repo_name.to_string(),
+                date: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+                head: head.hash.clone(),
+                count: commits.len(),
+                commits: commits
+                    .iter()
+                    .take(FEED_LENGTH)
+                    .map(|c| PushedCommit {
+                        hash: c.hash.clone(),
+                        short_hash: c.short_hash.clone(),
+                        summary: c.summary.clone(),
+                    })
+                    .collect(),
+            },
+        );
+        log.pushes.truncate(PUSH_LOG_LENGTH);
+
+        log.save()
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        write(Self::path(), toml::to_string(self)?).context("Couldn't write push log")?;
+        Ok(())
+    }
+
+    /// Drops pushes to repos that aren't public anymore, or are gone, so nothing about them stays online.
+    pub fn forget_private(&mut self, public: &HashSet<PathBuf>) -> anyhow::Result<()> {
+        let count = self.pushes.len();
+        self.pushes.retain(|push| public.contains(&push.repo_path));
+        if self.pushes.len() != count {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Writes `static/pushes.atom`.
+    pub fn write_feed(&self, server_name: &str, site_url: &str) -> anyhow::Result<()> {
+        let entries: Vec<FeedEntry> = self
+            .pushes
+            .iter()
+            .map(|push| {
+                let repo_url = repo_url(site_url, &push.repo_path);
+
+                // Listed as Markdown, so it goes through the same renderer as commit messages.
+                let mut summary = String::new();
+                for commit in &push.commits {
+                    summary.push_str(&format!(
+                        "- [`{}`]({}commit/{}/) {}\n",
+                        commit.short_hash, repo_url, commit.hash, commit.summary
+                    ));
+                }
+                if push.count > push.commits.len() {
+                    summary.push_str(&format!(
+                        "- ...and {} more\n",
+                        push.count - push.commits.len()
+                    ));
+                }
+
+                let plural = if push.count == 1 { "" } else { "s" };
+                FeedEntry {
+                    title: format!("{} new commit{} in {}", push.count, plural, push.repo_name),
+                    id: format!("{}commit/{}/#push-{}", repo_url, push.head, push.date),
+                    link: format!("{}log/", repo_url),
+                    updated: push.date,
+                    author: server_name.to_string(),
+                    content: render_markdown(&summary, DEFAULT_THEME),
+                }
+            })
+            .collect();
+
+        let feed = render_feed(
+            &format!("{} pushes", server_name),
+            &format!("{}/pushes.atom", site_url),
+            &format!("{}/", site_url),
+            &entries,
+        )?;
+        write(Path::new("static").join("pushes.atom"), feed)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn feeds_link_to_commits_and_releases() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::create_bare(&dir.path().join("tool.git"))
+            .await
+            .unwrap();
+        let first = repo
+            .write_commit(&[("README.md", "# Tool\n")], None, "First", "alex")
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(
+                &[("README.md", "# Tool\n\nDoes things.\n")],
+                Some(&first),
+                "Describe <the> tool",
+                "alex",
+            )
+            .await
+            .unwrap();
+        repo.update_ref("refs/heads/main", &second).await.unwrap();
+        repo.update_ref("refs/tags/v1.0", &first).await.unwrap();
+
+        let static_path = dir.path().join("static");
+        std::fs::create_dir_all(&static_path).unwrap();
+        FeedBuilder {
+            repo: &repo,
+            static_path: &static_path,
+            repo_name: "tool",
+            theme: DEFAULT_THEME,
+            repo_url: "https://example.com/alex/tool/",
+        }
+        .build()
+        .await
+        .unwrap();
+
+        let read_feed = |name| {
+            read_to_string(static_path.join(name))
+                .unwrap()
+                .replace("&#x2F;", "/")
+        };
+        let commits = read_feed("commits.atom");
+        assert_eq!(commits.matches("<entry>").count(), 2);
+        assert!(commits.contains(&format!("https://example.com/alex/tool/commit/{}/", second)));
+        assert!(commits.contains("Describe &lt;the&gt; tool"));
+        assert!(!commits.contains("<the>"));
+
+        let tags = read_feed("tags.atom");
+        assert_eq!(tags.matches("<entry>").count(), 1);
+        assert!(tags.contains("https://example.com/alex/tool/releases/#v1.0"));
+    }
+}
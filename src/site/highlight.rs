@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use comrak::plugins::syntect::SyntectAdapter;
+use log::warn;
+use syntect::{
+    highlighting::ThemeSet,
+    html::highlighted_html_for_string,
+    parsing::{SyntaxReference, SyntaxSet},
+};
+
+pub const DEFAULT_THEME: &str = "InspiredGitHub";
+
+/// Anything bigger than this is shown without highlighting, if at all.
+pub const MAX_HIGHLIGHT_SIZE: u64 = 512 * 1024;
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Picks the highlighting theme, preferring the repo's choice over the server's.
+/// Unknown themes fall back to the default.
+pub fn resolve_theme(repo_theme: Option<&str>, server_theme: Option<&str>) -> String {
+    let themes = &theme_set().themes;
+    for theme in [repo_theme, server_theme].into_iter().flatten() {
+        if themes.contains_key(theme) {
+            return theme.to_string();
+        }
+        warn!(
+            "Unknown highlight theme {:?}, try one of {:?}",
+            theme,
+            themes.keys()
+        );
+    }
+    DEFAULT_THEME.to_string()
+}
+
+/// Highlights fenced code blocks when rendering Markdown with comrak.
+pub fn markdown_highlighter(theme: &str) -> &'static SyntectAdapter {
+    // One adapter per theme, and there's only a handful of those, so they live forever.
+    static ADAPTERS: OnceLock<Mutex<HashMap<String, &'static SyntectAdapter>>> = OnceLock::new();
+    let mut adapters = ADAPTERS.get_or_init(Default::default).lock().unwrap();
+    adapters
+        .entry(theme.to_string())
+        .or_insert_with(|| Box::leak(Box::new(SyntectAdapter::new(theme))))
+}
+
+fn highlight(code: &str, syntax: &SyntaxReference, theme: &str) -> anyhow::Result<String> {
+    let theme = &theme_set().themes[theme];
+    Ok(highlighted_html_for_string(
+        code,
+        syntax_set(),
+        syntax,
+        theme,
+    )?)
+}
+
+/// Renders a source file to highlighted HTML, picking the syntax from the file name.
+pub fn highlight_file(path: &Path, code: &str, theme: &str) -> anyhow::Result<String> {
+    let syntax_set = syntax_set();
+    let syntax = path
+        .file_name()
+        .and_then(|name| syntax_set.find_syntax_by_extension(&name.to_string_lossy()))
+        .or_else(|| {
+            path.extension()
+                .and_then(|ext| syntax_set.find_syntax_by_extension(&ext.to_string_lossy()))
+        })
+        .or_else(|| syntax_set.find_syntax_by_first_line(code))
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+
+    highlight(code, syntax, theme)
+}
+
+/// Renders a unified diff to highlighted HTML.
+pub fn highlight_diff(diff: &str, theme: &str) -> anyhow::Result<String> {
+    let syntax_set = syntax_set();
+    let syntax = syntax_set
+        .find_syntax_by_extension("diff")
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+
+    highlight(diff, syntax, theme)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_themes_fall_back() {
+        let dark = "base16-ocean.dark";
+        assert_eq!(resolve_theme(Some(dark), Some("Solarized (light)")), dark);
+        assert_eq!(resolve_theme(Some("nope"), Some(dark)), dark);
+        assert_eq!(resolve_theme(None, Some("nope")), DEFAULT_THEME);
+        assert_eq!(resolve_theme(None, None), DEFAULT_THEME);
+    }
+
+    #[test]
+    fn syntax_comes_from_the_name_or_first_line() {
+        let code = "fn main() {}\n";
+        let rust = highlight_file(Path::new("src/main.rs"), code, DEFAULT_THEME).unwrap();
+        let plain = highlight_file(Path::new("main"), code, DEFAULT_THEME).unwrap();
+        assert_ne!(rust, plain);
+
+        let script = "#!/bin/sh\necho hi\n";
+        let shell = highlight_file(Path::new("run"), script, DEFAULT_THEME).unwrap();
+        let text = highlight_file(Path::new("run.txt"), script, DEFAULT_THEME).unwrap();
+        assert_ne!(shell, text);
+
+        let html = highlight_file(Path::new("notes"), "<script>", DEFAULT_THEME).unwrap();
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{create_dir_all, write},
+    path::Path,
+};
+
+use log::warn;
+use serde::Serialize;
+use tera::Context;
+
+use crate::{
+    config::repo::committed_repo_config,
+    git::{find_repos, Repo},
+};
+
+use super::{
+    assets::write_assets,
+    feeds::PushLog,
+    repo_owner, repo_url,
+    sitemap::{repo_pages, write_sitemap, SitemapPage},
+    static_path,
+    templates::render,
+    SiteBuilder,
+};
+
+#[derive(Serialize)]
+struct RepoListing {
+    name: String,
+    path: String,
+    description: Option<String>,
+    updated: Option<i64>,
+    clone_url: String,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct OwnerGroup {
+    owner: String,
+    repos: Vec<RepoListing>,
+}
+
+impl SiteBuilder {
+    /// Regenerates `static/index.html`, listing every public repo on the server.
+    pub async fn rebuild_index(&self) -> anyhow::Result<()> {
+        let _shared = self.shared.lock().await;
+        write_assets()?;
+
+        // Top-level repos are grouped under an empty owner, which sorts first.
+        let mut groups: BTreeMap<String, Vec<RepoListing>> = BTreeMap::new();
+        let mut public = HashSet::new();
+        let site_url = self.server_config.site_url();
+        let mut sitemap = vec![SitemapPage {
+            url: format!("{}/", site_url),
+            updated: None,
+        }];
+        let mut hidden = Vec::new();
+
+        for repo_path in find_repos(Path::new("."))? {
+            let repo = Repo::open(&repo_path);
+            let config = match committed_repo_config(&repo_path).await {
+                Ok(Some(config)) => config,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Skipping {} in index: {}", repo_path.display(), e);
+                    continue;
+                }
+            };
+
+            if !config.public {
+                continue;
+            }
+            public.insert(repo_path.clone());
+
+            // Repos without a README only have the file browser.
+            let site_path = static_path(&repo_path);
+            let has_readme = site_path.join("index.html").exists();
+            let site_path = site_path.strip_prefix("static")?.to_string_lossy();
+            let url = match has_readme {
+                true => format!("{}/", site_path),
+                false => format!("{}/tree/", site_path),
+            };
+
+            let updated = repo.latest_commit().await?.map(|c| c.date);
+            match config.indexable.unwrap_or(true) {
+                true => {
+                    for page in repo_pages(&static_path(&repo_path))? {
+                        sitemap.push(SitemapPage {
+                            url: format!("{}{}", repo_url(&site_url, &repo_path), page),
+                            updated,
+                        });
+                    }
+                }
+                false => hidden.push(site_path.to_string()),
+            }
+
+            let owner = repo_owner(&repo_path);
+
+            groups.entry(owner).or_default().push(RepoListing {
+                name: config.name,
+                path: repo_path.to_string_lossy().to_string(),
+                description: config.description,
+                updated,
+                clone_url: self.server_config.clone_url(&repo_path),
+                url,
+            });
+        }
+
+        let groups: Vec<OwnerGroup> = groups
+            .into_iter()
+            .map(|(owner, repos)| OwnerGroup { owner, repos })
+            .collect();
+
+        let mut context = Context::new();
+        context.insert("assets", "_assets/");
+        context.insert("server_name", &self.server_config.name);
+        context.insert("groups", &groups);
+        let result = render(&self.templates, "index.html", &context)?;
+
+        create_dir_all("static")?;
+        write(Path::new("static").join("index.html"), result)?;
+
+        write_sitemap(&site_url, &sitemap, &hidden)?;
+
+        let mut push_log = PushLog::load()?;
+        push_log.forget_private(&public)?;
+        push_log.write_feed(&self.server_config.name, &site_url)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    fs::{create_dir_all, remove_dir_all},
+    path::Path,
+};
+
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use crate::{
+    git::Repo,
+    issues::{history, list},
+};
+
+use super::{page_context, render_markdown, templates::render, write_page};
+
+#[derive(Serialize)]
+struct Comment {
+    author: String,
+    date: i64,
+    message: String,
+}
+
+/// Lists the repo's issues at `issues/`, with a page for each at `issues/<id>/`.
+pub struct IssueBuilder<'a> {
+    /// The bare repo, as clones don't get the issue refs.
+    pub repo: &'a Repo,
+    pub static_path: &'a Path,
+    pub context: &'a Context,
+    pub theme: &'a str,
+    pub templates: &'a Tera,
+}
+
+impl IssueBuilder<'_> {
+    pub async fn build(&self) -> anyhow::Result<()> {
+        // Issues are few and cheap to render, so they're all redone.
+        let issues_path = self.static_path.join("issues");
+        if issues_path.exists() {
+            remove_dir_all(&issues_path)?;
+        }
+        create_dir_all(&issues_path)?;
+
+        let issues = list(self.repo).await?;
+        for issue in &issues {
+            let comments: Vec<Comment> = history(self.repo, issue)
+                .await?
+                .into_iter()
+                .map(|commit| Comment {
+                    author: commit.author,
+                    date: commit.date,
+                    message: render_markdown(&commit.message, self.theme),
+                })
+                .collect();
+
+            let mut context = page_context(self.context, "../../");
+            context.insert("issue", issue);
+            context.insert("comments", &comments);
+            let result = render(self.templates, "issue.html", &context)?;
+            write_page(&issues_path.join(issue.id.to_string()), result)?;
+        }
+
+        // Newest first.
+        let mut context = page_context(self.context, "../");
+        context.insert("issues", &issues.iter().rev().collect::<Vec<_>>());
+        let result = render(self.templates, "issues.html", &context)?;
+        write_page(&issues_path, result)
+    }
+}
@@ -0,0 +1,186 @@
+use std::{fs::remove_dir_all, path::Path};
+
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use crate::git::{Commit, Repo};
+
+use super::{
+    build::BuildState,
+    highlight::{highlight_diff, MAX_HIGHLIGHT_SIZE},
+    page_context,
+    templates::render,
+    write_page,
+};
+
+const COMMITS_PER_PAGE: usize = 50;
+
+#[derive(Serialize)]
+struct Pagination {
+    page: usize,
+    pages: usize,
+    prev: Option<String>,
+    next: Option<String>,
+}
+
+/// Renders the commit history into `static/<repo>/log` and `static/<repo>/commit`.
+pub struct LogBuilder<'a> {
+    pub repo: &'a Repo,
+    pub static_path: &'a Path,
+    pub context: &'a Context,
+    pub theme: &'a str,
+    pub templates: &'a Tera,
+    /// Redo every commit page, like when the templates have changed.
+    pub rebuild_all: bool,
+}
+
+impl LogBuilder<'_> {
+    /// Returns the commits that are new since the last build.
+    pub async fn build(&self, build_state: &mut BuildState) -> anyhow::Result<Vec<Commit>> {
+        let commits = self.repo.log(&["HEAD"]).await?;
+
+        // The history pages shift with every commit, so they're always redone.
+        let log_path = self.static_path.join("log");
+        if log_path.exists() {
+            remove_dir_all(&log_path)?;
+        }
+
+        let pages = commits.len().div_ceil(COMMITS_PER_PAGE).max(1);
+        for page in 1..=pages {
+            let start = (page - 1) * COMMITS_PER_PAGE;
+            let end = commits.len().min(start + COMMITS_PER_PAGE);
+
+            // Page one is `log/`, the rest are `log/<n>/`.
+            let root = if page == 1 { "../" } else { "../../" };
+            let page_url = |n: usize| match n {
+                1 => format!("{}log/", root),
+                n => format!("{}log/{}/", root, n),
+            };
+
+            let mut context = page_context(self.context, root);
+            context.insert("commits", &commits[start..end]);
+            context.insert(
+                "pagination",
+                &Pagination {
+                    page,
+                    pages,
+                    prev: (page > 1).then(|| page_url(page - 1)),
+                    next: (page < pages).then(|| page_url(page + 1)),
+                },
+            );
+            let result = render(self.templates, "log.html", &context)?;
+
+            let dir = match page {
+                1 => log_path.clone(),
+                n => log_path.join(n.to_string()),
+            };
+            write_page(&dir, result)?;
+        }
+
+        // Commits never change, so only the ones since the last build need pages.
+        let new_commits = match &build_state.last_commit {
+            Some(last) if self.repo.is_ancestor(last, "HEAD").await? => {
+                self.repo.log(&[&format!("{}..HEAD", last)]).await?
+            }
+            _ => commits.clone(),
+        };
+
+        let stale_commits = match self.rebuild_all {
+            true => &commits,
+            false => &new_commits,
+        };
+        for commit in stale_commits {
+            let diff = self.repo.diff(&commit.hash).await?;
+            let stats = self.repo.diff_stats(&commit.hash).await?;
+            let diff = match diff.len() as u64 <= MAX_HIGHLIGHT_SIZE {
+                true => Some(highlight_diff(&diff, self.theme)?),
+                false => None,
+            };
+
+            let mut context = page_context(self.context, "../../");
+            context.insert("commit", commit);
+            context.insert("files", &stats);
+            context.insert("diff", &diff);
+            let result = render(self.templates, "commit.html", &context)?;
+
+            write_page(&self.static_path.join("commit").join(&commit.hash), result)?;
+        }
+
+        build_state.last_commit = commits.first().map(|c| c.hash.clone());
+        Ok(new_commits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::site::{highlight::resolve_theme, templates::builtin_templates};
+
+    async fn commit(repo: &Repo, parent: Option<&str>, n: usize) -> String {
+        let hash = repo
+            .write_commit(
+                &[("count.txt", &format!("{}\n", n))],
+                parent,
+                &format!("Commit {}", n),
+                "alex",
+            )
+            .await
+            .unwrap();
+        repo.update_ref("refs/heads/main", &hash).await.unwrap();
+        hash
+    }
+
+    async fn build(repo: &Repo, static_path: &Path, build_state: &mut BuildState) -> Vec<Commit> {
+        let mut context = Context::new();
+        context.insert("repo_name", "tool");
+        LogBuilder {
+            repo,
+            static_path,
+            context: &page_context(&context, ""),
+            theme: &resolve_theme(None, None),
+            templates: &builtin_templates(),
+            rebuild_all: false,
+        }
+        .build(build_state)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_new_commits_get_pages() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::create_bare(&dir.path().join("tool.git"))
+            .await
+            .unwrap();
+        let static_path = dir.path().join("static");
+        let mut head = None;
+        for n in 0..=COMMITS_PER_PAGE {
+            head = Some(commit(&repo, head.as_deref(), n).await);
+        }
+
+        let mut build_state = BuildState::default();
+        let first = build(&repo, &static_path, &mut build_state).await;
+        assert_eq!(first.len(), COMMITS_PER_PAGE + 1);
+        assert_eq!(build_state.last_commit, head);
+        let log_path = static_path.join("log");
+        assert!(log_path.join("index.html").is_file());
+        assert!(log_path.join("2").join("index.html").is_file());
+        assert!(!log_path.join("3").exists());
+        let commit_path = |hash: &str| static_path.join("commit").join(hash).join("index.html");
+        assert!(first
+            .iter()
+            .all(|commit| commit_path(&commit.hash).is_file()));
+
+        // An old page that's gone stays gone, as commits don't change.
+        let oldest = &first.last().unwrap().hash;
+        remove_dir_all(commit_path(oldest).parent().unwrap()).unwrap();
+        let newest = commit(&repo, head.as_deref(), COMMITS_PER_PAGE + 1).await;
+        let second = build(&repo, &static_path, &mut build_state).await;
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].hash, newest);
+        assert!(commit_path(&newest).is_file());
+        assert!(!commit_path(oldest).exists());
+    }
+}
@@ -0,0 +1,289 @@
+use std::path::{Component, Path};
+
+use clean_path::Clean;
+use comrak::{
+    arena_tree::NodeEdge,
+    format_html_with_plugins,
+    nodes::{AstNode, NodeCode, NodeValue},
+    parse_document, Anchorizer, Arena, ComrakOptions, ComrakPlugins,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Serialize;
+
+use crate::config::repo::MarkdownConfig;
+
+use super::{docs::doc_url, highlight::markdown_highlighter};
+
+/// Characters that can't appear as-is in a URL path.
+pub const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub fn markdown_options(config: &MarkdownConfig) -> ComrakOptions {
+    let mut options = ComrakOptions::default();
+    options.extension.table = config.table;
+    options.extension.tasklist = config.tasklist;
+    options.extension.strikethrough = config.strikethrough;
+    options.extension.autolink = config.autolink;
+    options.extension.footnotes = config.footnotes;
+    options
+}
+
+/// Points relative links in a Markdown file at the generated file browser.
+pub struct LinkRewriter<'a> {
+    pub clone_dir: &'a Path,
+    /// The directory of the Markdown file, relative to the repo.
+    pub base_dir: &'a Path,
+    /// The relative path from the rendered page to the repo's main page.
+    pub root: &'a str,
+    /// Markdown files in here link to their rendered docs page instead.
+    pub docs_dir: Option<&'a Path>,
+}
+
+#[derive(Serialize)]
+pub struct Heading {
+    pub level: u8,
+    pub text: String,
+    pub id: String,
+}
+
+pub struct Rendered {
+    pub html: String,
+    /// The text of the first top-level heading.
+    pub title: Option<String>,
+    pub headings: Vec<Heading>,
+    /// The text of the first paragraph, for page descriptions.
+    pub summary: Option<String>,
+    /// Everything the document says, as plain text.
+    pub text: String,
+}
+
+/// A heading's plain text, the same way comrak gathers it for anchors.
+fn collect_text<'a>(node: &'a AstNode<'a>, output: &mut String) {
+    match &node.data.borrow().value {
+        NodeValue::Text(literal) | NodeValue::Code(NodeCode { literal, .. }) => {
+            output.push_str(literal)
+        }
+        NodeValue::LineBreak | NodeValue::SoftBreak => output.push(' '),
+        _ => {
+            for child in node.children() {
+                collect_text(child, output);
+            }
+        }
+    }
+}
+
+/// A document's plain text, with its blocks kept apart and whitespace collapsed.
+fn document_text<'a>(root: &'a AstNode<'a>) -> String {
+    let mut text = String::new();
+    for edge in root.traverse() {
+        match edge {
+            NodeEdge::Start(node) => match &node.data.borrow().value {
+                NodeValue::Text(literal) | NodeValue::Code(NodeCode { literal, .. }) => {
+                    text.push_str(literal)
+                }
+                NodeValue::CodeBlock(block) => text.push_str(&block.literal),
+                NodeValue::LineBreak | NodeValue::SoftBreak => text.push(' '),
+                _ => {}
+            },
+            NodeEdge::End(node) => {
+                if node.data.borrow().value.block() {
+                    text.push(' ');
+                }
+            }
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl LinkRewriter<'_> {
+    fn rewrite(&self, url: &str, is_image: bool) -> Option<String> {
+        let is_relative = !(url.is_empty()
+            || url.starts_with('#')
+            || url.starts_with('/')
+            || url.split('/').next()?.contains(':'));
+        if !is_relative {
+            return None;
+        }
+
+        let (url, fragment) = match url.split_once('#') {
+            Some((url, fragment)) => (url, format!("#{}", fragment)),
+            None => (url, String::new()),
+        };
+        let url = url.split('?').next()?;
+        let path = percent_decode_str(url).decode_utf8().ok()?;
+
+        // Links outside the repo, or to files that don't exist, are left alone.
+        let path = self.base_dir.join(path.as_ref()).clean();
+        if path.components().next() == Some(Component::ParentDir) {
+            return None;
+        }
+        let full_path = self.clone_dir.join(&path);
+        if !full_path.exists() {
+            return None;
+        }
+
+        if let Some(docs_dir) = self.docs_dir {
+            let is_markdown = path.extension().unwrap_or_default() == "md";
+            if let (true, Ok(doc)) = (is_markdown, path.strip_prefix(docs_dir)) {
+                return Some(format!("{}{}{}", self.root, doc_url(doc), fragment));
+            }
+        }
+
+        let encoded = utf8_percent_encode(&path.to_string_lossy(), PATH).to_string();
+        if path.as_os_str().is_empty() || path == Path::new(".") {
+            return Some(format!("{}tree/{}", self.root, fragment));
+        }
+
+        if !full_path.is_file() {
+            return Some(format!("{}tree/{}/{}", self.root, encoded, fragment));
+        }
+        // Images point straight at the file browser's raw copy.
+        match is_image {
+            true => Some(format!("{}raw/{}", self.root, encoded)),
+            false => Some(format!("{}blob/{}/{}", self.root, encoded, fragment)),
+        }
+    }
+}
+
+/// Renders Markdown to HTML, with code blocks highlighted at build time.
+pub fn render_document(
+    text: &str,
+    options: &ComrakOptions,
+    theme: &str,
+    links: Option<&LinkRewriter>,
+) -> Rendered {
+    let arena = Arena::new();
+    let root = parse_document(&arena, text, options);
+
+    let mut title = None;
+    let mut headings = Vec::new();
+    let mut anchorizer = Anchorizer::new();
+    for node in root.descendants() {
+        let level = match &node.data.borrow().value {
+            NodeValue::Heading(heading) => heading.level,
+            _ => continue,
+        };
+
+        let mut text = String::new();
+        collect_text(node, &mut text);
+        if level == 1 && title.is_none() {
+            title = Some(text.clone());
+        }
+        headings.push(Heading {
+            level,
+            id: anchorizer.anchorize(text.clone()),
+            text,
+        });
+    }
+
+    let summary = root
+        .children()
+        .find(|node| matches!(node.data.borrow().value, NodeValue::Paragraph))
+        .map(|node| {
+            let mut text = String::new();
+            collect_text(node, &mut text);
+            text
+        });
+    let text = document_text(root);
+
+    if let Some(links) = links {
+        for edge in root.traverse() {
+            if let NodeEdge::Start(node) = edge {
+                let mut data = node.data.borrow_mut();
+                let (link, is_image) = match &mut data.value {
+                    NodeValue::Link(link) => (link, false),
+                    NodeValue::Image(link) => (link, true),
+                    _ => continue,
+                };
+                if let Some(url) = links.rewrite(&link.url, is_image) {
+                    link.url = url;
+                }
+            }
+        }
+    }
+
+    let mut plugins = ComrakPlugins::default();
+    plugins.render.codefence_syntax_highlighter = Some(markdown_highlighter(theme));
+
+    let mut html = Vec::new();
+    // Writing to a Vec can't fail.
+    format_html_with_plugins(root, options, &mut html, &plugins).unwrap();
+
+    Rendered {
+        html: String::from_utf8_lossy(&html).to_string(),
+        title,
+        headings,
+        summary,
+        text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn links_point_at_the_file_browser() {
+        let dir = tempdir().unwrap();
+        let clone_dir = dir.path();
+        create_dir_all(clone_dir.join("src")).unwrap();
+        create_dir_all(clone_dir.join("docs").join("guide")).unwrap();
+        for file in [
+            "src/main.rs",
+            "logo one.png",
+            "docs/guide/setup.md",
+            "NOTES.md",
+        ] {
+            write(clone_dir.join(file), "").unwrap();
+        }
+        let links = LinkRewriter {
+            clone_dir,
+            base_dir: Path::new("docs"),
+            root: "../",
+            docs_dir: Some(Path::new("docs")),
+        };
+        let rewrite = |url| links.rewrite(url, false);
+
+        // Anything that isn't a relative link to a file in the repo is left alone.
+        for url in [
+            "",
+            "#usage",
+            "/src/main.rs",
+            "https://example.com/src/main.rs",
+            "mailto:alex@example.com",
+            "../../outside.md",
+            "missing.md",
+        ] {
+            assert_eq!(rewrite(url), None, "{}", url);
+        }
+
+        assert_eq!(rewrite("..").as_deref(), Some("../tree/"));
+        assert_eq!(rewrite("../src").as_deref(), Some("../tree/src/"));
+        assert_eq!(
+            rewrite("../src/main.rs?plain=1#L3").as_deref(),
+            Some("../blob/src/main.rs/#L3")
+        );
+        assert_eq!(rewrite("../NOTES.md").as_deref(), Some("../blob/NOTES.md/"));
+        assert_eq!(
+            rewrite("guide/setup.md#install").as_deref(),
+            Some(format!("../{}#install", doc_url(Path::new("guide/setup.md"))).as_str())
+        );
+        assert_eq!(
+            links.rewrite("../logo%20one.png", true).as_deref(),
+            Some("../raw/logo%20one.png")
+        );
+    }
+}
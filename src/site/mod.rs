@@ -1,86 +1,432 @@
 use std::{
-    fs::{create_dir_all, read_to_string, write},
-    path::{Path, PathBuf},
+    fs::create_dir_all,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
 };
 
-use anyhow::Context as AnyhowContext;
-use comrak::{markdown_to_html, ComrakOptions};
+use ::log::debug;
 use tempfile::tempdir;
 use tera::{Context, Tera};
+use tokio::sync::Mutex;
 
-use crate::{config::repo::RepoConfig, git::Repo, state::State, vars::*};
+use crate::{
+    config::{
+        repo::{committed_repo_config, load_repo_config, MarkdownConfig},
+        server::ServerConfig,
+    },
+    git::Repo,
+    state::State,
+    vars::*,
+};
 
-impl State {
-    pub async fn rebuild_site(&self, repo_path: &Path) -> anyhow::Result<()> {
-        let config_name = PathBuf::from(REPO_CONFIG_FILE);
-        let readmes = ["README.md", "readme.me"];
+mod assets;
+mod build;
+mod checkout;
+mod docs;
+mod feeds;
+mod highlight;
+mod index;
+mod issues;
+mod log;
+mod markdown;
+mod preview;
+mod reconcile;
+mod releases;
+mod search;
+mod sitemap;
+mod templates;
+mod tree;
+use self::{
+    build::{hash_inputs, publish, remove_site, stage, staging_path, write_file, BuildState},
+    checkout::{docs_dir, CheckoutBuilder},
+    feeds::{FeedBuilder, PushLog},
+    highlight::resolve_theme,
+    issues::IssueBuilder,
+    log::LogBuilder,
+    markdown::{markdown_options, render_document},
+    preview::{find_previews, Preview, PreviewBuilder},
+    releases::ReleaseBuilder,
+    templates::repo_templates,
+};
+pub use self::{
+    reconcile::reconcile_sites,
+    templates::{builtin_templates, load_templates},
+};
 
-        let temp_dir = tempdir()?;
-        let clone_dir = temp_dir.path().join(repo_path);
-        Repo::clone(repo_path, &clone_dir).await?;
+/// Writes a page as `index.html` inside its own directory.
+fn write_page(dir: &Path, html: String) -> anyhow::Result<()> {
+    create_dir_all(dir)?;
+    write_file(&dir.join("index.html"), html)?;
+    Ok(())
+}
 
-        let config: RepoConfig = {
-            let text =
-                read_to_string(clone_dir.join(&config_name)).context("Couldn't read eejit.toml")?;
-            toml::from_str(&text)?
-        };
+/// Clones the shared page context for a page `root` away from the repo's main page.
+fn page_context(base: &Context, root: &str) -> Context {
+    let static_root = base
+        .get("static_root")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
 
-        if !config.public {
-            return Ok(());
+    let mut context = base.clone();
+    context.insert("root", root);
+    context.insert("assets", &format!("{}{}_assets/", root, static_root));
+    context
+}
+
+/// Where a repo's site goes: its path under `static/`, without the `.git`.
+fn static_path(repo_path: &Path) -> PathBuf {
+    let mut static_path = PathBuf::from("static").join(repo_path);
+    if let Some(ext) = static_path.extension() {
+        if ext == "git" {
+            static_path.set_extension("");
         }
+    }
+    static_path
+}
+
+/// The absolute URL of a repo's pages, with a trailing slash.
+fn repo_url(site_url: &str, repo_path: &Path) -> String {
+    let static_path = static_path(repo_path);
+    let path = static_path.strip_prefix("static").unwrap_or(&static_path);
+    format!("{}/{}/", site_url, path.to_string_lossy())
+}
+
+/// The user a repo belongs to, which is empty for top-level repos.
+fn repo_owner(repo_path: &Path) -> String {
+    match repo_path.components().count() {
+        1 => String::new(),
+        _ => match repo_path.components().next() {
+            Some(Component::Normal(dir)) => dir.to_string_lossy().to_string(),
+            _ => String::new(),
+        },
+    }
+}
+
+/// A path from a repo's config, as long as it stays inside the checkout. Absolute paths, `..`
+/// and symlinks out of the checkout would otherwise publish files from elsewhere on the server.
+fn checkout_path<'a>(clone_dir: &Path, path: &'a str) -> Option<&'a Path> {
+    let path = Path::new(path);
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    let resolved = clone_dir.join(path).canonicalize().ok()?;
+    resolved
+        .starts_with(clone_dir.canonicalize().ok()?)
+        .then_some(path)
+}
 
-        let mut readme = None;
-        for r in readmes {
-            let path = clone_dir.join(r);
-            if path.exists() {
-                readme = Some(path);
-                break;
-            }
+/// Renders Markdown that doesn't come from a file, like commit and tag messages.
+fn render_markdown(text: &str, theme: &str) -> String {
+    let options = markdown_options(&MarkdownConfig::default());
+    render_document(text, &options, theme, None).html
+}
+
+/// What building sites takes from the server's state. It's a copy, so a build only holds up the
+/// repo it's for.
+pub struct SiteBuilder {
+    pub server_config: ServerConfig,
+    pub templates: Tera,
+    /// Held while writing what every site shares: the index, the sitemap and the push log.
+    pub shared: Arc<Mutex<()>>,
+}
+
+/// The site builder, and the lock of the repo it's about to build.
+async fn lock_repo(state: &Arc<Mutex<State>>, repo_path: &Path) -> (SiteBuilder, Arc<Mutex<()>>) {
+    let mut state = state.lock().await;
+    (state.site_builder(), state.repo_lock(repo_path))
+}
+
+/// Rebuilds a repo's pages, and the server index that lists it.
+pub async fn rebuild_site(state: &Arc<Mutex<State>>, repo_path: &Path) -> anyhow::Result<()> {
+    let (builder, repo_lock) = lock_repo(state, repo_path).await;
+    let _repo_lock = repo_lock.lock().await;
+    builder.build_repo_site(repo_path, false).await?;
+    builder.rebuild_index().await
+}
+
+/// Redoes every page of a repo's site, for when something outside the repo has changed, like
+/// gitenator itself. The index is left as it is.
+pub async fn force_rebuild_site(state: &Arc<Mutex<State>>, repo_path: &Path) -> anyhow::Result<()> {
+    let (builder, repo_lock) = lock_repo(state, repo_path).await;
+    let _repo_lock = repo_lock.lock().await;
+    builder.build_repo_site(repo_path, true).await
+}
+
+/// Regenerates the server index, for when no one repo has changed.
+pub async fn rebuild_index(state: &Arc<Mutex<State>>) -> anyhow::Result<()> {
+    let builder = state.lock().await.site_builder();
+    builder.rebuild_index().await
+}
+
+impl SiteBuilder {
+    async fn build_repo_site(&self, repo_path: &Path, force: bool) -> anyhow::Result<()> {
+        let static_path = static_path(repo_path);
+        let mut build_state = BuildState::load(&static_path)?;
+        if force {
+            build_state.refs = None;
+            build_state.inputs = None;
         }
 
-        if readme.is_none() {
+        // Nothing a page shows can change without moving a ref, here or in the server config.
+        let config_head = Repo::open(Path::new(SERVER_CONFIG_REPO))
+            .latest_commit()
+            .await?
+            .map(|c| c.hash)
+            .unwrap_or_default();
+        let refs = Repo::open(repo_path).refs(&[]).await?;
+        let refs = hash_inputs(
+            [env!("CARGO_PKG_VERSION"), &config_head].into_iter().chain(
+                refs.iter()
+                    .flat_map(|(name, object)| [name.as_str(), object]),
+            ),
+        );
+        if build_state.refs.as_ref() == Some(&refs) {
+            debug!("Site for {} is up to date", repo_path.display());
             return Ok(());
         }
 
-        let readme = read_to_string(readme.unwrap())?;
-        let body = markdown_to_html(&readme, &ComrakOptions::default());
+        let temp_dir = tempdir()?;
+        let clone_dir = temp_dir.path().join(repo_path);
+        let repo = Repo::clone(repo_path, &clone_dir).await?;
+
+        let config = load_repo_config(repo_path).await?;
+
+        if !config.public {
+            return remove_site(&static_path);
+        }
+
+        // Everything is written to a copy of the site, which replaces it at the end.
+        let staging = staging_path(&static_path);
+        stage(&static_path, &staging)?;
+
+        let theme = resolve_theme(
+            config.highlight_theme.as_deref(),
+            self.server_config.highlight_theme.as_deref(),
+        );
+
+        // Relative links from the repo's main page back up to `static/`.
+        let static_root = "../".repeat(static_path.components().count() - 1);
 
         let mut context = Context::new();
+        context.insert("static_root", &static_root);
+        let mut context = page_context(&context, "");
         context.insert("repo_name", &config.name);
-        context.insert("content", &body);
-        context.insert(
-            "clone_url",
-            &format!(
-                "ssh://{}:{}/{}",
-                self.server_config.hostname,
-                self.server_config.port,
-                repo_path.to_string_lossy()
-            ),
+        context.insert("description", &config.description);
+        context.insert("clone_url", &self.server_config.clone_url(repo_path));
+        context.insert("server_name", &self.server_config.name);
+        context.insert("owner", &repo_owner(repo_path));
+        context.insert("repo_root", "");
+        context.insert("url", &repo_url(&self.server_config.site_url(), repo_path));
+        context.insert("indexable", &config.indexable.unwrap_or(true));
+        context.insert("has_docs", &docs_dir(&config, &clone_dir).is_some());
+        if let Some(source) = &config.forked_from {
+            // Only linked if the original has a site to link to.
+            let source_path = Path::new(source);
+            let is_public = matches!(
+                committed_repo_config(source_path).await,
+                Ok(Some(source_config)) if source_config.public
+            );
+            context.insert("forked_from", source);
+            context.insert(
+                "forked_from_url",
+                &is_public.then(|| repo_url(&self.server_config.site_url(), source_path)),
+            );
+        }
+
+        let templates = repo_templates(&self.templates, &clone_dir, &config)?;
+
+        // What goes into every page besides its own content. The shared templates come from config.git,
+        // and the repo's own are covered by their object IDs.
+        let objects = repo.tree_objects("HEAD").await?;
+        let template_objects = [
+            &config.web_template,
+            &config.tree_template,
+            &config.blob_template,
+            &config.log_template,
+            &config.commit_template,
+            &config.releases_template,
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|path| objects.get(Path::new(path)))
+        .map(String::as_str);
+        let inputs = hash_inputs(
+            [
+                env!("CARGO_PKG_VERSION"),
+                &config_head,
+                &theme,
+                &context.clone().into_json().to_string(),
+                // Mirrors keep theirs outside of the tree.
+                &toml::to_string(&config)?,
+            ]
+            .into_iter()
+            .chain(template_objects),
         );
+        let inputs_changed = build_state.inputs.as_ref() != Some(&inputs);
+
+        // The README and docs can show what's new, and switch between previews of other refs.
+        let bare_repo = Repo::open(repo_path);
+        let head_branch = bare_repo.head_branch().await?;
+        let previews = find_previews(&bare_repo, &config, &head_branch).await?;
+        let mut switcher = vec![Preview {
+            name: head_branch.clone(),
+            path: String::new(),
+            object: String::new(),
+        }];
+        switcher.extend(previews.iter().cloned());
+        let mut main_context = Context::new();
+        main_context.insert("branches", &bare_repo.branches().await?);
+        main_context.insert("last_commit", &repo.latest_commit().await?);
+        main_context.insert("previews", &switcher);
+        main_context.insert("current_ref", &head_branch);
+
+        build_state.tree = CheckoutBuilder {
+            clone_dir: &clone_dir,
+            static_path: &staging,
+            context: &context,
+            main_context: &main_context,
+            theme: &theme,
+            templates: &templates,
+            config: &config,
+            objects: &objects,
+            inputs: &inputs,
+        }
+        .build(&build_state.tree)?;
+
+        let new_commits = LogBuilder {
+            repo: &repo,
+            static_path: &staging,
+            context: &context,
+            theme: &theme,
+            templates: &templates,
+            rebuild_all: inputs_changed,
+        }
+        .build(&mut build_state)
+        .await?;
+        {
+            let _shared = self.shared.lock().await;
+            PushLog::record(repo_path, &config.name, &new_commits)?;
+        }
+
+        ReleaseBuilder {
+            repo: &repo,
+            static_path: &staging,
+            context: &context,
+            theme: &theme,
+            templates: &templates,
+            project_name: &repo_path.file_stem().unwrap_or_default().to_string_lossy(),
+        }
+        .build(&mut build_state)
+        .await?;
+
+        IssueBuilder {
+            repo: &bare_repo,
+            static_path: &staging,
+            context: &context,
+            theme: &theme,
+            templates: &templates,
+        }
+        .build()
+        .await?;
 
-        let template = {
-            if let Some(path) = config.web_template {
-                read_to_string(clone_dir.join(path)).context("Couldn't read user template")?
-            } else {
-                include_str!("default.html").to_string()
-            }
-        };
-
-        let result = Tera::one_off(&template, &context, true)?;
-
-        let mut static_path = PathBuf::from("static").join(repo_path);
-        if let Some(ext) = static_path.extension() {
-            if ext == "git" {
-                static_path.set_extension("");
-            }
+        FeedBuilder {
+            repo: &repo,
+            static_path: &staging,
+            repo_name: &config.name,
+            theme: &theme,
+            repo_url: &repo_url(&self.server_config.site_url(), repo_path),
         }
+        .build()
+        .await?;
 
-        if !static_path.exists() {
-            create_dir_all(&static_path)?;
+        // Checks out each preview in turn, so it comes last.
+        build_state.previews = PreviewBuilder {
+            repo: &repo,
+            clone_dir: &clone_dir,
+            static_path: &staging,
+            context: &context,
+            main_context: &main_context,
+            theme: &theme,
+            templates: &templates,
+            config: &config,
+            inputs: &inputs,
+            previews: &previews,
         }
+        .build(&build_state.previews)
+        .await?;
 
-        write(static_path.join("index.html"), result)?;
+        build_state.refs = Some(refs);
+        build_state.inputs = Some(inputs);
+        build_state.save(&staging)?;
+        publish(&staging, &static_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::create_dir_all, os::unix::fs::symlink};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn static_path_drops_the_git_extension() {
+        assert_eq!(
+            static_path(Path::new("alex/tool.git")),
+            Path::new("static/alex/tool")
+        );
+        assert_eq!(static_path(Path::new("tool.git")), Path::new("static/tool"));
+        assert_eq!(
+            static_path(Path::new("alex/tool")),
+            Path::new("static/alex/tool")
+        );
+    }
+
+    #[test]
+    fn repo_url_ends_in_a_slash() {
+        assert_eq!(
+            repo_url("https://example.com", Path::new("alex/tool.git")),
+            "https://example.com/alex/tool/"
+        );
+        assert_eq!(
+            repo_url("https://example.com", Path::new("tool.git")),
+            "https://example.com/tool/"
+        );
+    }
+
+    #[test]
+    fn repo_owner_is_the_first_directory() {
+        assert_eq!(repo_owner(Path::new("alex/tool.git")), "alex");
+        assert_eq!(repo_owner(Path::new("alex/group/tool.git")), "alex");
+        assert_eq!(repo_owner(Path::new("tool.git")), "");
+    }
+
+    #[test]
+    fn checkout_path_stays_inside_the_checkout() {
+        let temp_dir = tempdir().unwrap();
+        let clone_dir = temp_dir.path().join("clone");
+        create_dir_all(clone_dir.join("docs/guide")).unwrap();
+        symlink("/etc", clone_dir.join("etc")).unwrap();
+        symlink("docs", clone_dir.join("manual")).unwrap();
+
+        assert_eq!(checkout_path(&clone_dir, "docs"), Some(Path::new("docs")));
+        assert_eq!(
+            checkout_path(&clone_dir, "docs/guide"),
+            Some(Path::new("docs/guide"))
+        );
+        // Symlinks are fine as long as they don't lead out.
+        assert_eq!(
+            checkout_path(&clone_dir, "manual"),
+            Some(Path::new("manual"))
+        );
 
-        Ok(())
+        assert_eq!(checkout_path(&clone_dir, "/etc"), None);
+        assert_eq!(checkout_path(&clone_dir, "../clone/docs"), None);
+        assert_eq!(checkout_path(&clone_dir, "docs/../../.."), None);
+        assert_eq!(checkout_path(&clone_dir, "./docs"), None);
+        assert_eq!(checkout_path(&clone_dir, "etc"), None);
+        assert_eq!(checkout_path(&clone_dir, "missing"), None);
     }
 }
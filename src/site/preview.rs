@@ -0,0 +1,230 @@
+use std::{
+    collections::BTreeMap,
+    fs::{remove_dir, remove_dir_all},
+    path::{Path, PathBuf},
+};
+
+use percent_encoding::utf8_percent_encode;
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use crate::{config::repo::RepoConfig, git::Repo};
+
+use super::{
+    build::hash_inputs,
+    checkout::{docs_dir, CheckoutBuilder},
+    markdown::PATH,
+    page_context,
+};
+
+/// A branch or tag with its own copy of the site, for the ref switcher.
+#[derive(Serialize, Clone)]
+pub struct Preview {
+    pub name: String,
+    /// Relative to the repo's main page.
+    pub path: String,
+    #[serde(skip)]
+    pub object: String,
+}
+
+impl Preview {
+    /// Where the preview goes, relative to the repo's site.
+    fn dir(&self) -> PathBuf {
+        Path::new("refs").join(&self.name)
+    }
+}
+
+/// Finds the branches and tags the repo config asks for previews of. The default branch is the site
+/// itself, so it's left out.
+pub async fn find_previews(
+    repo: &Repo,
+    config: &RepoConfig,
+    head_branch: &str,
+) -> anyhow::Result<Vec<Preview>> {
+    let Some(site) = &config.site else {
+        return Ok(Vec::new());
+    };
+    let patterns: Vec<String> = site
+        .branches
+        .iter()
+        .map(|pattern| format!("refs/heads/{}", pattern))
+        .chain(
+            site.tags
+                .iter()
+                .map(|pattern| format!("refs/tags/{}", pattern)),
+        )
+        .collect();
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    let mut previews: BTreeMap<String, Preview> = BTreeMap::new();
+    // Branches come first, so they win over tags of the same name.
+    for (refname, object) in repo.refs(&patterns).await? {
+        let name = match refname.strip_prefix("refs/heads/") {
+            Some(branch) if branch == head_branch => continue,
+            Some(branch) => branch,
+            None => refname.strip_prefix("refs/tags/").unwrap_or(&refname),
+        };
+        previews.entry(name.to_string()).or_insert_with(|| Preview {
+            name: name.to_string(),
+            path: format!("refs/{}/", utf8_percent_encode(name, PATH)),
+            object,
+        });
+    }
+
+    Ok(previews.into_values().collect())
+}
+
+/// Renders a copy of the file browser, docs and README for each preview, under `static/<repo>/refs`.
+pub struct PreviewBuilder<'a> {
+    /// A clone, which gets each preview checked out in turn.
+    pub repo: &'a Repo,
+    pub clone_dir: &'a Path,
+    pub static_path: &'a Path,
+    pub context: &'a Context,
+    pub main_context: &'a Context,
+    pub theme: &'a str,
+    pub templates: &'a Tera,
+    pub config: &'a RepoConfig,
+    pub inputs: &'a str,
+    pub previews: &'a [Preview],
+}
+
+impl PreviewBuilder<'_> {
+    /// Returns the file browser's page keys for each preview, given the ones from the last build.
+    pub async fn build(
+        &self,
+        previous: &BTreeMap<String, BTreeMap<String, String>>,
+    ) -> anyhow::Result<BTreeMap<String, BTreeMap<String, String>>> {
+        let static_root = self
+            .context
+            .get("static_root")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+
+        let mut built = BTreeMap::new();
+        for preview in self.previews {
+            self.repo.checkout(&preview.object).await?;
+
+            // Previews are pages of their own, a few levels below the repo's.
+            let repo_root = "../".repeat(preview.dir().components().count());
+            let mut context = self.context.clone();
+            context.insert("static_root", &format!("{}{}", repo_root, static_root));
+            context.insert("repo_root", &repo_root);
+            if let Some(url) = self.context.get("url").and_then(|v| v.as_str()) {
+                context.insert("url", &format!("{}{}", url, preview.path));
+            }
+            context.insert("has_docs", &docs_dir(self.config, self.clone_dir).is_some());
+            let context = page_context(&context, "");
+
+            let mut main_context = self.main_context.clone();
+            main_context.insert("current_ref", &preview.name);
+            main_context.insert(
+                "last_commit",
+                &self.repo.log(&["-1", &preview.object]).await?.first(),
+            );
+
+            let objects = self.repo.tree_objects(&preview.object).await?;
+            let inputs = hash_inputs([self.inputs, &context.clone().into_json().to_string()]);
+            let pages = CheckoutBuilder {
+                clone_dir: self.clone_dir,
+                static_path: &self.static_path.join(preview.dir()),
+                context: &context,
+                main_context: &main_context,
+                theme: self.theme,
+                templates: self.templates,
+                config: self.config,
+                objects: &objects,
+                inputs: &inputs,
+            }
+            .build(previous.get(&preview.name).unwrap_or(&BTreeMap::new()))?;
+            built.insert(preview.name.clone(), pages);
+        }
+
+        // Previews of refs that are gone, or aren't asked for anymore.
+        let refs_dir = self.static_path.join("refs");
+        for name in previous.keys() {
+            let dir = refs_dir.join(name);
+            if built.contains_key(name) || !dir.exists() {
+                continue;
+            }
+            remove_dir_all(&dir)?;
+
+            // Along with the folders of refs like `feature/x`, once they're empty.
+            for parent in dir.ancestors().skip(1) {
+                if !parent.starts_with(&refs_dir) || remove_dir(parent).is_err() {
+                    break;
+                }
+            }
+        }
+
+        Ok(built)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn previews_are_the_refs_asked_for() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::create_bare(&dir.path().join("tool.git"))
+            .await
+            .unwrap();
+        let first = repo
+            .write_commit(&[("README.md", "# Tool\n")], None, "First", "alex")
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(
+                &[("README.md", "# Tool 2\n")],
+                Some(&first),
+                "Second",
+                "alex",
+            )
+            .await
+            .unwrap();
+        for (refname, hash) in [
+            ("refs/heads/main", &first),
+            ("refs/heads/next", &second),
+            ("refs/heads/feature/ü", &second),
+            ("refs/heads/wip/x", &second),
+            ("refs/tags/v1", &first),
+            ("refs/tags/next", &first),
+            ("refs/tags/old", &first),
+        ] {
+            repo.update_ref(refname, hash).await.unwrap();
+        }
+
+        let mut config: RepoConfig =
+            toml::from_str("name = \"tool\"\npublic = true\nmembers = []\n").unwrap();
+        assert!(find_previews(&repo, &config, "main")
+            .await
+            .unwrap()
+            .is_empty());
+
+        config.site = Some(
+            toml::from_str("branches = [\"*\", \"feature/*\"]\ntags = [\"v*\", \"next\"]").unwrap(),
+        );
+        let previews = find_previews(&repo, &config, "main").await.unwrap();
+        let found: Vec<_> = previews
+            .iter()
+            .map(|p| (p.name.as_str(), p.path.as_str(), p.object.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("feature/ü", "refs/feature/%C3%BC/", second.as_str()),
+                // The branch wins over the tag.
+                ("next", "refs/next/", second.as_str()),
+                ("v1", "refs/v1/", first.as_str()),
+            ]
+        );
+        assert_eq!(previews[0].dir(), Path::new("refs/feature/ü"));
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    fs::{read_dir, remove_dir_all},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{info, warn};
+use tokio::sync::Mutex;
+
+use crate::{config::repo::committed_repo_config, git::find_repos, state::State, vars::*};
+
+use super::build::remove_site;
+
+/// Finds the generated sites under `static/`: directories with a build state or a main page.
+fn find_sites(dir: &Path, sites: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        // Skips the shared assets and unfinished builds.
+        if !entry.file_type()?.is_dir() || name.to_string_lossy().starts_with(['.', '_']) {
+            continue;
+        }
+
+        if path.join(SITE_STATE_FILE).exists() || path.join("index.html").exists() {
+            sites.push(path);
+        } else {
+            find_sites(&path, sites)?;
+        }
+    }
+    Ok(())
+}
+
+/// The repo a site was generated from, if it's still there.
+fn site_repo(site: &Path) -> Option<PathBuf> {
+    let path = site.strip_prefix("static").ok()?;
+    let mut repo_path = path.to_path_buf().into_os_string();
+    repo_path.push(".git");
+
+    [PathBuf::from(repo_path), path.to_path_buf()]
+        .into_iter()
+        .find(|repo_path| repo_path.exists())
+}
+
+/// Brings `static/` in line with the repos on disk. Sites of repos that are gone or not public
+/// anymore are taken down, along with unfinished builds, and the index is rebuilt.
+pub async fn reconcile_sites(state: &Arc<Mutex<State>>) -> anyhow::Result<()> {
+    let builder = state.lock().await.site_builder();
+    let static_dir = Path::new("static");
+    if !static_dir.exists() {
+        return builder.rebuild_index().await;
+    }
+
+    let mut sites = Vec::new();
+    find_sites(static_dir, &mut sites)?;
+
+    for site in sites {
+        let repo_path = site_repo(&site);
+        let is_public = match &repo_path {
+            Some(repo_path) => match committed_repo_config(repo_path).await {
+                Ok(config) => config.map(|c| c.public).unwrap_or(false),
+                // Better to leave it up than to take it down over a typo.
+                Err(e) => {
+                    warn!("Can't tell if {} is public: {}", repo_path.display(), e);
+                    continue;
+                }
+            },
+            None => false,
+        };
+
+        if !is_public {
+            info!("Removing site {}", site.display());
+            // Not from under a build of it, if its repo's still there to build.
+            let repo_lock = state
+                .lock()
+                .await
+                .repo_lock(repo_path.as_deref().unwrap_or(&site));
+            let _repo_lock = repo_lock.lock().await;
+            remove_site(&site)?;
+        }
+    }
+
+    let build_dir = static_dir.join(".build");
+    if build_dir.exists() {
+        // Builds that are still going are waited for, so only unfinished ones are removed.
+        let repo_locks: Vec<_> = {
+            let mut state = state.lock().await;
+            find_repos(Path::new("."))?
+                .iter()
+                .map(|repo_path| state.repo_lock(repo_path))
+                .collect()
+        };
+        let mut held = Vec::new();
+        for repo_lock in &repo_locks {
+            held.push(repo_lock.lock().await);
+        }
+        remove_dir_all(build_dir)?;
+    }
+
+    builder.rebuild_index().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn sites_are_found_under_their_owners() {
+        let dir = tempdir().unwrap();
+        let static_dir = dir.path();
+        for page in [
+            "alex/tool/index.html",
+            "alex/tool/log/index.html",
+            "alex/group/lib/index.html",
+            "_assets/index.html",
+            ".build/alex/tool/index.html",
+        ] {
+            let page = static_dir.join(page);
+            create_dir_all(page.parent().unwrap()).unwrap();
+            write(page, "").unwrap();
+        }
+        create_dir_all(static_dir.join("sam/notes")).unwrap();
+        write(static_dir.join("sam/notes").join(SITE_STATE_FILE), "").unwrap();
+        create_dir_all(static_dir.join("sam/empty")).unwrap();
+        // The server-wide pages aren't a repo's site.
+        write(static_dir.join("index.html"), "").unwrap();
+
+        let mut sites = Vec::new();
+        find_sites(static_dir, &mut sites).unwrap();
+        sites.sort();
+        let expected: Vec<_> = ["alex/group/lib", "alex/tool", "sam/notes"]
+            .iter()
+            .map(|site| static_dir.join(site))
+            .collect();
+        assert_eq!(sites, expected);
+    }
+
+    #[test]
+    fn sites_without_repos_have_no_repo() {
+        assert_eq!(site_repo(Path::new("static/nobody/nothing")), None);
+        assert_eq!(site_repo(Path::new("elsewhere/src")), None);
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read_dir, remove_file, File},
+    io::copy,
+    path::Path,
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tera::{Context, Tera};
+
+use crate::git::Repo;
+
+use super::{
+    build::{write_file, BuildState, Release},
+    page_context, render_markdown,
+    templates::render,
+    write_page,
+};
+
+const ARCHIVE_FORMATS: [&str; 2] = ["tar.gz", "zip"];
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+#[derive(Serialize)]
+struct Archive {
+    name: String,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct ReleaseListing {
+    name: String,
+    hash: String,
+    author: String,
+    date: i64,
+    annotated: bool,
+    message: String,
+    archives: Vec<Archive>,
+}
+
+fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// The start of a tag's archive names. Tags can have slashes, which don't belong in file names,
+/// so they're percent-encoded, along with `%` itself so `a/b` and `a%2Fb` stay apart.
+fn archive_name(project_name: &str, tag: &str) -> String {
+    let tag = tag.replace('%', "%25").replace('/', "%2F");
+    format!("{}-{}", project_name, tag)
+}
+
+/// Archives every tag into `static/<repo>/releases` and lists them on a page.
+pub struct ReleaseBuilder<'a> {
+    pub repo: &'a Repo,
+    pub static_path: &'a Path,
+    pub context: &'a Context,
+    pub theme: &'a str,
+    pub templates: &'a Tera,
+    /// Used for archive names and the directory inside them.
+    pub project_name: &'a str,
+}
+
+impl ReleaseBuilder<'_> {
+    pub async fn build(&self, build_state: &mut BuildState) -> anyhow::Result<()> {
+        let releases_path = self.static_path.join("releases");
+        create_dir_all(&releases_path)?;
+
+        let tags = self.repo.tags().await?;
+        let mut releases = BTreeMap::new();
+        let mut listings = Vec::new();
+
+        for tag in tags {
+            let archive_name = archive_name(self.project_name, &tag.name);
+            let names: Vec<String> = ARCHIVE_FORMATS
+                .iter()
+                .map(|format| format!("{}.{}", archive_name, format))
+                .collect();
+            // The folder inside them doesn't need to be told apart from other tags'.
+            let prefix = format!("{}-{}", self.project_name, tag.name.replace('/', "-"));
+
+            // Archives are only made once per tag, unless it's been moved since or they were named
+            // differently.
+            let release = match build_state.releases.get(&tag.name) {
+                Some(release)
+                    if release.commit == tag.hash
+                        && names
+                            .iter()
+                            .all(|name| release.checksums.contains_key(name)) =>
+                {
+                    release.clone()
+                }
+                _ => {
+                    let mut checksums = BTreeMap::new();
+                    for (format, name) in ARCHIVE_FORMATS.into_iter().zip(names) {
+                        let path = releases_path.join(&name);
+                        // A moved tag's old archive may be shared with the live site.
+                        if path.exists() {
+                            remove_file(&path)?;
+                        }
+                        self.repo.archive(&tag.hash, format, &prefix, &path).await?;
+                        checksums.insert(name, sha256_file(&path)?);
+                    }
+                    Release {
+                        commit: tag.hash.clone(),
+                        checksums,
+                    }
+                }
+            };
+
+            listings.push(ReleaseListing {
+                name: tag.name.clone(),
+                hash: tag.hash.clone(),
+                author: tag.author.clone(),
+                date: tag.date,
+                annotated: tag.annotated,
+                message: render_markdown(&tag.message, self.theme),
+                archives: release
+                    .checksums
+                    .iter()
+                    .map(|(name, sha256)| Archive {
+                        name: name.clone(),
+                        sha256: sha256.clone(),
+                    })
+                    .collect(),
+            });
+            releases.insert(tag.name, release);
+        }
+
+        // Clean up archives of deleted tags.
+        for entry in read_dir(&releases_path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let is_archive = ARCHIVE_FORMATS.iter().any(|f| name.ends_with(f));
+            if is_archive && !releases.values().any(|r| r.checksums.contains_key(&name)) {
+                remove_file(entry.path())?;
+            }
+        }
+
+        // In the format `sha256sum --check` reads.
+        let mut sums = String::new();
+        for release in releases.values() {
+            for (name, sha256) in &release.checksums {
+                sums.push_str(&format!("{}  {}\n", sha256, name));
+            }
+        }
+        write_file(&releases_path.join(CHECKSUMS_FILE), sums)?;
+
+        let mut context = page_context(self.context, "../");
+        context.insert("releases", &listings);
+        let result = render(self.templates, "releases.html", &context)?;
+        write_page(&releases_path, result)?;
+
+        build_state.releases = releases;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read_to_string;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::site::templates::builtin_templates;
+
+    #[test]
+    fn archive_names_keep_tags_apart() {
+        assert_eq!(archive_name("tool", "v1.0"), "tool-v1.0");
+        assert_eq!(archive_name("tool", "release/1.0"), "tool-release%2F1.0");
+        let names = ["a/b", "a-b", "a%2Fb", "a%b"].map(|tag| archive_name("tool", tag));
+        for (i, name) in names.iter().enumerate() {
+            assert!(!name.contains('/'));
+            assert!(!names[i + 1..].contains(name));
+        }
+    }
+
+    #[tokio::test]
+    async fn every_tag_gets_its_own_archives() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::create_bare(&dir.path().join("tool.git"))
+            .await
+            .unwrap();
+        let commit = repo
+            .write_commit(&[("README.md", "# Tool\n")], None, "First", "alex")
+            .await
+            .unwrap();
+        for tag in ["a/b", "a-b"] {
+            repo.update_ref(&format!("refs/tags/{}", tag), &commit)
+                .await
+                .unwrap();
+        }
+
+        let static_path = dir.path().join("static");
+        let mut context = Context::new();
+        context.insert("repo_name", "tool");
+        let mut build_state = BuildState::default();
+        ReleaseBuilder {
+            repo: &repo,
+            static_path: &static_path,
+            context: &page_context(&context, ""),
+            theme: "",
+            templates: &builtin_templates(),
+            project_name: "tool",
+        }
+        .build(&mut build_state)
+        .await
+        .unwrap();
+
+        let releases_path = static_path.join("releases");
+        let sums = read_to_string(releases_path.join(CHECKSUMS_FILE)).unwrap();
+        assert_eq!(sums.lines().count(), 4);
+        for name in [
+            "tool-a%2Fb.tar.gz",
+            "tool-a%2Fb.zip",
+            "tool-a-b.tar.gz",
+            "tool-a-b.zip",
+        ] {
+            assert!(releases_path.join(name).is_file());
+            assert!(sums.contains(name));
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+use super::{build::write_file, markdown::Rendered};
+
+/// Sits next to each checkout's main page, for the search box to fetch.
+pub const SEARCH_INDEX_FILE: &str = "search.json";
+
+/// Pages longer than this only have their beginning searched, to keep the index small.
+const MAX_TEXT_LENGTH: usize = 20_000;
+
+/// A README or docs page, as the search box sees it.
+#[derive(Serialize)]
+pub struct SearchEntry {
+    title: String,
+    /// Relative to the checkout's main page.
+    url: String,
+    headings: Vec<SearchHeading>,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct SearchHeading {
+    text: String,
+    /// Empty on pages without heading anchors.
+    id: String,
+}
+
+impl SearchEntry {
+    pub fn new(title: &str, url: &str, rendered: &Rendered, has_anchors: bool) -> Self {
+        Self {
+            title: title.to_string(),
+            url: url.to_string(),
+            headings: rendered
+                .headings
+                .iter()
+                .map(|heading| SearchHeading {
+                    text: heading.text.clone(),
+                    id: match has_anchors {
+                        true => heading.id.clone(),
+                        false => String::new(),
+                    },
+                })
+                .collect(),
+            text: rendered.text.chars().take(MAX_TEXT_LENGTH).collect(),
+        }
+    }
+}
+
+/// Writes the pages of a checkout as minified JSON, which the default template searches locally.
+pub fn write_search_index(static_path: &Path, entries: &[SearchEntry]) -> anyhow::Result<()> {
+    let json = tera::to_value(entries)?.to_string();
+    write_file(&static_path.join(SEARCH_INDEX_FILE), json)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read_to_string;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        config::repo::MarkdownConfig,
+        site::{
+            highlight::DEFAULT_THEME,
+            markdown::{markdown_options, render_document},
+        },
+    };
+
+    fn rendered(text: &str) -> Rendered {
+        let options = markdown_options(&MarkdownConfig::default());
+        render_document(text, &options, DEFAULT_THEME, None)
+    }
+
+    #[test]
+    fn entries_have_headings_and_text() {
+        let page = rendered("# Setup\n\nInstall   it.\n\n## Running it\n\n```\nrun --now\n```\n");
+        let entry = tera::to_value(SearchEntry::new("Setup", "docs/setup/", &page, true)).unwrap();
+        assert_eq!(entry["title"], "Setup");
+        assert_eq!(entry["url"], "docs/setup/");
+        assert_eq!(entry["headings"][1]["text"], "Running it");
+        assert_eq!(entry["headings"][1]["id"], "running-it");
+        let text = entry["text"].as_str().unwrap();
+        assert!(text.contains("Install it."));
+        assert!(text.contains("run --now"));
+
+        let entry = tera::to_value(SearchEntry::new("Setup", "", &page, false)).unwrap();
+        assert_eq!(entry["headings"][1]["id"], "");
+    }
+
+    #[test]
+    fn long_pages_are_cut_short() {
+        let page = rendered(&"word ".repeat(MAX_TEXT_LENGTH));
+        let entry = SearchEntry::new("Long", "", &page, false);
+        assert_eq!(entry.text.chars().count(), MAX_TEXT_LENGTH);
+
+        let dir = tempdir().unwrap();
+        write_search_index(dir.path(), &[entry]).unwrap();
+        let json = read_to_string(dir.path().join(SEARCH_INDEX_FILE)).unwrap();
+        assert!(json.starts_with("[{"));
+        assert!(!json.contains('\n'));
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    fs::{read_dir, write},
+    path::Path,
+};
+
+use percent_encoding::utf8_percent_encode;
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use super::markdown::PATH;
+
+#[derive(Serialize)]
+pub struct SitemapPage {
+    pub url: String,
+    pub updated: Option<i64>,
+}
+
+/// Collects the docs pages under a directory, as URLs relative to the repo's main page.
+fn find_docs_pages(static_path: &Path, dir: &Path, pages: &mut Vec<String>) -> anyhow::Result<()> {
+    let full_path = static_path.join(dir);
+    if full_path.join("index.html").exists() {
+        let url = utf8_percent_encode(&dir.to_string_lossy(), PATH).to_string();
+        pages.push(format!("{}/", url));
+    }
+    for entry in read_dir(full_path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            find_docs_pages(static_path, &dir.join(entry.file_name()), pages)?;
+        }
+    }
+    Ok(())
+}
+
+/// The pages of a repo's site worth listing: the main page, docs, history and releases. The file
+/// browser is left to crawlers.
+pub fn repo_pages(static_path: &Path) -> anyhow::Result<Vec<String>> {
+    let mut pages = match static_path.join("index.html").exists() {
+        true => vec![String::new()],
+        false => vec!["tree/".to_string()],
+    };
+    if static_path.join("docs").is_dir() {
+        find_docs_pages(static_path, Path::new("docs"), &mut pages)?;
+    }
+    pages.extend(["log/".to_string(), "releases/".to_string()]);
+    Ok(pages)
+}
+
+/// The path part of a URL, without a trailing slash, like `/git` for `https://example.com/git`.
+fn url_path(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    match without_scheme.find('/') {
+        Some(i) => without_scheme[i..].trim_end_matches('/'),
+        None => "",
+    }
+}
+
+/// Writes `static/sitemap.xml` with the given pages, and a `static/robots.txt` that points to it and
+/// keeps crawlers out of the sites of repos that aren't indexable.
+pub fn write_sitemap(
+    site_url: &str,
+    pages: &[SitemapPage],
+    hidden: &[String],
+) -> anyhow::Result<()> {
+    let mut context = Context::new();
+    context.insert("pages", pages);
+    let sitemap = Tera::one_off(include_str!("sitemap.xml"), &context, true)?;
+    write(Path::new("static").join("sitemap.xml"), sitemap)?;
+
+    let mut robots = String::from("User-agent: *\n");
+    for site_path in hidden {
+        robots.push_str(&format!(
+            "Disallow: {}/{}/\n",
+            url_path(site_url),
+            utf8_percent_encode(site_path, PATH)
+        ));
+    }
+    if hidden.is_empty() {
+        robots.push_str("Disallow:\n");
+    }
+    robots.push_str(&format!("\nSitemap: {}/sitemap.xml\n", site_url));
+    write(Path::new("static").join("robots.txt"), robots)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::create_dir_all;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn url_paths_have_no_trailing_slash() {
+        assert_eq!(url_path("https://example.com"), "");
+        assert_eq!(url_path("https://example.com/"), "");
+        assert_eq!(url_path("https://example.com/git/"), "/git");
+        assert_eq!(url_path("http://example.com:8080/a/b"), "/a/b");
+    }
+
+    #[test]
+    fn repo_pages_list_docs_but_not_files() {
+        let dir = tempdir().unwrap();
+        let site = dir.path();
+        for page in [
+            "tree",
+            "blob/src/main.rs",
+            "docs",
+            "docs/setup guide",
+            "docs/api/v1",
+        ] {
+            create_dir_all(site.join(page)).unwrap();
+            write(site.join(page).join("index.html"), "").unwrap();
+        }
+        let mut pages = repo_pages(site).unwrap();
+        pages.sort();
+        assert_eq!(
+            pages,
+            [
+                "docs/",
+                "docs/api/v1/",
+                "docs/setup%20guide/",
+                "log/",
+                "releases/",
+                "tree/"
+            ]
+        );
+
+        // With a README, the main page comes first instead of the file browser.
+        write(site.join("index.html"), "").unwrap();
+        let pages = repo_pages(site).unwrap();
+        assert_eq!(pages[0], "");
+        assert!(!pages.contains(&"tree/".to_string()));
+    }
+}
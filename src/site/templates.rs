@@ -0,0 +1,178 @@
+use std::{
+    fs::{read_dir, read_to_string},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context as AnyhowContext};
+use tempfile::tempdir;
+use tera::{Context, Tera};
+
+use crate::{config::repo::RepoConfig, git::Repo, vars::*};
+
+use super::checkout_path;
+
+/// The built-in page templates, which config.git's `templates/` can replace.
+pub(super) const BUILTIN: [(&str, &str); 9] = [
+    ("default.html", include_str!("default.html")),
+    ("tree.html", include_str!("tree.html")),
+    ("blob.html", include_str!("blob.html")),
+    ("log.html", include_str!("log.html")),
+    ("commit.html", include_str!("commit.html")),
+    ("releases.html", include_str!("releases.html")),
+    ("issues.html", include_str!("issues.html")),
+    ("issue.html", include_str!("issue.html")),
+    ("index.html", include_str!("index.html")),
+];
+
+/// Repos' own templates are kept apart, so they can still extend the shared ones they replace.
+const REPO_PREFIX: &str = "repo/";
+
+fn read_templates(
+    root: &Path,
+    dir: &Path,
+    templates: &mut Vec<(String, String)>,
+) -> anyhow::Result<()> {
+    for entry in read_dir(root.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            read_templates(root, &path, templates)?;
+        } else {
+            let text = read_to_string(root.join(&path))
+                .with_context(|| format!("Couldn't read template {}", path.display()))?;
+            templates.push((path.to_string_lossy().to_string(), text));
+        }
+    }
+    Ok(())
+}
+
+/// Just the built-in templates, for when config.git's can't be loaded.
+pub fn builtin_templates() -> Tera {
+    let mut tera = Tera::default();
+    // Everything renders to HTML, whatever the template is called.
+    tera.autoescape_on(vec![""]);
+    tera.add_raw_templates(BUILTIN)
+        .expect("The built-in templates should parse");
+    tera
+}
+
+/// Loads the server's template library: the built-ins, then anything in config.git's `templates/`.
+pub async fn load_templates() -> anyhow::Result<Tera> {
+    let mut tera = builtin_templates();
+
+    let temp_dir = tempdir()?;
+    let clone_dir = temp_dir.path().join(SERVER_CONFIG_REPO);
+    Repo::clone(Path::new(SERVER_CONFIG_REPO), &clone_dir).await?;
+
+    let templates_dir = clone_dir.join(TEMPLATES_DIR);
+    if templates_dir.is_dir() {
+        let mut templates = Vec::new();
+        read_templates(&templates_dir, Path::new(""), &mut templates)?;
+        tera.add_raw_templates(templates)
+            .context("Couldn't load the shared templates")?;
+    }
+
+    Ok(tera)
+}
+
+/// Adds a repo's own templates on top of the server's library.
+pub fn repo_templates(
+    library: &Tera,
+    clone_dir: &Path,
+    config: &RepoConfig,
+) -> anyhow::Result<Tera> {
+    let overrides = [
+        ("default.html", &config.web_template),
+        ("tree.html", &config.tree_template),
+        ("blob.html", &config.blob_template),
+        ("log.html", &config.log_template),
+        ("commit.html", &config.commit_template),
+        ("releases.html", &config.releases_template),
+    ];
+
+    let mut templates = Vec::new();
+    for (name, path) in overrides {
+        if let Some(path) = path {
+            let path = checkout_path(clone_dir, path)
+                .ok_or_else(|| anyhow!("Invalid template path {}", path))?;
+            let text =
+                read_to_string(clone_dir.join(path)).context("Couldn't read user template")?;
+            templates.push((format!("{}{}", REPO_PREFIX, name), text));
+        }
+    }
+
+    let mut tera = library.clone();
+    tera.add_raw_templates(templates)?;
+    Ok(tera)
+}
+
+/// Renders one of the built-in pages, preferring the repo's own version of it.
+pub fn render(tera: &Tera, name: &str, context: &Context) -> anyhow::Result<String> {
+    let repo_name = format!("{}{}", REPO_PREFIX, name);
+    let name = match tera.get_template_names().any(|n| n == repo_name) {
+        true => &repo_name,
+        false => name,
+    };
+    Ok(tera.render(name, context)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use super::*;
+
+    fn repo_config(text: &str) -> RepoConfig {
+        toml::from_str(&format!(
+            "name = \"tool\"\npublic = true\nmembers = []\n{}",
+            text
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn repo_templates_replace_their_page_only() {
+        let dir = tempdir().unwrap();
+        let mut library = Tera::default();
+        library
+            .add_raw_templates([
+                ("log.html", "log {% block body %}shared{% endblock %}"),
+                ("commit.html", "commit"),
+            ])
+            .unwrap();
+        // A repo's version can extend the shared one it replaces.
+        write(
+            dir.path().join("log.html"),
+            "{% extends \"log.html\" %}{% block body %}{{name}}'s own{% endblock %}",
+        )
+        .unwrap();
+
+        let config = repo_config("log_template = \"log.html\"");
+        let tera = repo_templates(&library, dir.path(), &config).unwrap();
+        let mut context = Context::new();
+        context.insert("name", "tool");
+        assert_eq!(
+            render(&tera, "log.html", &context).unwrap(),
+            "log tool's own"
+        );
+        assert_eq!(render(&tera, "commit.html", &context).unwrap(), "commit");
+        assert_eq!(
+            render(&library, "log.html", &context).unwrap(),
+            "log shared"
+        );
+    }
+
+    #[test]
+    fn repo_templates_stay_inside_the_checkout() {
+        let dir = tempdir().unwrap();
+        let library = builtin_templates();
+        for path in ["/etc/passwd", "../log.html", "missing.html"] {
+            let config = repo_config(&format!("log_template = \"{}\"", path));
+            assert!(
+                repo_templates(&library, dir.path(), &config).is_err(),
+                "{}",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read, read_dir, remove_dir_all, remove_file},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use super::{
+    build::{copy_file, hash_inputs},
+    highlight::{highlight_file, MAX_HIGHLIGHT_SIZE},
+    page_context,
+    templates::render,
+    write_page,
+};
+
+#[derive(Serialize)]
+struct Crumb {
+    name: String,
+    href: String,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    /// Its page, relative to the folder's.
+    href: String,
+    is_dir: bool,
+    size: u64,
+}
+
+/// Renders a browsable copy of a checked out repo into `static/<repo>`. Folders get pages under
+/// `tree/` and files under `blob/`, with their raw copies under `raw/`, so a file can be called
+/// anything without getting in the way of another page.
+pub struct TreeBuilder<'a> {
+    pub clone_dir: &'a Path,
+    pub static_path: &'a Path,
+    pub context: &'a Context,
+    pub theme: &'a str,
+    pub templates: &'a Tera,
+    /// What each page shows, from [`Repo::tree_objects`](crate::git::Repo::tree_objects).
+    pub objects: &'a BTreeMap<PathBuf, String>,
+    /// The hash of everything else that goes into a page.
+    pub inputs: &'a str,
+    /// The page keys from the last build.
+    pub previous: &'a BTreeMap<String, String>,
+}
+
+impl TreeBuilder<'_> {
+    /// Only redoes pages whose file or folder has changed, returning the keys of every page.
+    pub fn build(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut pages = BTreeMap::new();
+        self.build_dir(Path::new(""), &mut pages)?;
+
+        // Pages for files and folders that are gone. A removed folder takes its pages with it.
+        for path in self.previous.keys() {
+            if !pages.contains_key(path) {
+                self.remove_dir_pages(Path::new(path))?;
+                self.remove_blob_pages(Path::new(path))?;
+            }
+        }
+
+        Ok(pages)
+    }
+
+    /// Whether a page needs rendering, recording its key either way.
+    fn is_stale(&self, path: &Path, page: &Path, pages: &mut BTreeMap<String, String>) -> bool {
+        let Some(object) = self.objects.get(path) else {
+            return true;
+        };
+        let key = hash_inputs([self.inputs, object]);
+        let path = path.to_string_lossy().to_string();
+        let is_stale = self.previous.get(&path) != Some(&key) || !page.join("index.html").exists();
+        pages.insert(path, key);
+        is_stale
+    }
+
+    fn build_dir(&self, path: &Path, pages: &mut BTreeMap<String, String>) -> anyhow::Result<()> {
+        // In case this used to be a file. Its folder's pages go where its own did.
+        if self.raw_path(path).is_file() {
+            self.remove_blob_pages(path)?;
+        }
+
+        let mut entries = Vec::new();
+        for entry in read_dir(self.clone_dir.join(path))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if path.as_os_str().is_empty() && name == ".git" {
+                continue;
+            }
+
+            let file_type = entry.file_type()?;
+            let size = entry.metadata()?.len();
+            let entry_path = path.join(&name);
+            let href = if file_type.is_dir() {
+                self.build_dir(&entry_path, pages)?;
+                format!("{}/", name)
+            } else if file_type.is_file() {
+                if self.is_stale(&entry_path, &self.blob_dir(&entry_path), pages) {
+                    self.build_blob(&entry_path, size)?;
+                }
+                format!("{}blob/{}/", self.root(path), entry_path.to_string_lossy())
+            } else {
+                continue;
+            };
+
+            entries.push(Entry {
+                name,
+                href,
+                is_dir: file_type.is_dir(),
+                size,
+            });
+        }
+
+        if !self.is_stale(path, &self.tree_dir(path), pages) {
+            return Ok(());
+        }
+
+        // Directories first, then files, both alphabetically.
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then(a.name.cmp(&b.name)));
+
+        let mut context = self.page_context(path);
+        context.insert("entries", &entries);
+        let result = render(self.templates, "tree.html", &context)?;
+        write_page(&self.tree_dir(path), result)
+    }
+
+    fn build_blob(&self, path: &Path, size: u64) -> anyhow::Result<()> {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let data = read(self.clone_dir.join(path))?;
+
+        // Git's own heuristic: a NUL byte early on means binary.
+        let text = match data.iter().take(8000).any(|b| *b == 0) {
+            true => None,
+            false => String::from_utf8(data).ok(),
+        };
+        let is_binary = text.is_none();
+        let content = match text {
+            Some(text) if size <= MAX_HIGHLIGHT_SIZE => {
+                Some(highlight_file(path, &text, self.theme)?)
+            }
+            _ => None,
+        };
+
+        let mut context = self.page_context(path);
+        context.insert("name", &name);
+        context.insert("size", &size);
+        context.insert("is_binary", &is_binary);
+        context.insert("content", &content);
+        context.insert(
+            "raw_url",
+            &format!("{}raw/{}", self.root(path), path.to_string_lossy()),
+        );
+        let result = render(self.templates, "blob.html", &context)?;
+
+        // In case this used to be a folder.
+        self.remove_dir_pages(path)?;
+        self.remove_blob_pages(path)?;
+
+        write_page(&self.blob_dir(path), result)?;
+        let raw_path = self.raw_path(path);
+        create_dir_all(raw_path.parent().unwrap())?;
+        copy_file(&self.clone_dir.join(path), &raw_path)?;
+
+        Ok(())
+    }
+
+    /// Every page gets its own directory, so both files and folders have pretty URLs.
+    fn tree_dir(&self, path: &Path) -> PathBuf {
+        self.static_path.join("tree").join(path)
+    }
+
+    fn blob_dir(&self, path: &Path) -> PathBuf {
+        self.static_path.join("blob").join(path)
+    }
+
+    fn raw_path(&self, path: &Path) -> PathBuf {
+        self.static_path.join("raw").join(path)
+    }
+
+    /// Removes a folder's pages, and those of everything in it.
+    fn remove_dir_pages(&self, path: &Path) -> anyhow::Result<()> {
+        let tree_dir = self.tree_dir(path);
+        if tree_dir.is_dir() {
+            remove_dir_all(tree_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a file's page and raw copy, or a folder's worth of them.
+    fn remove_blob_pages(&self, path: &Path) -> anyhow::Result<()> {
+        let blob_dir = self.blob_dir(path);
+        if blob_dir.is_dir() {
+            remove_dir_all(blob_dir)?;
+        }
+        let raw_path = self.raw_path(path);
+        if raw_path.is_dir() {
+            remove_dir_all(raw_path)?;
+        } else if raw_path.is_file() {
+            remove_file(raw_path)?;
+        }
+        Ok(())
+    }
+
+    /// Pages live at `tree/<path>/index.html` or `blob/<path>/index.html`, so this climbs back to
+    /// the repo's root.
+    fn root(&self, path: &Path) -> String {
+        "../".repeat(path.components().count() + 1)
+    }
+
+    fn page_context(&self, path: &Path) -> Context {
+        let root = self.root(path);
+        let mut crumbs = vec![Crumb {
+            name: "tree".to_string(),
+            href: format!("{}tree/", root),
+        }];
+        let mut prefix = PathBuf::new();
+        for component in path.components() {
+            prefix.push(component);
+            crumbs.push(Crumb {
+                name: component.as_os_str().to_string_lossy().to_string(),
+                href: format!("{}tree/{}/", root, prefix.to_string_lossy()),
+            });
+        }
+
+        let mut context = page_context(self.context, &root);
+        context.insert("path", &path.to_string_lossy());
+        context.insert("breadcrumbs", &crumbs);
+        context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_to_string, remove_file, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::site::{highlight::resolve_theme, templates::builtin_templates};
+
+    fn build(
+        clone_dir: &Path,
+        static_path: &Path,
+        previous: &BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        // Every file and folder's object changes with each build here, as if it had been pushed.
+        let mut objects = BTreeMap::new();
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            for entry in read_dir(clone_dir.join(&dir)).unwrap() {
+                let path = dir.join(entry.unwrap().file_name());
+                if clone_dir.join(&path).is_dir() {
+                    dirs.push(path.clone());
+                }
+                objects.insert(path, previous.len().to_string());
+            }
+            objects.insert(dir, previous.len().to_string());
+        }
+
+        let mut context = Context::new();
+        context.insert("repo_name", "demo");
+        TreeBuilder {
+            clone_dir,
+            static_path,
+            context: &page_context(&context, ""),
+            theme: &resolve_theme(None, None),
+            templates: &builtin_templates(),
+            objects: &objects,
+            inputs: "",
+            previous,
+        }
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    fn files_can_be_called_anything() {
+        let dir = tempdir().unwrap();
+        let clone_dir = dir.path().join("clone");
+        let static_path = dir.path().join("static");
+        create_dir_all(clone_dir.join("web").join("raw")).unwrap();
+        write(clone_dir.join("index.html"), "<p>Home</p>").unwrap();
+        write(clone_dir.join("raw"), "raw").unwrap();
+        write(clone_dir.join("web").join("index.html"), "<p>Web</p>").unwrap();
+        write(clone_dir.join("web").join("raw").join("a.txt"), "a").unwrap();
+
+        let pages = build(&clone_dir, &static_path, &BTreeMap::new());
+        // Tera escapes slashes.
+        let read_page = |path: PathBuf| read_to_string(path).unwrap().replace("&#x2F;", "/");
+        let listing = read_page(static_path.join("tree").join("index.html"));
+        assert!(listing.contains(r#"href="../blob/index.html/""#));
+        assert!(listing.contains(r#"href="web/""#));
+        let page = static_path
+            .join("blob")
+            .join("index.html")
+            .join("index.html");
+        assert!(read_page(page).contains(r#"href="../../raw/index.html""#));
+        assert_eq!(
+            read_to_string(static_path.join("raw").join("raw")).unwrap(),
+            "raw"
+        );
+        let web = static_path.join("tree").join("web");
+        assert!(web.join("index.html").is_file());
+        assert!(web.join("raw").join("index.html").is_file());
+        let raw = static_path.join("raw").join("web");
+        assert!(raw.join("index.html").is_file());
+        assert!(raw.join("raw").join("a.txt").is_file());
+
+        // Files and folders can swap places, or go.
+        remove_file(clone_dir.join("web").join("raw").join("a.txt")).unwrap();
+        remove_dir_all(clone_dir.join("web").join("raw")).unwrap();
+        write(clone_dir.join("web").join("raw"), "now a file").unwrap();
+        remove_file(clone_dir.join("raw")).unwrap();
+        create_dir_all(clone_dir.join("raw")).unwrap();
+        write(clone_dir.join("raw").join("b.txt"), "b").unwrap();
+        remove_file(clone_dir.join("index.html")).unwrap();
+
+        build(&clone_dir, &static_path, &pages);
+        assert!(!web.join("raw").exists());
+        assert!(static_path
+            .join("blob")
+            .join("web")
+            .join("raw")
+            .join("index.html")
+            .is_file());
+        assert!(raw.join("raw").is_file());
+        assert!(static_path
+            .join("tree")
+            .join("raw")
+            .join("index.html")
+            .is_file());
+        assert!(static_path.join("raw").join("raw").join("b.txt").is_file());
+        assert!(!static_path
+            .join("blob")
+            .join("raw")
+            .join("index.html")
+            .exists());
+        assert!(!static_path.join("blob").join("index.html").exists());
+        assert!(!static_path.join("raw").join("index.html").exists());
+    }
+}
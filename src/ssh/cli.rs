@@ -0,0 +1,914 @@
+use std::{
+    cmp::Reverse,
+    fs::read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::anyhow;
+use log::error;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::repo::{committed_repo_config, new_repo_config, repin_repo_config, RepoConfig},
+    git::{find_repos, Repo},
+    issues,
+    merge_requests::{MergeRequests, Status},
+    mirror::{
+        now, stop_mirror, update_mirror, MirrorStatus, PushAttempt, DEFAULT_INTERVAL_MINUTES,
+    },
+    patches::{intake, PatchQueue},
+    replication::{delete_repo, pin_config, replicate, unpin_config},
+    search::{search, update_index},
+    site::{force_rebuild_site, rebuild_index, rebuild_site, reconcile_sites},
+    state::State,
+    vars::*,
+};
+
+use super::{
+    commands::{parse_repo_path, push_to_mirrors, Knob},
+    Handler,
+};
+
+const USAGE: &str = "Usage:
+  repo info <repo>
+  repo fork <repo> [<name>]
+  repo delete <repo>
+  mr list|show|merge|close <repo> [<id>]
+  issue new <repo> <title>
+  issue list <repo>
+  issue show <repo> <id>
+  issue comment <repo> <id> <text>
+  issue close <repo> <id> [<text>]
+  patch list <repo>
+  patch show <repo> <id>
+  patch apply <repo> <id> <branch>
+  repo sync <repo>
+  mirror config <repo> public|private
+  mirror config <repo> members <user>...
+  mirror config <repo> interval <minutes>
+  mirror config <repo> url <url>
+  mirror stop <repo>
+  search <query> [--repo <repo>]
+  site rebuild <repo>|--all";
+
+/// Searches stop after this many matching lines.
+const MAX_SEARCH_HITS: usize = 100;
+
+impl Handler {
+    /// Runs the server's own commands, like `ssh <server> site rebuild --all`.
+    pub async fn handle_cli(&mut self, knob: Knob, args: Vec<String>) -> anyhow::Result<()> {
+        let user = self.user.clone().unwrap_or_default();
+        let username = self.username.clone().unwrap_or(GUEST_USERNAME.to_string());
+        let is_admin = user.is_admin.unwrap_or(false);
+        let can_create_repos = user.can_create_repos.unwrap_or(false);
+        let is_replicator = self.state.lock().await.is_replicator(&username);
+        let handler = CliHandler {
+            knob: knob.clone(),
+            state: self.state.clone(),
+            username,
+            is_admin,
+            can_create_repos,
+            is_replicator,
+        };
+
+        // Commands can take a while, and the session has to keep going meanwhile.
+        let fut = async move {
+            let status = match handler.run(&args).await {
+                Ok(()) => 0,
+                Err(e) => {
+                    knob.error(&format!("{:#}", e)).await?;
+                    1
+                }
+            };
+            knob.exit_status(status).await?;
+            knob.eof().await?;
+            knob.close().await?;
+            Ok::<(), anyhow::Error>(())
+        };
+        tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                error!("{:#}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// A rough age, like `5 minutes ago`.
+fn ago(seconds: u64) -> String {
+    let (amount, unit) = match seconds {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{} {}{} ago", amount, unit, plural)
+}
+
+struct CliHandler {
+    knob: Knob,
+    state: Arc<Mutex<State>>,
+    username: String,
+    is_admin: bool,
+    can_create_repos: bool,
+    is_replicator: bool,
+}
+
+impl CliHandler {
+    async fn run(&self, args: &[String]) -> anyhow::Result<()> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match args.as_slice() {
+            ["site", "rebuild", target] => {
+                self.require_admin()?;
+                self.rebuild_sites(target).await
+            }
+            ["repo", "info", repo] => self.repo_info(repo).await,
+            ["repo", "sync", repo] => self.repo_sync(repo).await,
+            ["repo", "fork", repo] => self.repo_fork(repo, None).await,
+            ["repo", "fork", repo, name] => self.repo_fork(repo, Some(name)).await,
+            ["repo", "delete", repo] => {
+                self.require_admin()?;
+                self.repo_delete(repo).await
+            }
+            ["mirror", "config", repo, setting @ ..] => self.mirror_config(repo, setting).await,
+            ["mirror", "stop", repo] => self.mirror_stop(repo).await,
+            ["search", query @ ..] => self.search(query).await,
+            ["mr", "list", repo] => self.mr_list(repo).await,
+            ["mr", "show", repo, id] => self.mr_show(repo, id).await,
+            ["mr", "merge", repo, id] => self.mr_merge(repo, id).await,
+            ["mr", "close", repo, id] => self.mr_close(repo, id).await,
+            // Text can be left unquoted, as it's put back together.
+            ["issue", "new", repo, title @ ..] if !title.is_empty() => {
+                self.issue_new(repo, &title.join(" ")).await
+            }
+            ["issue", "list", repo] => self.issue_list(repo).await,
+            ["issue", "show", repo, id] => self.issue_show(repo, id).await,
+            ["issue", "comment", repo, id, text @ ..] if !text.is_empty() => {
+                self.issue_comment(repo, id, &text.join(" ")).await
+            }
+            ["issue", "close", repo, id, text @ ..] => {
+                self.issue_close(repo, id, &text.join(" ")).await
+            }
+            ["patch", "list", repo] => self.patch_list(repo).await,
+            ["patch", "show", repo, id] => self.patch_show(repo, id).await,
+            ["patch", "apply", repo, id, branch] => self.patch_apply(repo, id, branch).await,
+            // Only for a primary keeping this replica in sync, so not in the usage.
+            ["replication", args @ ..] => self.replication(args).await,
+            _ => Err(anyhow!(USAGE)),
+        }
+    }
+
+    fn require_admin(&self) -> anyhow::Result<()> {
+        match self.is_admin {
+            true => Ok(()),
+            false => Err(anyhow!("Only admins are allowed to do that.")),
+        }
+    }
+
+    /// Turns away changes on a replica, which only takes them from its primary.
+    async fn require_primary(&self) -> anyhow::Result<()> {
+        match &self.state.lock().await.replica {
+            Some(replica) => Err(anyhow!(
+                "This server is a read-only replica, use {} instead.",
+                replica.primary
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Looks up a repo the primary sent. Unlike users, it can make new ones.
+    fn replicated_repo(&self, repo: &str) -> anyhow::Result<PathBuf> {
+        parse_repo_path(repo)
+            .filter(|path| path != Path::new(SERVER_CONFIG_REPO))
+            .ok_or_else(|| anyhow!("Invalid repository {}", repo))
+    }
+
+    /// Looks up a repo the user can read. Ones they can't don't exist, as far as they know.
+    async fn readable_repo(&self, repo: &str) -> anyhow::Result<(PathBuf, RepoConfig)> {
+        let not_found = || anyhow!("That repository doesn't exist :(");
+        let repo_path = parse_repo_path(repo)
+            .filter(|path| path.exists() && path != Path::new(SERVER_CONFIG_REPO))
+            .ok_or_else(not_found)?;
+        match committed_repo_config(&repo_path).await? {
+            Some(config) if config.can_read(&self.username, self.is_admin) => {
+                Ok((repo_path, config))
+            }
+            _ => Err(not_found()),
+        }
+    }
+
+    async fn print(&self, lines: &[String]) -> anyhow::Result<()> {
+        let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        self.knob.data(text.as_bytes()).await
+    }
+
+    async fn repo_info(&self, repo: &str) -> anyhow::Result<()> {
+        let (repo_path, config) = self.readable_repo(repo).await?;
+        let repo = Repo::open(&repo_path);
+
+        let mut lines = vec![format!("name: {}", config.name)];
+        if let Some(description) = &config.description {
+            lines.push(format!("description: {}", description));
+        }
+        lines.push(format!(
+            "visibility: {}",
+            if config.public { "public" } else { "private" }
+        ));
+        lines.push(format!("members: {}", config.members.join(", ")));
+        if let Some(commit) = repo.latest_commit().await? {
+            lines.push(format!(
+                "default branch: {} ({} {})",
+                repo.head_branch().await?,
+                commit.short_hash,
+                commit.summary
+            ));
+        }
+
+        let when = |time: Option<u64>| match time {
+            Some(time) => ago(now().saturating_sub(time)),
+            None => "never".to_string(),
+        };
+        if let Some(source) = &config.forked_from {
+            lines.push(format!("forked from: {}", source));
+        }
+        if let Some(mirror) = &config.mirror {
+            let status = MirrorStatus::load(&repo_path)?;
+            lines.push(format!("mirror of: {}", mirror.url));
+            let interval = mirror.interval_minutes.unwrap_or(DEFAULT_INTERVAL_MINUTES);
+            lines.push(format!(
+                "sync interval: {} minute{}",
+                interval,
+                if interval == 1 { "" } else { "s" }
+            ));
+            lines.push(format!("last sync: {}", when(status.last_attempt)));
+            lines.push(format!(
+                "last successful sync: {}",
+                when(status.last_success)
+            ));
+            if let Some(error) = &status.error {
+                lines.push(format!("sync error: {}", error));
+            }
+        }
+
+        let log = PushAttempt::load_log(&repo_path)?;
+        for mirror in &config.push_mirrors {
+            lines.push(format!("pushes to: {}", mirror.url));
+            match log.iter().rev().find(|attempt| attempt.url == mirror.url) {
+                Some(attempt) => lines.push(format!(
+                    "  last push: {}, {}",
+                    when(Some(attempt.time)),
+                    attempt.error.as_deref().unwrap_or("ok")
+                )),
+                None => lines.push("  last push: never".to_string()),
+            }
+        }
+        self.print(&lines).await
+    }
+
+    /// Syncs a mirror now, rather than waiting for its turn.
+    async fn repo_sync(&self, repo: &str) -> anyhow::Result<()> {
+        let (repo_path, config) = self.readable_repo(repo).await?;
+        if !config.can_push(&self.username, self.is_admin) {
+            return Err(anyhow!("Only members can sync a mirror."));
+        }
+        self.require_primary().await?;
+        let mirror = config
+            .mirror
+            .ok_or_else(|| anyhow!("That repository isn't a mirror."))?;
+
+        match update_mirror(&self.state, &repo_path, &mirror).await? {
+            true => self.knob.info("Synced, with new changes.").await,
+            false => self.knob.info("Synced, already up to date.").await,
+        }
+    }
+
+    /// Looks up a mirror the user can change.
+    async fn member_mirror(&self, repo: &str) -> anyhow::Result<(PathBuf, RepoConfig)> {
+        self.require_primary().await?;
+        let (repo_path, config) = self.readable_repo(repo).await?;
+        if !config.can_push(&self.username, self.is_admin) {
+            return Err(anyhow!("Only members can change a mirror."));
+        }
+        if config.mirror.is_none() {
+            return Err(anyhow!("That repository isn't a mirror."));
+        }
+        Ok((repo_path, config))
+    }
+
+    /// Changes a mirror's settings, as its own branches are upstream's and can't be pushed to.
+    async fn mirror_config(&self, repo: &str, setting: &[&str]) -> anyhow::Result<()> {
+        let (repo_path, mut config) = self.member_mirror(repo).await?;
+        let mut mirror = config
+            .mirror
+            .take()
+            .ok_or_else(|| anyhow!("That repository isn't a mirror."))?;
+        match setting {
+            ["public"] => config.public = true,
+            ["private"] => config.public = false,
+            ["members", members @ ..] if !members.is_empty() => {
+                config.members = members.iter().map(|member| member.to_string()).collect()
+            }
+            ["interval", minutes] => {
+                let minutes = minutes
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid interval {}", minutes))?;
+                mirror.interval_minutes = Some(minutes);
+            }
+            ["url", url] => mirror.url = url.to_string(),
+            _ => return Err(anyhow!(USAGE)),
+        }
+        config.mirror = Some(mirror);
+        repin_repo_config(&repo_path, &config)?;
+        self.knob.info("Updated the mirror's config.").await?;
+
+        // No ref moved, so the site wouldn't otherwise notice.
+        force_rebuild_site(&self.state, &repo_path).await?;
+        rebuild_index(&self.state).await?;
+        replicate(&self.state, &repo_path).await;
+        Ok(())
+    }
+
+    /// Stops mirroring, leaving a normal repo with everything fetched so far.
+    async fn mirror_stop(&self, repo: &str) -> anyhow::Result<()> {
+        let (repo_path, config) = self.member_mirror(repo).await?;
+        stop_mirror(&repo_path, config).await?;
+        self.knob
+            .info("Stopped mirroring, the config is back on the default branch - please pull.")
+            .await?;
+
+        update_index(&repo_path).await?;
+        rebuild_site(&self.state, &repo_path).await?;
+        replicate(&self.state, &repo_path).await;
+        Ok(())
+    }
+
+    async fn replication(&self, args: &[&str]) -> anyhow::Result<()> {
+        if !self.is_replicator {
+            return Err(anyhow!("Only the primary is allowed to do that."));
+        }
+        match args {
+            ["list"] => {
+                let repos = find_repos(Path::new("."))?;
+                self.print(
+                    &repos
+                        .iter()
+                        .map(|r| r.display().to_string())
+                        .collect::<Vec<_>>(),
+                )
+                .await
+            }
+            ["delete", repo] => {
+                let repo_path = self.replicated_repo(repo)?;
+                if !repo_path.exists() {
+                    return Err(anyhow!("That repository doesn't exist :("));
+                }
+                delete_repo(&self.state, &repo_path).await
+            }
+            ["pin", repo, hex] => {
+                let repo_path = self.replicated_repo(repo)?;
+                if pin_config(&repo_path, hex).await? {
+                    force_rebuild_site(&self.state, &repo_path).await?;
+                    rebuild_index(&self.state).await?;
+                }
+                Ok(())
+            }
+            ["unpin", repo] => unpin_config(&self.replicated_repo(repo)?),
+            _ => Err(anyhow!("Unknown replication command.")),
+        }
+    }
+
+    /// Forks a repo into the user's own directory, as a private repo of theirs.
+    async fn repo_fork(&self, repo: &str, name: Option<&str>) -> anyhow::Result<()> {
+        if !self.is_admin && !self.can_create_repos {
+            return Err(anyhow!("You aren't allowed to create repositories."));
+        }
+        self.require_primary().await?;
+        let (source, _) = self.readable_repo(repo).await?;
+        if Repo::open(&source).latest_commit().await?.is_none() {
+            return Err(anyhow!(
+                "That repository is empty, there's nothing to fork."
+            ));
+        }
+
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => source.file_name().unwrap().to_string_lossy().to_string(),
+        };
+        let fork = parse_repo_path(&format!("{}/{}", self.username, name))
+            .filter(|path| path.starts_with(&self.username) && path.components().count() > 1)
+            .ok_or_else(|| anyhow!("Invalid repository name {}", name))?;
+        if fork.exists() {
+            return Err(anyhow!("{} already exists.", fork.display()));
+        }
+
+        Repo::fork(&source, &fork).await?;
+        new_repo_config(&fork, &self.username, Some(&source)).await?;
+        update_index(&fork).await?;
+        rebuild_site(&self.state, &fork).await?;
+        replicate(&self.state, &fork).await;
+
+        let clone_url = self.state.lock().await.server_config.clone_url(&fork);
+        self.knob
+            .info(&format!("Forked {} to {}", source.display(), clone_url))
+            .await
+    }
+
+    /// Deletes a repo for good. Replicas delete it at their next sync.
+    async fn repo_delete(&self, repo: &str) -> anyhow::Result<()> {
+        self.require_primary().await?;
+        let (repo_path, _) = self.readable_repo(repo).await?;
+        delete_repo(&self.state, &repo_path).await?;
+        self.knob
+            .info(&format!("Deleted {}.", repo_path.display()))
+            .await
+    }
+
+    async fn mr_list(&self, repo: &str) -> anyhow::Result<()> {
+        let (repo_path, _) = self.readable_repo(repo).await?;
+        let requests = MergeRequests::load(&repo_path).await?;
+        if requests.requests.is_empty() {
+            return self.knob.info("No merge requests yet.").await;
+        }
+
+        // Open ones first, newest first.
+        let mut requests = requests.requests;
+        requests.sort_by_key(|request| (request.status != Status::Open, Reverse(request.id)));
+        let lines: Vec<String> = requests
+            .iter()
+            .map(|request| {
+                format!(
+                    "!{} [{}] {} -> {}: {} ({})",
+                    request.id,
+                    request.status.name(),
+                    request.author,
+                    request.target,
+                    request.title,
+                    ago(now().saturating_sub(request.updated))
+                )
+            })
+            .collect();
+        self.print(&lines).await
+    }
+
+    async fn mr_show(&self, repo: &str, id: &str) -> anyhow::Result<()> {
+        let (repo_path, _) = self.readable_repo(repo).await?;
+        let mut requests = MergeRequests::load(&repo_path).await?;
+        let request = requests.get_mut(id)?;
+
+        let mut lines = vec![
+            format!("!{}: {}", request.id, request.title),
+            format!("status: {}", request.status.name()),
+            format!("author: {}", request.author),
+            format!("target: {}", request.target),
+            format!("topic: {}", request.topic),
+            format!("head: {}", request.head_ref()),
+            format!("opened: {}", ago(now().saturating_sub(request.created))),
+            format!("updated: {}", ago(now().saturating_sub(request.updated))),
+        ];
+        if let Some(description) = &request.description {
+            lines.push(String::new());
+            lines.push(description.clone());
+        }
+
+        // What merging would bring in. Once merged, that's nothing.
+        let range = format!("{}..{}", request.target, request.head);
+        if let Ok(commits) = Repo::open(&repo_path).log(&[&range]).await {
+            lines.push(String::new());
+            lines.extend(
+                commits
+                    .iter()
+                    .map(|commit| format!("{} {}", commit.short_hash, commit.summary)),
+            );
+        }
+        self.print(&lines).await
+    }
+
+    /// Merges a request into its target, as if a member had pushed the merge.
+    async fn mr_merge(&self, repo: &str, id: &str) -> anyhow::Result<()> {
+        self.require_primary().await?;
+        let (repo_path, config) = self.readable_repo(repo).await?;
+        if !config.can_push(&self.username, self.is_admin) {
+            return Err(anyhow!("Only members can merge."));
+        }
+
+        let request = {
+            let repo_lock = self.state.lock().await.repo_lock(&repo_path);
+            let _repo_lock = repo_lock.lock().await;
+            let mut requests = MergeRequests::load(&repo_path).await?;
+            let request = requests.get_mut(id)?;
+            if request.status != Status::Open {
+                return Err(anyhow!(
+                    "!{} is already {}.",
+                    request.id,
+                    request.status.name()
+                ));
+            }
+            let message = format!("Merge request !{}: {}", request.id, request.title);
+            Repo::open(&repo_path)
+                .merge(&request.target, &request.head, &message, &self.username)
+                .await?;
+            request.status = Status::Merged;
+            request.updated = now();
+            let request = request.clone();
+            requests
+                .save(
+                    &repo_path,
+                    &format!("Merged !{}", request.id),
+                    &self.username,
+                )
+                .await?;
+            request
+        };
+        self.knob
+            .info(&format!("Merged !{} into {}.", request.id, request.target))
+            .await?;
+
+        update_index(&repo_path).await?;
+        let server_config = self.state.lock().await.server_config.clone();
+        push_to_mirrors(&self.knob, &repo_path, &config, &server_config).await?;
+        rebuild_site(&self.state, &repo_path).await?;
+        replicate(&self.state, &repo_path).await;
+        Ok(())
+    }
+
+    /// Closes a request without merging it, which its author can do too.
+    async fn mr_close(&self, repo: &str, id: &str) -> anyhow::Result<()> {
+        self.require_primary().await?;
+        let (repo_path, config) = self.readable_repo(repo).await?;
+
+        let repo_lock = self.state.lock().await.repo_lock(&repo_path);
+        let _repo_lock = repo_lock.lock().await;
+        let mut requests = MergeRequests::load(&repo_path).await?;
+        let request = requests.get_mut(id)?;
+        if !config.can_push(&self.username, self.is_admin) && request.author != self.username {
+            return Err(anyhow!("Only members and its author can close it."));
+        }
+        if request.status != Status::Open {
+            return Err(anyhow!(
+                "!{} is already {}.",
+                request.id,
+                request.status.name()
+            ));
+        }
+        request.status = Status::Closed;
+        request.updated = now();
+        let id = request.id;
+        requests
+            .save(&repo_path, &format!("Closed !{}", id), &self.username)
+            .await?;
+        replicate(&self.state, &repo_path).await;
+        self.knob.info(&format!("Closed !{}.", id)).await
+    }
+
+    /// Looks up a repo the user can open issues on and comment in, which is any they can read
+    /// as long as they've logged in.
+    async fn discussable_repo(&self, repo: &str) -> anyhow::Result<(PathBuf, RepoConfig)> {
+        self.require_primary().await?;
+        let found = self.readable_repo(repo).await?;
+        if self.username == GUEST_USERNAME {
+            return Err(anyhow!("Guests can't take part in issues."));
+        }
+        Ok(found)
+    }
+
+    /// Sends a changed issue everywhere the repo's refs go.
+    async fn issue_changed(&self, repo_path: &Path, config: &RepoConfig) -> anyhow::Result<()> {
+        let server_config = self.state.lock().await.server_config.clone();
+        push_to_mirrors(&self.knob, repo_path, config, &server_config).await?;
+        rebuild_site(&self.state, repo_path).await?;
+        replicate(&self.state, repo_path).await;
+        Ok(())
+    }
+
+    async fn issue_new(&self, repo: &str, title: &str) -> anyhow::Result<()> {
+        let (repo_path, config) = self.discussable_repo(repo).await?;
+        let issue = {
+            let repo_lock = self.state.lock().await.repo_lock(&repo_path);
+            let _repo_lock = repo_lock.lock().await;
+            issues::open(&Repo::open(&repo_path), title, &self.username).await?
+        };
+        self.knob
+            .info(&format!("Opened issue #{}: {}", issue.id, issue.title))
+            .await?;
+        self.issue_changed(&repo_path, &config).await
+    }
+
+    async fn issue_list(&self, repo: &str) -> anyhow::Result<()> {
+        let (repo_path, _) = self.readable_repo(repo).await?;
+        let mut issues = issues::list(&Repo::open(&repo_path)).await?;
+        if issues.is_empty() {
+            return self.knob.info("No issues yet.").await;
+        }
+
+        // Open ones first, newest first.
+        issues.sort_by_key(|issue| (issue.status != issues::Status::Open, Reverse(issue.id)));
+        let lines: Vec<String> = issues
+            .iter()
+            .map(|issue| {
+                format!(
+                    "#{} [{}] {}: {} ({})",
+                    issue.id,
+                    issue.status.name(),
+                    issue.author,
+                    issue.title,
+                    ago(now().saturating_sub(issue.created))
+                )
+            })
+            .collect();
+        self.print(&lines).await
+    }
+
+    async fn issue_show(&self, repo: &str, id: &str) -> anyhow::Result<()> {
+        let (repo_path, _) = self.readable_repo(repo).await?;
+        let repo = Repo::open(&repo_path);
+        let (issue, _) = issues::load(&repo, id).await?;
+
+        let mut lines = vec![
+            format!("#{}: {}", issue.id, issue.title),
+            format!("status: {}", issue.status.name()),
+            format!("author: {}", issue.author),
+            format!("opened: {}", ago(now().saturating_sub(issue.created))),
+        ];
+        for comment in issues::history(&repo, &issue).await? {
+            let age = now().saturating_sub(comment.date.max(0) as u64);
+            lines.push(String::new());
+            lines.push(format!("{}, {}:", comment.author, ago(age)));
+            lines.extend(comment.message.lines().map(|line| match line {
+                "" => String::new(),
+                line => format!("  {}", line),
+            }));
+        }
+        self.print(&lines).await
+    }
+
+    async fn issue_comment(&self, repo: &str, id: &str, text: &str) -> anyhow::Result<()> {
+        let (repo_path, config) = self.discussable_repo(repo).await?;
+        let issue = {
+            let repo_lock = self.state.lock().await.repo_lock(&repo_path);
+            let _repo_lock = repo_lock.lock().await;
+            let repo = Repo::open(&repo_path);
+            let (issue, head) = issues::load(&repo, id).await?;
+            issues::update(&repo, &issue, &head, text, &self.username).await?;
+            issue
+        };
+        self.knob
+            .info(&format!("Commented on #{}.", issue.id))
+            .await?;
+        self.issue_changed(&repo_path, &config).await
+    }
+
+    /// Closes an issue, with a last comment if there's one. Its author can do it too.
+    async fn issue_close(&self, repo: &str, id: &str, text: &str) -> anyhow::Result<()> {
+        let (repo_path, config) = self.discussable_repo(repo).await?;
+        let issue = {
+            let repo_lock = self.state.lock().await.repo_lock(&repo_path);
+            let _repo_lock = repo_lock.lock().await;
+            let repo = Repo::open(&repo_path);
+            let (mut issue, head) = issues::load(&repo, id).await?;
+            if !config.can_push(&self.username, self.is_admin) && issue.author != self.username {
+                return Err(anyhow!("Only members and its author can close it."));
+            }
+            if issue.status != issues::Status::Open {
+                return Err(anyhow!("#{} is already closed.", issue.id));
+            }
+            issue.status = issues::Status::Closed;
+            let message = match text {
+                "" => "Closed.".to_string(),
+                text => format!("Closed.\n\n{}", text),
+            };
+            issues::update(&repo, &issue, &head, &message, &self.username).await?;
+            issue
+        };
+        self.knob.info(&format!("Closed #{}.", issue.id)).await?;
+        self.issue_changed(&repo_path, &config).await
+    }
+
+    /// Looks up a repo for one of its members to go through its emailed patches, checking the
+    /// mailbox for new ones first.
+    async fn patch_repo(&self, repo: &str) -> anyhow::Result<(PathBuf, RepoConfig)> {
+        self.require_primary().await?;
+        let (repo_path, config) = self.readable_repo(repo).await?;
+        if !config.can_push(&self.username, self.is_admin) {
+            return Err(anyhow!("Only members can see emailed patches."));
+        }
+        intake(&self.state).await?;
+        Ok((repo_path, config))
+    }
+
+    async fn patch_list(&self, repo: &str) -> anyhow::Result<()> {
+        let (repo_path, _) = self.patch_repo(repo).await?;
+        let mut series = PatchQueue::load(&repo_path)?.series;
+        if series.is_empty() {
+            return self.knob.info("No patches yet.").await;
+        }
+
+        // Ones still to apply first, newest first.
+        series.sort_by_key(|series| (series.applied.is_some(), Reverse(series.received)));
+        let lines: Vec<String> = series
+            .iter()
+            .map(|series| {
+                let status = match &series.applied {
+                    Some(branch) => format!("applied to {}", branch),
+                    None if series.is_complete() => "pending".to_string(),
+                    None => format!(
+                        "incomplete, {} of {}",
+                        series.to_apply().len(),
+                        series.total
+                    ),
+                };
+                format!(
+                    "{} [{}] {}: {} ({})",
+                    series.id,
+                    status,
+                    series.author,
+                    series.subject(),
+                    ago(now().saturating_sub(series.received))
+                )
+            })
+            .collect();
+        self.print(&lines).await
+    }
+
+    /// Prints a series' patches as an mbox, which can be piped into `git am` to try them out.
+    async fn patch_show(&self, repo: &str, id: &str) -> anyhow::Result<()> {
+        let (repo_path, _) = self.patch_repo(repo).await?;
+        let mut queue = PatchQueue::load(&repo_path)?;
+        let series = queue.get_mut(id)?;
+        for patch in series.to_apply() {
+            let mail = read(series.mail_path(&repo_path, patch))?;
+            // Mails from an mbox keep the line that separates them, and others get the one
+            // `git format-patch` uses.
+            if !mail.starts_with(b"From ") {
+                let from =
+                    "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n";
+                self.knob.data(from.as_bytes()).await?;
+            }
+            self.knob.data(&mail).await?;
+            if !mail.ends_with(b"\n") {
+                self.knob.data(b"\n").await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a series onto a branch, like `git am` would, starting the branch from the default
+    /// branch if it's new.
+    async fn patch_apply(&self, repo: &str, id: &str, branch: &str) -> anyhow::Result<()> {
+        let (repo_path, config) = self.patch_repo(repo).await?;
+        if branch.starts_with('-') {
+            return Err(anyhow!("Invalid branch name {}", branch));
+        }
+
+        let count = {
+            let repo_lock = self.state.lock().await.repo_lock(&repo_path);
+            let _repo_lock = repo_lock.lock().await;
+            let mut queue = PatchQueue::load(&repo_path)?;
+            let series = queue.get_mut(id)?;
+            if let Some(applied) = &series.applied {
+                return Err(anyhow!("{} is already applied to {}.", id, applied));
+            }
+            if !series.is_complete() {
+                return Err(anyhow!(
+                    "{} only has {} of its {} patches so far.",
+                    id,
+                    series.to_apply().len(),
+                    series.total
+                ));
+            }
+            let mails: Vec<PathBuf> = series
+                .to_apply()
+                .into_iter()
+                .map(|patch| series.mail_path(&repo_path, patch))
+                .collect();
+            Repo::open(&repo_path)
+                .apply_mails(branch, &mails, &self.username)
+                .await?;
+            queue.mark_applied(&repo_path, id, branch)?;
+            mails.len()
+        };
+        self.knob
+            .info(&format!(
+                "Applied {} patch{} onto {}.",
+                count,
+                if count == 1 { "" } else { "es" },
+                branch
+            ))
+            .await?;
+
+        update_index(&repo_path).await?;
+        let server_config = self.state.lock().await.server_config.clone();
+        push_to_mirrors(&self.knob, &repo_path, &config, &server_config).await?;
+        rebuild_site(&self.state, &repo_path).await?;
+        replicate(&self.state, &repo_path).await;
+        Ok(())
+    }
+
+    /// Searches the code of every repo the user can read, or just the one they ask for.
+    async fn search(&self, args: &[&str]) -> anyhow::Result<()> {
+        let mut words = Vec::new();
+        let mut repo = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "--repo" => repo = Some(*args.next().ok_or_else(|| anyhow!(USAGE))?),
+                word => words.push(word),
+            }
+        }
+        if words.is_empty() {
+            return Err(anyhow!(USAGE));
+        }
+
+        let candidates = match repo {
+            None => find_repos(Path::new("."))?,
+            Some(repo) => parse_repo_path(repo)
+                .filter(|path| path.exists() && path != Path::new(SERVER_CONFIG_REPO))
+                .into_iter()
+                .collect(),
+        };
+
+        // The same rules as cloning: anything else doesn't exist as far as the user knows.
+        let mut repos = Vec::new();
+        for repo_path in candidates {
+            let can_read = match committed_repo_config(&repo_path).await {
+                Ok(Some(config)) => config.can_read(&self.username, self.is_admin),
+                _ => self.is_admin,
+            };
+            if can_read {
+                repos.push(repo_path);
+            }
+        }
+        if repo.is_some() && repos.is_empty() {
+            return Err(anyhow!("That repository doesn't exist :("));
+        }
+
+        // One more than shown, to tell whether there were too many.
+        let hits = search(&repos, &words.join(" "), MAX_SEARCH_HITS + 1).await?;
+        for hit in hits.iter().take(MAX_SEARCH_HITS) {
+            let line = format!(
+                "{}:{}:{}: {}\n",
+                hit.repo.display(),
+                hit.path,
+                hit.line_number,
+                hit.line
+            );
+            self.knob.data(line.as_bytes()).await?;
+        }
+
+        match hits.len() {
+            0 => self.knob.info("No matches.").await,
+            n if n > MAX_SEARCH_HITS => {
+                self.knob
+                    .info(&format!(
+                        "Stopped after {} matches, try narrowing the search.",
+                        MAX_SEARCH_HITS
+                    ))
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rebuilds sites from scratch, one repo at a time so pushes can get in between.
+    async fn rebuild_sites(&self, target: &str) -> anyhow::Result<()> {
+        let repos = match target {
+            "--all" => find_repos(Path::new("."))?,
+            repo => {
+                let repo_path = parse_repo_path(repo)
+                    .filter(|path| path.exists() && path != Path::new(SERVER_CONFIG_REPO))
+                    .ok_or_else(|| anyhow!("That repository doesn't exist :("))?;
+                vec![repo_path]
+            }
+        };
+
+        let mut failed = 0;
+        for repo_path in &repos {
+            match force_rebuild_site(&self.state, repo_path).await {
+                Ok(()) => {
+                    self.knob
+                        .info(&format!("Rebuilt {}", repo_path.display()))
+                        .await?
+                }
+                Err(e) => {
+                    failed += 1;
+                    self.knob
+                        .error(&format!(
+                            "Couldn't rebuild {}: {:#}",
+                            repo_path.display(),
+                            e
+                        ))
+                        .await?;
+                }
+            }
+        }
+
+        // Also takes down the sites of repos that are gone.
+        reconcile_sites(&self.state).await?;
+
+        match failed {
+            0 => Ok(()),
+            n => Err(anyhow!("{} of {} sites failed to rebuild.", n, repos.len())),
+        }
+    }
+}
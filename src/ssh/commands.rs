@@ -1,5 +1,5 @@
 use std::ffi::OsStr;
-use std::path::Component;
+use std::path::{Component, Path};
 use std::str::from_utf8;
 use std::{path::PathBuf, process::Stdio};
 
@@ -8,11 +8,17 @@ use clean_path::Clean;
 use log::info;
 use russh::{server::Handle, ChannelId, CryptoVec};
 use shellwords::split;
-use tokio::{io::AsyncReadExt, process::Command};
+use tempfile::NamedTempFile;
+use tokio::{io::AsyncReadExt, process::Command, time::sleep};
 
-use crate::config::repo::{load_repo_config, new_repo_config};
-use crate::config::server::load_server_config;
+use crate::config::repo::{load_repo_config, new_repo_config, RepoConfig};
+use crate::config::server::{load_server_config, ServerConfig};
 use crate::git::Repo;
+use crate::merge_requests::{receive_pack_env, receive_push, upload_pack_env, MergeRequests};
+use crate::mirror::{push_to_mirror, update_mirror, PUSH_ATTEMPTS, PUSH_BACKOFF};
+use crate::replication::replicate;
+use crate::search::update_index;
+use crate::site::{builtin_templates, load_templates, rebuild_index, rebuild_site};
 use crate::utils::CustomContext;
 use crate::vars::*;
 
@@ -37,32 +43,21 @@ impl Handler {
         let command = from_utf8(command).context("Failed to parse command bytes into a string")?;
         let command = split(command).context("Could not split command into words.")?;
 
-        // Politely decline non-git commands.
-        if !GIT_COMMANDS.contains(&command[0].as_str()) {
-            knob.close().await?;
-            return Ok(());
+        // Anything that isn't git is one of ours.
+        if !GIT_COMMANDS.contains(&command.first().map(String::as_str).unwrap_or_default()) {
+            return self.handle_cli(knob, command).await;
         }
 
-        // The git plumbing commands give the repo like this: '/repo.git'.
-        let mut repo_path = PathBuf::from(&command[1]);
-        repo_path = repo_path.strip_prefix("/")?.into();
-        repo_path = repo_path.clean();
-
-        // Reject repo paths outside eejit's dir.
-        if repo_path.components().next() == Some(Component::ParentDir) {
+        let Some(repo_path) = command.get(1).and_then(|path| parse_repo_path(path)) else {
             knob.close().await?;
             return Ok(());
-        }
-
-        // Enforce a .git extension.
-        if repo_path.extension().unwrap_or(OsStr::new("")) != "git" {
-            repo_path.set_file_name(format!(
-                "{}.git",
-                repo_path.file_name().unwrap().to_str().unwrap()
-            ));
-        }
+        };
 
+        let operation = command.get(2).cloned();
         let command = command[0].clone();
+        // LFS uploads come before the push itself, and need the same permission.
+        let is_push = command == GIT_PUSH_COMMAND
+            || (command == LFS_TRANSFER_COMMAND && operation.as_deref() == Some("upload"));
 
         let user = self.user.clone().unwrap_or_default();
         let username = self.username.clone().unwrap_or(GUEST_USERNAME.to_string());
@@ -70,12 +65,27 @@ impl Handler {
         let is_admin = user.is_admin.unwrap_or(false);
         let can_create_repos = user.can_create_repos.unwrap_or(false);
 
+        // Replicas only take pushes from their primary, which copies everything over as it is.
+        let (replica, is_replicator) = {
+            let state = self.state.lock().await;
+            (state.replica.clone(), state.is_replicator(&username))
+        };
+        if let (Some(replica), true, false) = (&replica, is_push, is_replicator) {
+            knob.error(&format!(
+                "This server is a read-only replica, push to {} instead.",
+                replica.primary
+            ))
+            .await?;
+            knob.close().await?;
+            return Ok(());
+        }
+
         if let Some(welcome_message) = server_config.welcome_message {
             knob.info(&welcome_message.replace('%', &username)).await?;
         }
 
         // Deny non-admins access to the config repo.
-        if !is_admin && repo_path == PathBuf::from(SERVER_CONFIG_REPO) {
+        if !is_admin && !is_replicator && repo_path == Path::new(SERVER_CONFIG_REPO) {
             knob.error("Only admins are allowed to access this repository.")
                 .await?;
             knob.close().await?;
@@ -85,9 +95,9 @@ impl Handler {
         // Handle non-existent repos, including creating a new one on push for some users.
         let mut new_repo = false;
         if !repo_path.exists() {
-            if command == GIT_PUSH_COMMAND && (can_create_repos || is_admin) {
+            if command == GIT_PUSH_COMMAND && (can_create_repos || is_admin || is_replicator) {
                 // Non-admins can only make new repos in thier personal directory.
-                if !is_admin {
+                if !is_admin && !is_replicator {
                     let mut dir = None;
                     if let Some(first_component) = repo_path.components().next() {
                         dir = Some(first_component.as_os_str().to_str().unwrap());
@@ -104,7 +114,8 @@ impl Handler {
 
                 knob.info("Creating a new repository...").await?;
                 Repo::create_bare(&repo_path).await?;
-                new_repo = true;
+                // Replicated repos come with their config.
+                new_repo = !is_replicator;
             } else {
                 knob.error("That repository doesn't exist :(").await?;
                 knob.close().await?;
@@ -112,44 +123,112 @@ impl Handler {
             }
         }
 
-        if !new_repo && repo_path != PathBuf::from(SERVER_CONFIG_REPO) {
+        // People who can read a repo but not push to it can still open merge requests.
+        let mut only_merge_requests = None;
+        let mut hide_merge_requests = false;
+        if !new_repo && !is_replicator && repo_path != Path::new(SERVER_CONFIG_REPO) {
             let repo_config = load_repo_config(&repo_path).await?;
 
             // Access control.
             // TODO: don't load the repo config on every request.
-            if is_admin {
-                let is_member = repo_config.members.contains(&username);
-
-                if command == GIT_PUSH_COMMAND && !is_member {
-                    knob.error("You don't have permission to push to this repository.")
-                        .await?;
+            if let (true, Some(mirror)) = (is_push, &repo_config.mirror) {
+                knob.error(&format!(
+                    "This repository is a mirror of {}, so it can't be pushed to.",
+                    mirror.url
+                ))
+                .await?;
+                knob.close().await?;
+                return Ok(());
+            }
 
-                    if let Some(message) = repo_config.failed_push_message {
-                        knob.repo_note(&message).await?;
-                    }
+            let can_read = repo_config.can_read(&username, is_admin);
+            hide_merge_requests = !repo_config.can_push(&username, is_admin);
+            if command == GIT_PUSH_COMMAND
+                && can_read
+                && hide_merge_requests
+                && username == GUEST_USERNAME
+            {
+                // Like issues, requests need someone to answer for them.
+                knob.error("Guests can't open merge requests.").await?;
+                knob.close().await?;
+                return Ok(());
+            } else if command == GIT_PUSH_COMMAND && can_read && hide_merge_requests {
+                let mut message = "You can only open merge requests here, like with \
+                    `git push origin HEAD:refs/for/main/my-change`."
+                    .to_string();
+                if let Some(note) = &repo_config.failed_push_message {
+                    message = format!("{}\n{}", message, note);
+                }
+                only_merge_requests = Some(message);
+            } else if is_push && !repo_config.can_push(&username, is_admin) {
+                knob.error("You don't have permission to push to this repository.")
+                    .await?;
 
-                    knob.close().await?;
-                    return Ok(());
+                if let Some(message) = repo_config.failed_push_message {
+                    knob.repo_note(&message).await?;
                 }
 
-                if !(repo_config.public || is_member) {
-                    knob.error("You don't have permission to access this repository.")
-                        .await?;
+                knob.close().await?;
+                return Ok(());
+            }
+
+            if !can_read {
+                knob.error("You don't have permission to access this repository.")
+                    .await?;
+
+                knob.close().await?;
+                return Ok(());
+            }
 
-                    knob.close().await?;
-                    return Ok(());
+            if repo_config.members.contains(&username) {
+                let unseen = {
+                    let repo_lock = self.state.lock().await.repo_lock(&repo_path);
+                    let _repo_lock = repo_lock.lock().await;
+                    let mut requests = MergeRequests::load(&repo_path).await?;
+                    let unseen = requests.notify(&username);
+                    if !unseen.is_empty() {
+                        let ids: Vec<String> = unseen
+                            .iter()
+                            .map(|request| format!("!{}", request.id))
+                            .collect();
+                        let message = format!("Told {} about {}", username, ids.join(", "));
+                        requests.save(&repo_path, &message, &username).await?;
+                    }
+                    unseen
+                };
+                for request in unseen {
+                    knob.info(&format!(
+                        "New merge request !{} from {}: {}",
+                        request.id, request.author, request.title
+                    ))
+                    .await?;
                 }
             }
         }
 
-        let mut shell = Command::new(&command)
+        if command == LFS_TRANSFER_COMMAND || command == LFS_AUTHENTICATE_COMMAND {
+            return self.handle_lfs(knob, repo_path, &command, operation).await;
+        }
+
+        // Where the hook notes down merge requests. The primary's pushes to replicas have none.
+        let push_file = NamedTempFile::new()?;
+        let mut shell = Command::new(&command);
+        if command == GIT_PUSH_COMMAND && !is_replicator {
+            shell.envs(receive_pack_env(
+                push_file.path(),
+                only_merge_requests.as_deref(),
+            )?);
+        } else if hide_merge_requests {
+            shell.envs(upload_pack_env());
+        }
+        let mut shell = shell
             .arg(&repo_path)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()?;
 
         let stdin = shell.stdin.take().unwrap();
-        self.stdin.insert(channel, stdin);
+        self.stdin.insert(channel, Box::new(stdin));
 
         let mut shell_stdout = shell.stdout.take().unwrap();
 
@@ -170,18 +249,72 @@ impl Handler {
 
             // Rebuild.
             if command == GIT_PUSH_COMMAND && !new_repo {
-                if repo_path == PathBuf::from(SERVER_CONFIG_REPO) {
+                if repo_path == Path::new(SERVER_CONFIG_REPO) {
                     info!("Reloading server config...");
                     knob.info("Reloading server config...").await?;
-                    state.lock().await.server_config = load_server_config().await?;
+                    {
+                        let mut state = state.lock().await;
+                        state.server_config = load_server_config().await?;
+                        state.templates = match load_templates().await {
+                            Ok(templates) => templates,
+                            Err(e) => {
+                                knob.error(&format!("{:#}, using the built-in templates.", e))
+                                    .await?;
+                                builtin_templates()
+                            }
+                        };
+                    }
+                    rebuild_index(&state).await?;
+                    replicate(&state, &repo_path).await;
+                } else if is_replicator {
+                    update_index(&repo_path).await?;
+                    rebuild_site(&state, &repo_path).await?;
                 } else {
-                    knob.info("Reloading repo information...").await?;
-                    state.lock().await.rebuild_site(&repo_path).await?;
+                    // Pushes to refs/for/... become merge requests, whoever makes them.
+                    let messages = {
+                        let repo_lock = state.lock().await.repo_lock(&repo_path);
+                        let _repo_lock = repo_lock.lock().await;
+                        receive_push(&repo_path, push_file.path(), &username).await?
+                    };
+                    for message in messages {
+                        knob.info(&message).await?;
+                    }
+
+                    if only_merge_requests.is_some() {
+                        replicate(&state, &repo_path).await;
+                    } else {
+                        knob.info("Reloading repo information...").await?;
+                        let repo_config = load_repo_config(&repo_path).await?;
+                        match &repo_config.mirror {
+                            // The push that makes a repo a mirror is its last.
+                            Some(mirror) => {
+                                knob.info(&format!(
+                                    "This repository is now a mirror of {}, syncing...",
+                                    mirror.url
+                                ))
+                                .await?;
+                                // It'll be tried again later, and `repo info` says how it went.
+                                if let Err(e) = update_mirror(&state, &repo_path, mirror).await {
+                                    knob.error(&format!("Couldn't sync: {:#}", e)).await?;
+                                }
+                            }
+                            None => {
+                                update_index(&repo_path).await?;
+                                let server_config = state.lock().await.server_config.clone();
+                                push_to_mirrors(&knob, &repo_path, &repo_config, &server_config)
+                                    .await?;
+                                rebuild_site(&state, &repo_path).await?;
+                                replicate(&state, &repo_path).await;
+                            }
+                        }
+                    }
                 }
             }
 
             if new_repo {
-                new_repo_config(&repo_path, &username).await?;
+                new_repo_config(&repo_path, &username, None).await?;
+                update_index(&repo_path).await?;
+                replicate(&state, &repo_path).await;
                 knob.info("Created a new repo config - please pull.")
                     .await?;
             }
@@ -196,15 +329,73 @@ impl Handler {
     }
 }
 
+/// Pushes to each of the repo's push mirrors, backing off between tries. Failures are only
+/// reported, since the push itself has already gone through.
+pub async fn push_to_mirrors(
+    knob: &Knob,
+    repo_path: &Path,
+    repo_config: &RepoConfig,
+    server_config: &ServerConfig,
+) -> anyhow::Result<()> {
+    for mirror in &repo_config.push_mirrors {
+        let mut backoff = PUSH_BACKOFF;
+        for attempt in 1..=PUSH_ATTEMPTS {
+            match push_to_mirror(repo_path, mirror, server_config).await {
+                Ok(()) => {
+                    knob.info(&format!("Pushed to {}", mirror.url)).await?;
+                    break;
+                }
+                Err(e) if attempt < PUSH_ATTEMPTS => {
+                    knob.info(&format!(
+                        "Couldn't push to {}, retrying in {}s: {:#}",
+                        mirror.url,
+                        backoff.as_secs(),
+                        e
+                    ))
+                    .await?;
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    knob.info(&format!("Gave up pushing to {}: {:#}", mirror.url, e))
+                        .await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Turns a repo path from a command into one relative to the data directory, like `/repo` into
+/// `repo.git`. Paths outside of it are rejected.
+pub fn parse_repo_path(path: &str) -> Option<PathBuf> {
+    // The git plumbing commands give the repo like this: '/repo.git'.
+    let mut repo_path = PathBuf::from(path);
+    repo_path = repo_path.strip_prefix("/").unwrap_or(&repo_path).into();
+    repo_path = repo_path.clean();
+
+    // Reject repo paths outside eejit's dir.
+    if repo_path.components().next() == Some(Component::ParentDir) {
+        return None;
+    }
+
+    // Enforce a .git extension.
+    if repo_path.extension().unwrap_or(OsStr::new("")) != "git" {
+        repo_path.set_file_name(format!("{}.git", repo_path.file_name()?.to_str()?));
+    }
+
+    Some(repo_path)
+}
+
 impl Knob {
-    async fn close(&self) -> anyhow::Result<()> {
+    pub async fn close(&self) -> anyhow::Result<()> {
         self.handle
             .close(self.channel)
             .await
             .context("Failed to close handle")?;
         Ok(())
     }
-    async fn data(&self, data: &[u8]) -> anyhow::Result<()> {
+    pub async fn data(&self, data: &[u8]) -> anyhow::Result<()> {
         let buf = CryptoVec::from_slice(data);
         self.handle
             .data(self.channel, buf)
@@ -212,14 +403,14 @@ impl Knob {
             .context("Failed to write data to channel")?;
         Ok(())
     }
-    async fn exit_status(&self, status: u32) -> anyhow::Result<()> {
+    pub async fn exit_status(&self, status: u32) -> anyhow::Result<()> {
         self.handle
             .exit_status_request(self.channel, status)
             .await
             .context("Failed to set exit status")?;
         Ok(())
     }
-    async fn eof(&self) -> anyhow::Result<()> {
+    pub async fn eof(&self) -> anyhow::Result<()> {
         self.handle
             .eof(self.channel)
             .await
@@ -227,3 +418,42 @@ impl Knob {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repo_paths_get_a_git_extension() {
+        assert_eq!(parse_repo_path("/repo"), Some(PathBuf::from("repo.git")));
+        assert_eq!(
+            parse_repo_path("/alex/tool.git"),
+            Some(PathBuf::from("alex/tool.git"))
+        );
+        assert_eq!(
+            parse_repo_path("alex/tool"),
+            Some(PathBuf::from("alex/tool.git"))
+        );
+    }
+
+    #[test]
+    fn repo_paths_are_cleaned_up() {
+        assert_eq!(
+            parse_repo_path("/alex//./tool.git"),
+            Some(PathBuf::from("alex/tool.git"))
+        );
+        assert_eq!(
+            parse_repo_path("alex/other/../tool.git"),
+            Some(PathBuf::from("alex/tool.git"))
+        );
+    }
+
+    #[test]
+    fn repo_paths_cant_leave_the_data_directory() {
+        assert_eq!(parse_repo_path("../repo.git"), None);
+        assert_eq!(parse_repo_path("/../repo.git"), None);
+        assert_eq!(parse_repo_path("alex/../../repo.git"), None);
+        assert_eq!(parse_repo_path(""), None);
+        assert_eq!(parse_repo_path("/"), None);
+    }
+}
@@ -0,0 +1,372 @@
+use std::{io::Read, path::PathBuf};
+
+use anyhow::anyhow;
+use log::error;
+use tokio::io::{duplex, DuplexStream};
+
+use crate::{
+    lfs::{
+        is_valid_oid, line, packet, packet_text, read_packet, LfsStore, Packet, DELIM, FLUSH,
+        MAX_PACKET_DATA,
+    },
+    vars::*,
+};
+
+use super::{commands::Knob, Handler};
+
+/// Enough to keep a transfer moving without holding much of it in memory.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A command from the client, up to its arguments. Any data after them is read separately.
+struct Request {
+    command: String,
+    args: Vec<String>,
+    has_data: bool,
+}
+
+/// An error the client is told about, after which the session carries on.
+#[derive(Debug)]
+struct Status {
+    code: u16,
+    message: String,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Status {}
+
+fn status(code: u16, message: &str) -> anyhow::Error {
+    Status {
+        code,
+        message: message.to_string(),
+    }
+    .into()
+}
+
+impl Handler {
+    /// Serves Git LFS objects over SSH with the `git-lfs-transfer` protocol, which git-lfs 3.0 and
+    /// newer use on their own. Access has already been checked against the operation.
+    pub async fn handle_lfs(
+        &mut self,
+        knob: Knob,
+        repo_path: PathBuf,
+        command: &str,
+        operation: Option<String>,
+    ) -> anyhow::Result<()> {
+        let quota = self.state.lock().await.server_config.lfs_quota(&repo_path);
+
+        let (input, output) = duplex(BUFFER_SIZE);
+        self.stdin.insert(knob.channel, Box::new(output));
+        let mut transfer = LfsTransfer {
+            knob: knob.clone(),
+            input,
+            store: LfsStore::open(&repo_path),
+            upload: operation.as_deref() == Some("upload"),
+            quota,
+        };
+
+        let command = command.to_string();
+        let fut = async move {
+            let result = match (command.as_str(), operation.as_deref()) {
+                (LFS_TRANSFER_COMMAND, Some("upload" | "download")) => transfer.run().await,
+                // It hands out credentials for the HTTP API, which there isn't one of.
+                (LFS_AUTHENTICATE_COMMAND, _) => Err(anyhow!(
+                    "This server only speaks git-lfs-transfer, which needs Git LFS 3.0 or newer."
+                )),
+                _ => Err(anyhow!("Usage: git-lfs-transfer <repo> upload|download")),
+            };
+            let status = match result {
+                Ok(()) => 0,
+                Err(e) => {
+                    knob.error(&format!("{:#}", e)).await?;
+                    1
+                }
+            };
+            knob.exit_status(status).await?;
+            knob.eof().await?;
+            knob.close().await?;
+            Ok::<(), anyhow::Error>(())
+        };
+        tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                error!("{:#}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+struct LfsTransfer {
+    knob: Knob,
+    input: DuplexStream,
+    store: LfsStore,
+    upload: bool,
+    quota: Option<u64>,
+}
+
+impl LfsTransfer {
+    async fn run(&mut self) -> anyhow::Result<()> {
+        // Capabilities first. Locking isn't one of them.
+        self.send(&[line("version=1"), FLUSH.to_vec()]).await?;
+
+        while let Some(request) = self.read_request().await? {
+            let (name, arg) = match request.command.split_once(' ') {
+                Some((name, arg)) => (name, arg),
+                None => (request.command.as_str(), ""),
+            };
+            let reads_data = matches!((name, self.upload), ("batch", _) | ("put-object", true));
+            let result = match (name, self.upload) {
+                ("version", _) if arg == "1" => self.ok().await,
+                ("quit", _) => return self.ok().await,
+                ("batch", _) => self.batch(&request).await,
+                ("put-object", true) => self.put_object(arg, &request).await,
+                ("verify-object", true) => self.verify_object(arg, &request).await,
+                ("get-object", false) => self.get_object(arg).await,
+                ("put-object" | "verify-object" | "get-object", _) => {
+                    Err(status(403, "Not allowed in this operation."))
+                }
+                ("lock" | "list-lock" | "unlock", _) => {
+                    Err(status(405, "Locking isn't supported."))
+                }
+                _ => Err(status(400, &format!("Unknown command {}", name))),
+            };
+
+            // Whatever else the client sent along has to be out of the way before replying.
+            if request.has_data && !reads_data {
+                self.skip_data().await?;
+            }
+            match result.map_err(|e| e.downcast::<Status>()) {
+                Ok(()) => {}
+                Err(Ok(status)) => {
+                    self.send(&[
+                        line(&format!("status {}", status.code)),
+                        DELIM.to_vec(),
+                        line(&status.message),
+                        FLUSH.to_vec(),
+                    ])
+                    .await?
+                }
+                Err(Err(e)) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&self, packets: &[Vec<u8>]) -> anyhow::Result<()> {
+        self.knob.data(&packets.concat()).await
+    }
+
+    async fn ok(&self) -> anyhow::Result<()> {
+        self.send(&[line("status 200"), FLUSH.to_vec()]).await
+    }
+
+    async fn read_request(&mut self) -> anyhow::Result<Option<Request>> {
+        let command = match read_packet(&mut self.input).await? {
+            Some(Packet::Data(data)) => packet_text(data)?,
+            Some(_) => return Err(anyhow!("Expected a command")),
+            None => return Ok(None),
+        };
+
+        let mut args = Vec::new();
+        loop {
+            match read_packet(&mut self.input).await? {
+                Some(Packet::Data(data)) => args.push(packet_text(data)?),
+                Some(Packet::Flush) => break,
+                Some(Packet::Delim) => {
+                    return Ok(Some(Request {
+                        command,
+                        args,
+                        has_data: true,
+                    }))
+                }
+                None => return Err(anyhow!("The client hung up mid-request")),
+            }
+        }
+        Ok(Some(Request {
+            command,
+            args,
+            has_data: false,
+        }))
+    }
+
+    /// The next packet of a request's data, or nothing at its end.
+    async fn read_data(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        match read_packet(&mut self.input).await? {
+            Some(Packet::Data(data)) => Ok(Some(data)),
+            Some(Packet::Flush) => Ok(None),
+            Some(Packet::Delim) => Err(anyhow!("Unexpected delimiter")),
+            None => Err(anyhow!("The client hung up mid-request")),
+        }
+    }
+
+    async fn skip_data(&mut self) -> anyhow::Result<()> {
+        while self.read_data().await?.is_some() {}
+        Ok(())
+    }
+
+    fn arg<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+        request
+            .args
+            .iter()
+            .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+    }
+
+    fn size_arg(request: &Request) -> anyhow::Result<u64> {
+        Self::arg(request, "size")
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| status(400, "Missing size."))
+    }
+
+    fn check_oid(oid: &str) -> anyhow::Result<()> {
+        match is_valid_oid(oid) {
+            true => Ok(()),
+            false => Err(status(400, &format!("Invalid object ID {}", oid))),
+        }
+    }
+
+    /// Says which objects need moving: uploads of what's missing, downloads of what's here.
+    async fn batch(&mut self, request: &Request) -> anyhow::Result<()> {
+        let mut lines = Vec::new();
+        if request.has_data {
+            while let Some(data) = self.read_data().await? {
+                lines.push(packet_text(data)?);
+            }
+        }
+
+        if let Some(algo) = Self::arg(request, "hash-algo") {
+            if algo != "sha256" {
+                return Err(status(409, "Only SHA-256 object IDs are supported."));
+            }
+        }
+
+        let mut replies = vec![line("status 200"), DELIM.to_vec()];
+        let mut incoming = 0;
+        for line_text in &lines {
+            let (oid, size) = line_text
+                .split_once(' ')
+                .and_then(|(oid, size)| Some((oid, size.split(' ').next()?.parse::<u64>().ok()?)))
+                .ok_or_else(|| status(400, &format!("Invalid object {}", line_text)))?;
+            Self::check_oid(oid)?;
+
+            let stored = self.store.size(oid).is_some();
+            let action = match (self.upload, stored) {
+                (true, true) | (false, false) => "noop",
+                (true, false) => {
+                    incoming += size;
+                    "upload"
+                }
+                (false, true) => "download",
+            };
+            replies.push(line(&format!("{} {} {}", oid, size, action)));
+        }
+        replies.push(FLUSH.to_vec());
+
+        if let (Some(quota), true) = (self.quota, incoming > 0) {
+            let usage = self.store.usage()?;
+            if usage + incoming > quota {
+                return Err(status(
+                    507,
+                    &format!(
+                        "This would take the repo's LFS storage to {} MB, over its {} MB quota.",
+                        (usage + incoming).div_ceil(1024 * 1024),
+                        quota / (1024 * 1024)
+                    ),
+                ));
+            }
+        }
+
+        self.send(&replies).await
+    }
+
+    async fn put_object(&mut self, oid: &str, request: &Request) -> anyhow::Result<()> {
+        let checks = self.check_upload(oid, request);
+
+        // The data's read either way, so the session stays in step, but only what's going to be
+        // kept is written down.
+        let mut upload = match (&checks, request.has_data) {
+            (Ok(Some(_)), true) => Some(self.store.start_upload()?),
+            _ => None,
+        };
+        let mut too_big = false;
+        if request.has_data {
+            while let Some(data) = self.read_data().await? {
+                let Some(current) = &mut upload else {
+                    continue;
+                };
+                match checks {
+                    Ok(Some(size)) if current.size() + data.len() as u64 > size => {
+                        upload = None;
+                        too_big = true;
+                    }
+                    _ => current.write(&data)?,
+                }
+            }
+        }
+
+        let Some(size) = checks? else {
+            return self.ok().await;
+        };
+        if too_big {
+            return Err(status(400, &format!("Expected {} bytes, got more", size)));
+        }
+        upload
+            .ok_or_else(|| status(400, "Missing object data."))?
+            .finish(&self.store, oid, size)
+            .map_err(|e| status(400, &e.to_string()))?;
+        self.ok().await
+    }
+
+    /// The size of an object that's about to be uploaded, or nothing if it's already stored.
+    fn check_upload(&self, oid: &str, request: &Request) -> anyhow::Result<Option<u64>> {
+        Self::check_oid(oid)?;
+        let size = Self::size_arg(request)?;
+        if self.store.size(oid).is_some() {
+            return Ok(None);
+        }
+        if let Some(quota) = self.quota {
+            if self.store.usage()? + size > quota {
+                return Err(status(507, "The repo is over its LFS quota."));
+            }
+        }
+        Ok(Some(size))
+    }
+
+    async fn verify_object(&mut self, oid: &str, request: &Request) -> anyhow::Result<()> {
+        Self::check_oid(oid)?;
+        let size = Self::size_arg(request)?;
+        match self.store.size(oid) {
+            Some(stored) if stored == size => self.ok().await,
+            Some(_) => Err(status(409, "The stored object is a different size.")),
+            None => Err(status(404, "Object not found.")),
+        }
+    }
+
+    async fn get_object(&mut self, oid: &str) -> anyhow::Result<()> {
+        Self::check_oid(oid)?;
+        let size = self
+            .store
+            .size(oid)
+            .ok_or_else(|| status(404, "Object not found."))?;
+        let mut file = self.store.read(oid)?;
+
+        self.send(&[
+            line("status 200"),
+            line(&format!("size={}", size)),
+            DELIM.to_vec(),
+        ])
+        .await?;
+        let mut buf = vec![0u8; MAX_PACKET_DATA];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            self.send(&[packet(&buf[..read])]).await?;
+        }
+        self.send(&[FLUSH.to_vec()]).await
+    }
+}
@@ -5,8 +5,7 @@ use async_trait::async_trait;
 use russh::server::{Msg, Session};
 use russh::*;
 use russh_keys::*;
-use tokio::io::AsyncWriteExt;
-use tokio::process::ChildStdin;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use log::error;
 use tokio::sync::Mutex;
@@ -17,7 +16,9 @@ use crate::State;
 mod keys;
 use self::keys::server_keys;
 
+mod cli;
 mod commands;
+mod lfs;
 mod messages;
 
 pub async fn start_server(state: Arc<Mutex<State>>) -> anyhow::Result<()> {
@@ -35,7 +36,7 @@ pub async fn start_server(state: Arc<Mutex<State>>) -> anyhow::Result<()> {
         state: state.clone(),
     };
 
-    let port = state.lock().await.server_config.port;
+    let port = state.lock().await.port();
 
     russh::server::run(config, ("0.0.0.0", port), sh).await?;
 
@@ -59,7 +60,8 @@ impl server::Server for Server {
 }
 
 struct Handler {
-    stdin: HashMap<ChannelId, ChildStdin>,
+    /// Where data from each channel goes, like a git process or one of our own protocols.
+    stdin: HashMap<ChannelId, Box<dyn AsyncWrite + Send + Unpin>>,
     state: Arc<Mutex<State>>,
     user: Option<ServerUser>,
     username: Option<String>,
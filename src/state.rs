@@ -1,15 +1,86 @@
-use crate::config::server::{load_server_config, ServerConfig};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::error;
+use tera::Tera;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::server::{load_replica_config, load_server_config, ReplicaConfig, ServerConfig},
+    site::{builtin_templates, load_templates, SiteBuilder},
+};
 
 pub struct State {
     pub server_config: ServerConfig,
+    /// The shared page templates, from config.git.
+    pub templates: Tera,
+    /// Set when this server is a read-only copy of another one.
+    pub replica: Option<ReplicaConfig>,
+    /// Held while changing what's kept alongside a repo, like its patch queue, so that slow
+    /// changes to one repo don't hold up everything else.
+    repo_locks: HashMap<PathBuf, Arc<Mutex<()>>>,
+    /// Held while taking in emailed patches, so only one run reads the mailbox at once.
+    pub patch_intake: Arc<Mutex<()>>,
+    /// Held while writing what every site shares. See [`SiteBuilder`].
+    site_lock: Arc<Mutex<()>>,
 }
 
 impl State {
     pub async fn new() -> anyhow::Result<Self> {
         let state = State {
             server_config: load_server_config().await?,
+            // A broken template mustn't keep the server down, or config.git couldn't be pushed
+            // to fix it.
+            templates: match load_templates().await {
+                Ok(templates) => templates,
+                Err(e) => {
+                    error!("{:#}, using the built-in templates", e);
+                    builtin_templates()
+                }
+            },
+            replica: load_replica_config()?,
+            repo_locks: HashMap::new(),
+            patch_intake: Arc::new(Mutex::new(())),
+            site_lock: Arc::new(Mutex::new(())),
         };
 
         Ok(state)
     }
+
+    pub fn port(&self) -> u16 {
+        self.replica
+            .as_ref()
+            .and_then(|replica| replica.port)
+            .unwrap_or(self.server_config.port)
+    }
+
+    /// The lock for a repo, to be taken once the state's is let go.
+    pub fn repo_lock(&mut self, repo_path: &Path) -> Arc<Mutex<()>> {
+        self.repo_locks
+            .entry(repo_path.to_path_buf())
+            .or_default()
+            .clone()
+    }
+
+    /// What building sites needs, for building them once the state's is let go.
+    pub fn site_builder(&self) -> SiteBuilder {
+        SiteBuilder {
+            server_config: self.server_config.clone(),
+            templates: self.templates.clone(),
+            shared: self.site_lock.clone(),
+        }
+    }
+
+    /// Whether a user is the primary, pushing to this replica.
+    pub fn is_replicator(&self, username: &str) -> bool {
+        self.replica.is_some()
+            && self
+                .server_config
+                .replication
+                .as_ref()
+                .is_some_and(|replication| replication.user == username)
+    }
 }
@@ -1,8 +1,35 @@
 pub const GUEST_USERNAME: &str = "guest";
 pub const SERVER_CONFIG_REPO: &str = "config.git";
 pub const SERVER_CONFIG_FILE: &str = "server.toml";
+pub const REPLICA_CONFIG_FILE: &str = "replica.toml";
+
+pub const TEMPLATES_DIR: &str = "templates";
 
 pub const REPO_CONFIG_FILE: &str = "gitenator.toml";
 
-pub const GIT_COMMANDS: [&str; 3] = ["git-receive-pack", "git-upload-archive", "git-upload-pack"];
+pub const GIT_COMMANDS: [&str; 5] = [
+    "git-receive-pack",
+    "git-upload-archive",
+    "git-upload-pack",
+    LFS_TRANSFER_COMMAND,
+    LFS_AUTHENTICATE_COMMAND,
+];
 pub const GIT_PUSH_COMMAND: &str = "git-receive-pack";
+pub const LFS_TRANSFER_COMMAND: &str = "git-lfs-transfer";
+pub const LFS_AUTHENTICATE_COMMAND: &str = "git-lfs-authenticate";
+
+pub const SITE_STATE_FILE: &str = ".site-state.toml";
+pub const PUSH_LOG_FILE: &str = ".push-log.toml";
+pub const MIRROR_STATUS_FILE: &str = "mirror-status.toml";
+pub const PUSH_MIRROR_LOG_FILE: &str = "push-mirrors.log";
+pub const MERGE_REQUESTS_FILE: &str = "merge-requests.toml";
+pub const PATCH_QUEUE_FILE: &str = "patches.toml";
+pub const PATCH_QUEUE_DIR: &str = "patches";
+pub const PATCHES_SEEN_FILE: &str = ".patches-seen";
+
+pub const HOOKS_DIR: &str = ".hooks";
+
+/// A ref's object when it doesn't exist, as git writes it.
+pub const NULL_OBJECT: &str = "0000000000000000000000000000000000000000";
+
+pub const SEARCH_INDEX_DIR: &str = ".search";